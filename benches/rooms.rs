@@ -0,0 +1,74 @@
+//Measures how many room worker iterations the database handle can serve concurrently.
+//Always runs against the in-memory backend, and against Redis too when REDIS_URL is set.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use social_music::config::Config;
+use social_music::db::{self, message::Message, Db};
+
+const OPS_PER_ROOM: u64 = 20;
+
+async fn room_worker(db: Db, room_id: String) {
+    //Roughly what one round of play_next_song plus a chat message costs
+    for i in 0..OPS_PER_ROOM / 10 {
+        let user_id = format!("user:{}", i);
+        db.push_user_queue(room_id.clone(), user_id.clone(), "track".to_string())
+            .await
+            .unwrap();
+        db.push_queue(room_id.clone(), user_id).await.unwrap();
+        let next = db.pop_queue(room_id.clone()).await.unwrap().unwrap();
+        db.pop_user_queue(room_id.clone(), next.clone())
+            .await
+            .unwrap();
+        db.get_auth(next.clone()).await.unwrap();
+        db.list_presences(room_id.clone()).await.unwrap();
+        db.push_queue(room_id.clone(), next).await.unwrap();
+        db.add_message(room_id.clone(), Message::queue_changed())
+            .await
+            .unwrap();
+        db.add_message(
+            room_id.clone(),
+            Message::chat_message("user".to_string(), "hello".to_string()),
+        )
+        .await
+        .unwrap();
+        db.list_queue(room_id.clone()).await.unwrap();
+    }
+}
+
+async fn serve_rooms(db: Db, rooms: u64) {
+    let workers: Vec<_> = (0..rooms)
+        .map(|room| tokio::task::spawn(room_worker(db.clone(), format!("bench-{}", room))))
+        .collect();
+
+    for worker in workers {
+        worker.await.unwrap();
+    }
+}
+
+fn bench_rooms(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut backends = vec![("memory", db::connect_memory_db(&Config::default()))];
+    if let Ok(redis_url) = std::env::var("REDIS_URL") {
+        let config = Config {
+            redis_url,
+            ..Default::default()
+        };
+        backends.push(("redis", runtime.block_on(db::connect_db(&config)).unwrap()));
+    }
+
+    for (name, db) in backends {
+        let mut group = c.benchmark_group(format!("rooms_{}", name));
+
+        for rooms in [1, 10, 100, 500] {
+            group.throughput(Throughput::Elements(rooms * OPS_PER_ROOM));
+            group.bench_with_input(BenchmarkId::from_parameter(rooms), &rooms, |b, &rooms| {
+                b.to_async(&runtime).iter(|| serve_rooms(db.clone(), rooms));
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_rooms);
+criterion_main!(benches);
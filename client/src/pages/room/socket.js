@@ -7,6 +7,9 @@ function createSocket() {
     messages: [],
     queue: [],
     presences: [],
+    pool: [],
+    playing: null,
+    roles: {},
     queueChange: 0,
   });
 
@@ -49,6 +52,19 @@ function createSocket() {
         update((data) => {
           data.queue = message.PresencesQueueMessage.queue;
           data.presences = message.PresencesQueueMessage.presences;
+          data.pool = message.PresencesQueueMessage.pool;
+          data.playing = message.PresencesQueueMessage.playing;
+          data.roles = message.PresencesQueueMessage.roles;
+          return data;
+        });
+      }
+      if(message.RoleChange) {
+        update((data) => {
+          if(message.RoleChange.role == "dj") {
+            delete data.roles[message.RoleChange.user_id];
+          } else {
+            data.roles[message.RoleChange.user_id] = message.RoleChange.role;
+          }
           return data;
         });
       }
@@ -111,6 +127,68 @@ function createSocket() {
     })
   }
 
+  const sendRemoveQueuedTrack = (index, track_id) => {
+    update((data) => {
+      let json = JSON.stringify({
+        RemoveQueuedTrack: {
+          index,
+          track_id
+        }
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
+  const sendMoveQueuedTrack = (index, to, track_id) => {
+    update((data) => {
+      let json = JSON.stringify({
+        MoveQueuedTrack: {
+          index,
+          to,
+          track_id
+        }
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
+  const sendClearMyQueue = () => {
+    update((data) => {
+      let json = JSON.stringify({
+        ClearMyQueue: null
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
+  const sendVotePoolTrack = (id, vote) => {
+    update((data) => {
+      let json = JSON.stringify({
+        VotePoolTrack: {
+          id,
+          vote
+        }
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
+  const sendRemovePoolTrack = (id) => {
+    update((data) => {
+      let json = JSON.stringify({
+        RemovePoolTrack: {
+          id
+        }
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
   const sendJoinQueue = () => {
     update((data) => {
       let json = JSON.stringify({
@@ -121,6 +199,72 @@ function createSocket() {
     })
   }
 
+  const sendLeaveQueue = () => {
+    update((data) => {
+      let json = JSON.stringify({
+        LeaveQueue: null
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
+  const sendStepDown = () => {
+    update((data) => {
+      let json = JSON.stringify({
+        StepDown: null
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
+  const sendUpvote = () => {
+    update((data) => {
+      let json = JSON.stringify({
+        Upvote: null
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
+  const sendVoteSkip = () => {
+    update((data) => {
+      let json = JSON.stringify({
+        VoteSkip: null
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
+  //Kick, Ban, Unban, Mute or Unmute
+  const sendModerate = (action, user_id) => {
+    update((data) => {
+      let json = JSON.stringify({
+        [action]: {
+          user_id
+        }
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
+  const sendSetRole = (user_id, role) => {
+    update((data) => {
+      let json = JSON.stringify({
+        SetRole: {
+          user_id,
+          role
+        }
+      });
+      data.ws.send(json);
+      return data;
+    })
+  }
+
   return {
     subscribe,
     init,
@@ -128,7 +272,18 @@ function createSocket() {
     sendChatMessage,
     sendSetDevice,
     sendQueueSong,
+    sendRemoveQueuedTrack,
+    sendMoveQueuedTrack,
+    sendClearMyQueue,
+    sendVotePoolTrack,
+    sendRemovePoolTrack,
     sendJoinQueue,
+    sendLeaveQueue,
+    sendStepDown,
+    sendUpvote,
+    sendVoteSkip,
+    sendModerate,
+    sendSetRole,
   }
 }
 
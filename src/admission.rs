@@ -0,0 +1,163 @@
+use crate::db::room::AdmissionRules;
+use crate::db::Db;
+use crate::error::{Error, Result};
+use crate::spotify::tracks::Track;
+
+//Room owners can keep some tracks out of their room, like explicit ones or anything longer than a few minutes.
+//Tracks are looked up on Spotify when they're queued and turned away with every rule they break.
+
+pub async fn check(db: &Db, room_id: String, track: &Track) -> Result<()> {
+    let rules = match db.get_room(room_id).await? {
+        Some(room) if !room.rules.is_empty() => room.rules,
+        _ => return Ok(()),
+    };
+
+    let reasons = rejections(&rules, track);
+    if reasons.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Validation(reasons))
+    }
+}
+
+pub fn rejections(rules: &AdmissionRules, track: &Track) -> Vec<String> {
+    let mut reasons = Vec::new();
+
+    if rules.no_explicit && track.explicit {
+        reasons.push("This room doesn't allow explicit tracks.".to_string());
+    }
+
+    if let Some(max_duration_ms) = rules.max_duration_ms {
+        if track.duration_ms > max_duration_ms {
+            reasons.push(format!(
+                "Tracks can be at most {} long here, this one is {}.",
+                minutes(max_duration_ms),
+                minutes(track.duration_ms)
+            ));
+        }
+    }
+
+    if rules.min_year.is_some() || rules.max_year.is_some() {
+        //Release dates are a year, a month or a day depending on how precise Spotify is
+        let year = track
+            .album
+            .release_date
+            .get(..4)
+            .and_then(|year| year.parse::<i32>().ok());
+        match year {
+            None => reasons.push("Couldn't tell when this track was released.".to_string()),
+            Some(year) => {
+                if let Some(min_year) = rules.min_year.filter(|min_year| year < *min_year) {
+                    reasons.push(format!(
+                        "This room only plays tracks released {} or later, this one is from {}.",
+                        min_year, year
+                    ));
+                }
+                if let Some(max_year) = rules.max_year.filter(|max_year| year > *max_year) {
+                    reasons.push(format!(
+                        "This room only plays tracks released {} or earlier, this one is from {}.",
+                        max_year, year
+                    ));
+                }
+            }
+        }
+    }
+
+    if let Some(market) = &rules.market {
+        //is_playable is only there when Spotify checked a market itself
+        let unavailable = track.is_playable == Some(false)
+            || (!track.available_markets.is_empty() && !track.available_markets.contains(market));
+        if unavailable {
+            reasons.push(format!("This track isn't available in {}.", market));
+        }
+    }
+
+    reasons
+}
+
+//Like 4:05
+fn minutes(ms: u64) -> String {
+    let seconds = ms / 1000;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn track(explicit: bool, duration_ms: u64, release_date: &str) -> Track {
+        serde_json::from_value(serde_json::json!({
+            "album": {
+                "album_type": "album",
+                "total_tracks": 1,
+                "available_markets": ["SE"],
+                "external_urls": { "spotify": "" },
+                "href": "",
+                "id": "album",
+                "images": [],
+                "name": "Album",
+                "release_date": release_date,
+                "type": "album",
+                "uri": "spotify:album:album",
+                "artists": [],
+            },
+            "artists": [],
+            "available_markets": ["SE", "NO"],
+            "disc_number": 1,
+            "duration_ms": duration_ms,
+            "explicit": explicit,
+            "external_ids": {},
+            "external_urls": { "spotify": "" },
+            "href": "",
+            "id": "track",
+            "is_local": false,
+            "name": "Track",
+            "popularity": 50,
+            "track_number": 1,
+            "type": "track",
+            "uri": "spotify:track:track",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_no_rules_let_everything_in() {
+        let rules = AdmissionRules::default();
+        assert!(rejections(&rules, &track(true, 900_000, "")).is_empty());
+    }
+
+    #[test]
+    fn test_every_broken_rule_is_reported() {
+        let rules = AdmissionRules {
+            no_explicit: true,
+            max_duration_ms: Some(300_000),
+            min_year: Some(1980),
+            max_year: Some(1989),
+            market: Some("US".to_string()),
+        };
+
+        let reasons = rejections(&rules, &track(true, 365_000, "1979-06"));
+        assert_eq!(reasons.len(), 4);
+        assert_eq!(
+            reasons[1],
+            "Tracks can be at most 5:00 long here, this one is 6:05."
+        );
+        assert!(reasons[2].contains("from 1979"));
+
+        assert_eq!(rejections(&rules, &track(false, 200_000, "1985")).len(), 1);
+    }
+
+    #[test]
+    fn test_release_year_bounds_are_inclusive() {
+        let rules = AdmissionRules {
+            min_year: Some(1980),
+            max_year: Some(1989),
+            ..Default::default()
+        };
+
+        assert!(rejections(&rules, &track(false, 1, "1980-01-01")).is_empty());
+        assert!(rejections(&rules, &track(false, 1, "1989")).is_empty());
+        assert_eq!(rejections(&rules, &track(false, 1, "1990")).len(), 1);
+        assert_eq!(rejections(&rules, &track(false, 1, "")).len(), 1);
+    }
+}
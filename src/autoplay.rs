@@ -0,0 +1,44 @@
+use crate::db::Db;
+use crate::error::Result;
+use crate::spotify::{self, tracks::Track};
+
+//Keeps a room from going silent when nobody has anything queued.
+//Tracks are recommended by Spotify based on what the room played last, using the token of someone listening.
+
+//How many recommendations to ask for, the ones the room just played are skipped
+const CANDIDATES: u32 = 10;
+//Spotify takes up to 5 seed tracks
+const SEEDS: usize = 5;
+
+//A track to fill the silence with, None if there's nobody to play it for or nothing to base it on
+pub async fn pick(db: &Db, spotify: &spotify::Spotify, room_id: String) -> Result<Option<Track>> {
+    let seeds: Vec<String> = db
+        .list_history(room_id.clone(), None, SEEDS)
+        .await?
+        .into_iter()
+        .map(|entry| entry.track_id)
+        .collect();
+    if seeds.is_empty() {
+        return Ok(None);
+    }
+
+    let mut token = None;
+    for user_id in db.list_presences(room_id).await? {
+        token = db.get_auth(user_id).await?;
+        if token.is_some() {
+            break;
+        }
+    }
+    let Some(token) = token else {
+        return Ok(None);
+    };
+
+    let recommendations = spotify
+        .request_recommendations(token, seeds.clone(), CANDIDATES)
+        .await?;
+
+    Ok(recommendations
+        .tracks
+        .into_iter()
+        .find(|track| !seeds.contains(&track.uri)))
+}
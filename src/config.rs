@@ -0,0 +1,445 @@
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use sodiumoxide::crypto::secretbox;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use warp::Filter;
+
+//Everything configurable about the server, read once at startup by load()
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub redis_url: String,
+    pub cookie_key: String,
+    pub www_dir: PathBuf,
+    pub log_format: LogFormat,
+    pub spotify: SpotifyConfig,
+    pub timings: Timings,
+    pub rooms: RoomConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    //One JSON object per line, including the fields of every enclosing span
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpotifyConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub return_url: String,
+    pub api_url: String,
+    pub accounts_url: String,
+    //Retries for 429, 5xx and network errors, delays grow exponentially from retry_base_delay_ms
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64,
+    //A 429 asking to wait longer than this fails right away instead of holding the caller up
+    pub max_retry_after_secs: u64,
+    //Requests allowed per budget_window_secs, for the whole instance and for a single user
+    pub budget_window_secs: u64,
+    pub global_budget: u32,
+    pub token_budget: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timings {
+    //How often a connected user refreshes their presence, has to be shorter than presence_expiry_secs
+    pub presence_heartbeat_secs: u64,
+    pub presence_expiry_secs: u64,
+    //How often a worker refreshes its room claim, has to be shorter than room_claim_expiry_secs
+    pub room_claim_refresh_secs: u64,
+    pub room_claim_expiry_secs: u64,
+    //How long a worker waits for a free room before asking again
+    pub room_claim_timeout_secs: u64,
+    //Spotify needs a moment before a freshly connected device accepts playback
+    pub device_settle_secs: u64,
+    //How long shutdown waits for rooms and sockets to clean up, has to cover room_claim_timeout_secs
+    pub shutdown_timeout_secs: u64,
+    //How often idle rooms and leftover queues are looked for, see crate::janitor
+    pub janitor_interval_secs: u64,
+}
+
+//How rooms behave, the same for every room on the instance
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomConfig {
+    //Fraction of the listeners in the room that have to vote to skip the playing track
+    pub vote_skip_threshold: f64,
+    //Play Spotify recommendations based on the last few tracks when nothing is queued, off unless turned on
+    pub autoplay: bool,
+    //A track can't be queued again while it's queued or for this long after it played, 0 only checks the queues
+    pub duplicate_cooldown_secs: u64,
+    //How long someone who lost their connection keeps their place in the rotation and their queue
+    pub reconnect_grace_secs: u64,
+    //Rooms nobody has been in for this long are archived or deleted, 0 turns this off
+    pub idle_room_secs: u64,
+    pub idle_room_action: IdleRoomAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleRoomAction {
+    //Keeps the room and its settings but clears its queues and chat, the next visit brings it back
+    #[default]
+    Archive,
+    Delete,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3030)),
+            redis_url: String::new(),
+            cookie_key: String::new(),
+            www_dir: PathBuf::from("www"),
+            log_format: LogFormat::default(),
+            spotify: SpotifyConfig::default(),
+            timings: Timings::default(),
+            rooms: RoomConfig::default(),
+        }
+    }
+}
+
+impl Default for SpotifyConfig {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            return_url: String::new(),
+            api_url: "https://api.spotify.com".to_string(),
+            accounts_url: "https://accounts.spotify.com".to_string(),
+            max_retries: 3,
+            retry_base_delay_ms: 250,
+            max_retry_after_secs: 10,
+            budget_window_secs: 30,
+            global_budget: 900,
+            token_budget: 150,
+        }
+    }
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            presence_heartbeat_secs: 3,
+            presence_expiry_secs: 5,
+            room_claim_refresh_secs: 3,
+            room_claim_expiry_secs: 5,
+            room_claim_timeout_secs: 5,
+            device_settle_secs: 3,
+            shutdown_timeout_secs: 10,
+            janitor_interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            vote_skip_threshold: 0.5,
+            autoplay: false,
+            duplicate_cooldown_secs: 3600,
+            reconnect_grace_secs: 60,
+            idle_room_secs: 30 * 24 * 60 * 60,
+            idle_room_action: IdleRoomAction::default(),
+        }
+    }
+}
+
+impl SpotifyConfig {
+    pub fn retry_base_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_base_delay_ms)
+    }
+
+    pub fn max_retry_after(&self) -> Duration {
+        Duration::from_secs(self.max_retry_after_secs)
+    }
+
+    pub fn budget_window(&self) -> Duration {
+        Duration::from_secs(self.budget_window_secs)
+    }
+}
+
+impl RoomConfig {
+    pub fn duplicate_cooldown(&self) -> Duration {
+        Duration::from_secs(self.duplicate_cooldown_secs)
+    }
+
+    pub fn reconnect_grace(&self) -> Duration {
+        Duration::from_secs(self.reconnect_grace_secs)
+    }
+
+    pub fn idle_room(&self) -> Duration {
+        Duration::from_secs(self.idle_room_secs)
+    }
+}
+
+impl Timings {
+    pub fn presence_heartbeat(&self) -> Duration {
+        Duration::from_secs(self.presence_heartbeat_secs)
+    }
+
+    pub fn presence_expiry(&self) -> Duration {
+        Duration::from_secs(self.presence_expiry_secs)
+    }
+
+    pub fn room_claim_refresh(&self) -> Duration {
+        Duration::from_secs(self.room_claim_refresh_secs)
+    }
+
+    pub fn room_claim_expiry(&self) -> Duration {
+        Duration::from_secs(self.room_claim_expiry_secs)
+    }
+
+    pub fn room_claim_timeout(&self) -> Duration {
+        Duration::from_secs(self.room_claim_timeout_secs)
+    }
+
+    pub fn device_settle(&self) -> Duration {
+        Duration::from_secs(self.device_settle_secs)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn janitor_interval(&self) -> Duration {
+        Duration::from_secs(self.janitor_interval_secs)
+    }
+}
+
+//Reads the TOML file pointed to by CONFIG_FILE (if any), then applies environment variables on top
+pub fn load() -> Result<Config> {
+    let file = match std::env::var("CONFIG_FILE") {
+        Ok(path) => Some(
+            std::fs::read_to_string(&path)
+                .map_err(|err| Error::Config(vec![format!("Can't read {}: {}", path, err)]))?,
+        ),
+        Err(_) => None,
+    };
+
+    Config::from_sources(file.as_deref(), |name| std::env::var(name).ok())
+}
+
+pub fn with(
+    config: Config,
+) -> impl Filter<Extract = (Config,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || config.clone())
+}
+
+impl Config {
+    fn from_sources(file: Option<&str>, env: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut config: Config = match file {
+            Some(file) => toml::from_str(file)
+                .map_err(|err| Error::Config(vec![format!("Invalid config file: {}", err)]))?,
+            None => Config::default(),
+        };
+        let mut errors = Vec::new();
+
+        if let Some(bind) = env("BIND_ADDRESS") {
+            match bind.parse() {
+                Ok(bind) => config.bind = bind,
+                Err(_) => errors.push(format!("BIND_ADDRESS {} isn't a valid address", bind)),
+            }
+        }
+
+        let overrides = [
+            ("REDIS_URL", &mut config.redis_url),
+            ("COOKIE_KEY", &mut config.cookie_key),
+            ("SPOTIFY_CLIENT_ID", &mut config.spotify.client_id),
+            ("SPOTIFY_CLIENT_SECRET", &mut config.spotify.client_secret),
+            ("SPOTIFY_RETURN_URL", &mut config.spotify.return_url),
+            ("SPOTIFY_API_URL", &mut config.spotify.api_url),
+            ("SPOTIFY_ACCOUNTS_URL", &mut config.spotify.accounts_url),
+        ];
+        for (name, field) in overrides {
+            if let Some(value) = env(name) {
+                *field = value;
+            }
+        }
+
+        if let Some(www_dir) = env("WWW_DIR") {
+            config.www_dir = PathBuf::from(www_dir);
+        }
+
+        if let Some(log_format) = env("LOG_FORMAT") {
+            match &log_format[..] {
+                "text" => config.log_format = LogFormat::Text,
+                "json" => config.log_format = LogFormat::Json,
+                _ => errors.push(format!(
+                    "LOG_FORMAT {} isn't one of text or json",
+                    log_format
+                )),
+            }
+        }
+
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(Error::Config(errors))
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        let required = [
+            ("REDIS_URL", &self.redis_url),
+            ("COOKIE_KEY", &self.cookie_key),
+            ("SPOTIFY_CLIENT_ID", &self.spotify.client_id),
+            ("SPOTIFY_CLIENT_SECRET", &self.spotify.client_secret),
+            ("SPOTIFY_RETURN_URL", &self.spotify.return_url),
+        ];
+        for (name, value) in required {
+            if value.is_empty() {
+                errors.push(format!("{} is not set", name));
+            }
+        }
+
+        if !self.cookie_key.is_empty() && self.cookie_key.len() != secretbox::KEYBYTES {
+            errors.push(format!(
+                "COOKIE_KEY has to be {} bytes long",
+                secretbox::KEYBYTES
+            ));
+        }
+
+        let spotify = &self.spotify;
+        if spotify.budget_window_secs == 0
+            || spotify.global_budget == 0
+            || spotify.token_budget == 0
+        {
+            errors.push(
+                "budget_window_secs, global_budget and token_budget have to be above 0".to_string(),
+            );
+        }
+
+        let timings = &self.timings;
+        if timings.presence_heartbeat_secs == 0
+            || timings.presence_heartbeat_secs >= timings.presence_expiry_secs
+        {
+            errors.push(
+                "presence_heartbeat_secs has to be above 0 and below presence_expiry_secs"
+                    .to_string(),
+            );
+        }
+        if timings.room_claim_refresh_secs == 0
+            || timings.room_claim_refresh_secs >= timings.room_claim_expiry_secs
+        {
+            errors.push(
+                "room_claim_refresh_secs has to be above 0 and below room_claim_expiry_secs"
+                    .to_string(),
+            );
+        }
+        if timings.room_claim_timeout_secs == 0 {
+            errors.push("room_claim_timeout_secs has to be above 0".to_string());
+        }
+        if timings.shutdown_timeout_secs <= timings.room_claim_timeout_secs {
+            errors
+                .push("shutdown_timeout_secs has to be above room_claim_timeout_secs".to_string());
+        }
+        if timings.janitor_interval_secs == 0 {
+            errors.push("janitor_interval_secs has to be above 0".to_string());
+        }
+
+        let threshold = self.rooms.vote_skip_threshold;
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            errors.push("vote_skip_threshold has to be above 0 and at most 1".to_string());
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn required_env() -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("REDIS_URL", "redis://localhost"),
+            ("COOKIE_KEY", "00000000000000000000000000000000"),
+            ("SPOTIFY_CLIENT_ID", "id"),
+            ("SPOTIFY_CLIENT_SECRET", "secret"),
+            ("SPOTIFY_RETURN_URL", "http://localhost:3030/authorize"),
+        ]
+    }
+
+    #[test]
+    fn test_env_only_uses_defaults() {
+        let config = Config::from_sources(None, env(&required_env())).unwrap();
+
+        assert_eq!(config.bind, SocketAddr::from(([0, 0, 0, 0], 3030)));
+        assert_eq!(config.www_dir, PathBuf::from("www"));
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert_eq!(config.spotify.api_url, "https://api.spotify.com");
+        assert_eq!(config.timings.presence_heartbeat(), Duration::from_secs(3));
+        assert!(!config.rooms.autoplay);
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let file = r#"
+            bind = "127.0.0.1:8080"
+            redis_url = "redis://from-file"
+
+            [timings]
+            presence_heartbeat_secs = 1
+        "#;
+        let config = Config::from_sources(Some(file), env(&required_env())).unwrap();
+
+        assert_eq!(config.bind, SocketAddr::from(([127, 0, 0, 1], 8080)));
+        assert_eq!(config.redis_url, "redis://localhost");
+        assert_eq!(config.timings.presence_heartbeat_secs, 1);
+        assert_eq!(config.timings.presence_expiry_secs, 5);
+    }
+
+    #[test]
+    fn test_reports_every_problem() {
+        let file = r#"
+            [timings]
+            presence_heartbeat_secs = 5
+
+            [rooms]
+            vote_skip_threshold = 1.5
+        "#;
+        let env = env(&[("COOKIE_KEY", "short"), ("LOG_FORMAT", "xml")]);
+        let errors = match Config::from_sources(Some(file), env) {
+            Err(Error::Config(errors)) => errors,
+            other => panic!("Expected config errors, got {:?}", other),
+        };
+
+        assert!(errors.contains(&"REDIS_URL is not set".to_string()));
+        assert!(errors.contains(&"SPOTIFY_RETURN_URL is not set".to_string()));
+        assert!(errors.contains(&"COOKIE_KEY has to be 32 bytes long".to_string()));
+        assert!(errors.contains(&"LOG_FORMAT xml isn't one of text or json".to_string()));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("presence_heartbeat_secs")));
+        assert!(errors.iter().any(|e| e.starts_with("vote_skip_threshold")));
+    }
+
+    #[test]
+    fn test_rejects_unknown_keys() {
+        let result = Config::from_sources(Some("redis = \"typo\""), env(&required_env()));
+
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+}
@@ -1,20 +1,39 @@
+use crate::config::Config;
+use crate::error::Error;
 use sodiumoxide::crypto::secretbox;
 use warp::Filter;
 
-pub fn with_user() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
-    warp::cookie("userid").map(|cookie| decrypt_cookie(cookie))
+pub type CookieKey = secretbox::Key;
+
+pub fn init(config: &Config) -> CookieKey {
+    secretbox::Key::from_slice(config.cookie_key.as_bytes())
+        .expect("COOKIE_KEY length is checked when loading the config")
+}
+
+pub fn with(
+    key: CookieKey,
+) -> impl Filter<Extract = (CookieKey,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || key.clone())
 }
 
-pub fn gen_user(user_id: String) -> String {
-    encrypt_cookie(user_id)
+pub fn with_user(
+    key: CookieKey,
+) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::cookie("userid")
+        .and(with(key))
+        .and_then(|cookie, key| async move {
+            decrypt_cookie(&key, cookie).map_err(warp::reject::custom)
+        })
 }
 
-fn encrypt_cookie(data: String) -> String {
-    let key_raw = std::env::var("COOKIE_KEY").unwrap();
-    let key = secretbox::Key::from_slice(key_raw.as_bytes()).unwrap();
+pub fn gen_user(key: &CookieKey, user_id: String) -> String {
+    encrypt_cookie(key, user_id)
+}
+
+fn encrypt_cookie(key: &CookieKey, data: String) -> String {
     let nonce = secretbox::gen_nonce();
 
-    let chypertext = secretbox::seal(data.as_bytes(), &nonce, &key);
+    let chypertext = secretbox::seal(data.as_bytes(), &nonce, key);
 
     let nonce_out = base64::encode(nonce);
     let chypertext_out = base64::encode(chypertext);
@@ -22,16 +41,17 @@ fn encrypt_cookie(data: String) -> String {
     format!("{}:{}", nonce_out, chypertext_out)
 }
 
-fn decrypt_cookie(cookie: String) -> String {
-    let parts: Vec<&str> = cookie.split(":").collect();
-    let nonce_in = base64::decode(parts[0]).unwrap();
-    let chypertext_in = base64::decode(parts[1]).unwrap();
+fn decrypt_cookie(key: &CookieKey, cookie: String) -> Result<String, Error> {
+    let invalid = || Error::Auth("Invalid user cookie".to_string());
+
+    let (nonce_in, chypertext_in) = cookie.split_once(":").ok_or_else(invalid)?;
+    let nonce_in = base64::decode(nonce_in).map_err(|_| invalid())?;
+    let chypertext_in = base64::decode(chypertext_in).map_err(|_| invalid())?;
 
-    let key_raw = std::env::var("COOKIE_KEY").unwrap();
-    let key = secretbox::Key::from_slice(key_raw.as_bytes()).unwrap();
-    let nonce = secretbox::Nonce::from_slice(&nonce_in).unwrap();
+    let nonce = secretbox::Nonce::from_slice(&nonce_in).ok_or_else(invalid)?;
 
-    String::from_utf8(secretbox::open(&chypertext_in, &nonce, &key).unwrap()).unwrap()
+    let data = secretbox::open(&chypertext_in, &nonce, key).map_err(|_| invalid())?;
+    String::from_utf8(data).map_err(|_| invalid())
 }
 
 #[cfg(test)]
@@ -40,18 +60,18 @@ mod tests {
 
     use super::*;
 
-    fn gen_cookie_key() {
+    fn gen_cookie_key() -> CookieKey {
         let cookie_key = "0".to_string().repeat(secretbox::KEYBYTES);
-        std::env::set_var("COOKIE_KEY", cookie_key);
+        secretbox::Key::from_slice(cookie_key.as_bytes()).unwrap()
     }
 
     #[test]
     fn test_decrypting_encrypted_data_should_return_same_data() {
-        gen_cookie_key();
+        let key = gen_cookie_key();
         let input_data = "this:is:an:user:id".to_string();
 
-        let encrypted = encrypt_cookie(input_data.clone());
-        let decrypted = decrypt_cookie(encrypted.clone());
+        let encrypted = encrypt_cookie(&key, input_data.clone());
+        let decrypted = decrypt_cookie(&key, encrypted.clone()).unwrap();
 
         assert_ne!(encrypted, input_data);
         assert_eq!(decrypted, input_data);
@@ -59,17 +79,17 @@ mod tests {
 
     #[tokio::test]
     async fn test_cookie_creation_and_extraction() {
-        gen_cookie_key();
+        let key = gen_cookie_key();
         let user_id = "this:is:user:data".to_string();
 
         let test_filter = warp::get()
-            .and(with_user())
+            .and(with_user(key.clone()))
             .and_then(|cookie| async {
-                let res: Result<String, Infallible>  = Ok(cookie);
+                let res: Result<String, Infallible> = Ok(cookie);
                 res
             });
 
-        let cookie = gen_user(user_id.clone());
+        let cookie = gen_user(&key, user_id.clone());
         let res = warp::test::request()
             .header("Cookie", format!("userid={}", cookie))
             .reply(&test_filter)
@@ -77,4 +97,12 @@ mod tests {
 
         assert_eq!(res.body(), user_id.as_bytes());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_decrypting_garbage_should_fail() {
+        let key = gen_cookie_key();
+
+        assert!(decrypt_cookie(&key, "garbage".to_string()).is_err());
+        assert!(decrypt_cookie(&key, "bm9uY2U=:dGV4dA==".to_string()).is_err());
+    }
+}
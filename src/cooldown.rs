@@ -0,0 +1,106 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::db::Db;
+use crate::error::{Error, Result};
+use crate::spotify::tracks::Track;
+
+//Keeps a room from hearing the same song over and over.
+//A track is turned away while it's queued anywhere in the room or if it played there within the cooldown,
+//re-releases of the same recording are caught by the ISRCs stored as tracks are queued and played.
+//A cooldown of 0 only keeps out what's queued.
+
+//How much history is read at once
+const BATCH: usize = 50;
+
+pub async fn check(
+    db: &Db,
+    room_id: String,
+    user_id: String,
+    track_id: String,
+    track: &Track,
+    cooldown: Duration,
+) -> Result<()> {
+    let taken = taken_tracks(db, room_id.clone(), user_id, cooldown).await?;
+    if taken.contains(&track_id) {
+        return Err(duplicate(cooldown));
+    }
+    let Some(isrc) = &track.external_ids.isrc else {
+        return Ok(());
+    };
+
+    let isrcs = db.list_isrcs(room_id, taken).await?;
+    if isrcs.iter().any(|taken| taken.as_ref() == Some(isrc)) {
+        return Err(duplicate(cooldown));
+    }
+
+    Ok(())
+}
+
+//Everything queued in the room, by the DJs in the rotation and the user, plus what played within the cooldown
+async fn taken_tracks(
+    db: &Db,
+    room_id: String,
+    user_id: String,
+    cooldown: Duration,
+) -> Result<Vec<String>> {
+    let mut taken = if cooldown.is_zero() {
+        Vec::new()
+    } else {
+        played_tracks(db, room_id.clone(), cooldown).await?
+    };
+
+    let mut users = db.list_queue(room_id.clone()).await?;
+    if !users.contains(&user_id) {
+        users.push(user_id);
+    }
+    for user_id in users {
+        taken.extend(db.list_user_queue(room_id.clone(), user_id).await?);
+    }
+    taken.extend(
+        db.list_pool(room_id)
+            .await?
+            .into_iter()
+            .map(|track| track.track_id),
+    );
+
+    taken.sort();
+    taken.dedup();
+    Ok(taken)
+}
+
+async fn played_tracks(db: &Db, room_id: String, cooldown: Duration) -> Result<Vec<String>> {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .saturating_sub(cooldown)
+        .as_millis();
+
+    let mut played = Vec::new();
+    let mut before = None;
+    loop {
+        let page = db.list_history(room_id.clone(), before, BATCH).await?;
+        let full = page.len() == BATCH;
+        for entry in page {
+            if entry.start_time < since {
+                return Ok(played);
+            }
+            before = Some(entry.id);
+            played.push(entry.track_id);
+        }
+        if !full {
+            return Ok(played);
+        }
+    }
+}
+
+fn duplicate(cooldown: Duration) -> Error {
+    let message = if cooldown.is_zero() {
+        "That track is already queued here.".to_string()
+    } else {
+        format!(
+            "That track is already queued or played here in the last {} minutes.",
+            cooldown.as_secs().div_ceil(60)
+        )
+    };
+    Error::Validation(vec![message])
+}
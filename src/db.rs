@@ -1,54 +1,142 @@
+use crate::config::{Config, Timings};
+use crate::error::{Error, Result};
+use crate::metrics::METRICS;
+use redis::aio::{Connection, MultiplexedConnection};
 use redis::Client;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use warp::Filter;
 
 pub mod auth;
 pub mod device;
+pub mod health;
+pub mod history;
+pub mod invite;
+pub mod memory;
 pub mod message;
+pub mod moderation;
+pub mod notice;
 pub mod playing;
+pub mod pool;
 pub mod presence;
 pub mod queue;
 pub mod room;
+pub mod schedule;
 
-pub type Db = Arc<Mutex<DbInternal>>;
+pub type Db = Arc<dyn Storage>;
 
-pub fn connect_db() -> Db {
-    Arc::new(Mutex::new(DbInternal::init()))
+//Key expiry (presence, room claims) is noticed through these notifications
+pub const KEYSPACE_EVENTS: &str = "Knxg";
+
+pub async fn connect_db(config: &Config) -> Result<Db> {
+    Ok(Arc::new(
+        DbInternal::init(config.redis_url.clone(), config.timings.clone()).await?,
+    ))
+}
+
+pub fn connect_memory_db(config: &Config) -> Db {
+    Arc::new(memory::MemoryDb::init(config.timings.clone()))
 }
 
 pub fn with(db: Db) -> impl Filter<Extract = (Db,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
 
+//Everything the rest of the app needs from a database, implemented by Redis (DbInternal) and memory (MemoryDb)
+pub trait Storage:
+    auth::AuthStorage
+    + device::DeviceStorage
+    + health::HealthStorage
+    + history::HistoryStorage
+    + invite::InviteStorage
+    + message::MessageStorage
+    + moderation::ModerationStorage
+    + notice::NoticeStorage
+    + playing::PlayingStorage
+    + pool::PoolStorage
+    + presence::PresenceStorage
+    + queue::QueueStorage
+    + room::RoomStorage
+    + schedule::ScheduleStorage
+    + Send
+    + Sync
+{
+}
+
+impl<T> Storage for T where
+    T: auth::AuthStorage
+        + device::DeviceStorage
+        + health::HealthStorage
+        + history::HistoryStorage
+        + invite::InviteStorage
+        + message::MessageStorage
+        + moderation::ModerationStorage
+        + notice::NoticeStorage
+        + playing::PlayingStorage
+        + pool::PoolStorage
+        + presence::PresenceStorage
+        + queue::QueueStorage
+        + room::RoomStorage
+        + schedule::ScheduleStorage
+        + Send
+        + Sync
+{
+}
+
 pub struct DbInternal {
     url: String,
     client: Client,
+    con: MultiplexedConnection,
+    timings: Timings,
 }
 
 impl DbInternal {
-    fn init() -> Self {
-        let url = std::env::var("REDIS_URL").unwrap();
-        let client = Client::open(url.clone()).unwrap();
+    async fn init(url: String, timings: Timings) -> Result<Self> {
+        let client = Client::open(url.clone())?;
+        let con = client.get_multiplexed_tokio_connection().await?;
 
-        let internal = Self { url, client };
-        internal.enable_keyspace_events();
+        let internal = Self {
+            url,
+            client,
+            con,
+            timings,
+        };
+        internal.enable_keyspace_events().await?;
 
-        internal
+        Ok(internal)
     }
 
-    fn blockable_client(&self) -> Client {
-        Client::open(self.url.clone()).unwrap()
+    //Shared by all regular commands, cloning it is cheap and commands from all clones are pipelined together
+    fn con(&self) -> MultiplexedConnection {
+        self.con.clone()
     }
 
-    fn enable_keyspace_events(&self) {
-        let mut con = self.client.get_connection().unwrap();
+    //Blocking reads (XREAD, BLPOP, pubsub) would stall the shared connection so they get their own client
+    fn blockable_client(&self) -> Result<Client> {
+        Ok(Client::open(self.url.clone())?)
+    }
+
+    async fn transaction_connection(&self) -> Result<Connection> {
+        Ok(self.client.get_async_connection().await?)
+    }
+
+    async fn enable_keyspace_events(&self) -> Result<()> {
+        let mut con = self.con();
         let _: () = redis::cmd("CONFIG")
             .arg("set")
             .arg("notify-keyspace-events")
-            .arg("Knxg")
-            .query(&mut con)
-            .unwrap();
+            .arg(KEYSPACE_EVENTS)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+}
+
+//Every failed Redis command passes through here on its way out of the db layer
+impl From<redis::RedisError> for Error {
+    fn from(err: redis::RedisError) -> Self {
+        let kind = format!("{:?}", err.kind());
+        METRICS.redis_errors.with_label_values(&[&kind]).inc();
+        Error::Redis(err)
     }
 }
 
@@ -1,42 +1,75 @@
 use crate::db;
+use crate::error::{Error, Result};
+use async_trait::async_trait;
 use redis::AsyncCommands;
 use std::collections::HashMap;
 
+#[async_trait]
+pub trait AuthStorage {
+    async fn set_auth(
+        &self,
+        user_id: String,
+        access_token: String,
+        refresh_token: String,
+    ) -> Result<()>;
+    async fn get_auth(&self, user_id: String) -> Result<Option<Auth>>;
+
+    //For when there is no sensible way to continue without the users tokens
+    async fn require_auth(&self, user_id: String) -> Result<Auth> {
+        self.get_auth(user_id.clone())
+            .await?
+            .ok_or_else(|| Error::Auth(format!("No Spotify tokens stored for {}", user_id)))
+    }
+}
+
 impl db::DbInternal {
     fn key_auth(user_id: String) -> String {
         format!("{}:auth", user_id)
     }
+}
 
-    pub async fn set_auth(&mut self, user_id: String, access_token: String, refresh_token: String) {
-        let mut data = Vec::new();
-        data.push(("access_token".to_string(), access_token));
-        data.push(("refresh_token".to_string(), refresh_token));
+#[async_trait]
+impl AuthStorage for db::DbInternal {
+    async fn set_auth(
+        &self,
+        user_id: String,
+        access_token: String,
+        refresh_token: String,
+    ) -> Result<()> {
+        let data = [
+            ("access_token".to_string(), access_token),
+            ("refresh_token".to_string(), refresh_token),
+        ];
 
-        let mut con = self.client.get_async_connection().await.unwrap();
+        let mut con = self.con();
         let _: () = con
             .hset_multiple(Self::key_auth(user_id), &data[..])
-            .await
-            .unwrap();
+            .await?;
+        Ok(())
     }
 
-    pub async fn get_auth(&mut self, user_id: String) -> Option<Auth> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let data: Option<HashMap<String, String>> =
-            con.hgetall(Self::key_auth(user_id.clone())).await.unwrap();
-
-        match data {
-            Some(data) => {
-                let access_token = data.get("access_token").unwrap().clone();
-                let refresh_token = data.get("refresh_token").unwrap().clone();
-
-                Some(Auth {
-                    user_id: Some(user_id),
-                    access_token,
-                    refresh_token,
-                })
-            }
-            None => None,
+    async fn get_auth(&self, user_id: String) -> Result<Option<Auth>> {
+        let mut con = self.con();
+        let data: HashMap<String, String> = con.hgetall(Self::key_auth(user_id.clone())).await?;
+
+        if data.is_empty() {
+            return Ok(None);
         }
+
+        let access_token = data
+            .get("access_token")
+            .ok_or_else(|| Error::Decode(format!("access_token of {}", user_id)))?
+            .clone();
+        let refresh_token = data
+            .get("refresh_token")
+            .ok_or_else(|| Error::Decode(format!("refresh_token of {}", user_id)))?
+            .clone();
+
+        Ok(Some(Auth {
+            user_id: Some(user_id),
+            access_token,
+            refresh_token,
+        }))
     }
 }
 
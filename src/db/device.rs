@@ -1,18 +1,30 @@
 use crate::db;
+use crate::error::Result;
+use async_trait::async_trait;
 use redis::AsyncCommands;
 
+#[async_trait]
+pub trait DeviceStorage {
+    async fn set_device(&self, user_id: String, device_id: String) -> Result<()>;
+    async fn get_device(&self, user_id: String) -> Result<Option<String>>;
+}
+
 impl db::DbInternal {
     fn key_device(user_id: String) -> String {
         format!("{}:device", user_id)
     }
+}
 
-    pub async fn set_device(&mut self, user_id: String, device_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con.set(Self::key_device(user_id), device_id).await.unwrap();
+#[async_trait]
+impl DeviceStorage for db::DbInternal {
+    async fn set_device(&self, user_id: String, device_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.set(Self::key_device(user_id), device_id).await?;
+        Ok(())
     }
 
-    pub async fn get_device(&mut self, user_id: String) -> Option<String> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        con.get(Self::key_device(user_id)).await.ok()
+    async fn get_device(&self, user_id: String) -> Result<Option<String>> {
+        let mut con = self.con();
+        Ok(con.get(Self::key_device(user_id)).await?)
     }
 }
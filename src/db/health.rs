@@ -0,0 +1,50 @@
+use crate::db;
+use crate::error::Result;
+use async_trait::async_trait;
+
+#[async_trait]
+pub trait HealthStorage {
+    async fn ping(&self) -> Result<()>;
+    async fn keyspace_events_enabled(&self) -> Result<bool>;
+}
+
+#[async_trait]
+impl HealthStorage for db::DbInternal {
+    async fn ping(&self) -> Result<()> {
+        let mut con = self.con();
+        let _: String = redis::cmd("PING").query_async(&mut con).await?;
+        Ok(())
+    }
+
+    async fn keyspace_events_enabled(&self) -> Result<bool> {
+        let mut con = self.con();
+        let (_, flags): (String, String) = redis::cmd("CONFIG")
+            .arg("get")
+            .arg("notify-keyspace-events")
+            .query_async(&mut con)
+            .await?;
+
+        Ok(covers_keyspace_events(&flags))
+    }
+}
+
+//Redis normalizes the flags it was given, e.g. "Knxg" might come back as "gxKn" or with A standing in for g and x
+fn covers_keyspace_events(flags: &str) -> bool {
+    db::KEYSPACE_EVENTS
+        .chars()
+        .all(|flag| flags.contains(flag) || (flags.contains('A') && "g$lshzxetd".contains(flag)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_covers_keyspace_events() {
+        assert!(covers_keyspace_events("Knxg"));
+        assert!(covers_keyspace_events("gxKn"));
+        assert!(covers_keyspace_events("AKn"));
+        assert!(!covers_keyspace_events("Kxg"));
+        assert!(!covers_keyspace_events(""));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::error::{Error, Result};
+
+//Every track a room has played, never trimmed
+#[async_trait]
+pub trait HistoryStorage {
+    //The id is ignored, entries are numbered from 1 in the order they're added
+    async fn add_history(&self, room_id: String, entry: HistoryEntry) -> Result<HistoryEntry>;
+    //Newest first, only entries older than before when it's given
+    async fn list_history(
+        &self,
+        room_id: String,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>>;
+    //Marks the newest entry skipped if it's the track started at start_time, returns whether it was
+    async fn skip_history(&self, room_id: String, start_time: u128) -> Result<bool>;
+}
+
+//Numbering and storing the entry in one go, so there are no numbers without an entry.
+//KEYS: the sequence and the history key that entries are stored under, ARGV: the entry's fields and values
+const ADD_HISTORY_SCRIPT: &str = r"
+local id = redis.call('INCR', KEYS[1])
+redis.call('HSET', KEYS[2] .. ':' .. id, unpack(ARGV))
+return id
+";
+
+impl db::DbInternal {
+    fn key_history(room_id: String) -> String {
+        format!("room:{}:history", room_id)
+    }
+
+    fn key_history_seq(room_id: String) -> String {
+        format!("{}:seq", Self::key_history(room_id))
+    }
+
+    fn key_history_entry(room_id: String, id: u64) -> String {
+        format!("{}:{}", Self::key_history(room_id), id)
+    }
+}
+
+#[async_trait]
+impl HistoryStorage for db::DbInternal {
+    async fn add_history(&self, room_id: String, entry: HistoryEntry) -> Result<HistoryEntry> {
+        let mut con = self.con();
+        let data: Vec<(String, String)> = entry.clone().into();
+        let args: Vec<String> = data
+            .into_iter()
+            .flat_map(|(field, value)| [field, value])
+            .collect();
+
+        let id: u64 = redis::Script::new(ADD_HISTORY_SCRIPT)
+            .key(Self::key_history_seq(room_id.clone()))
+            .key(Self::key_history(room_id))
+            .arg(args)
+            .invoke_async(&mut con)
+            .await?;
+        Ok(HistoryEntry { id, ..entry })
+    }
+
+    async fn list_history(
+        &self,
+        room_id: String,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>> {
+        let mut con = self.con();
+        let newest: Option<u64> = con.get(Self::key_history_seq(room_id.clone())).await?;
+        let newest = newest.unwrap_or(0);
+        let start = before.map_or(newest, |before| before.saturating_sub(1).min(newest));
+        let ids: Vec<u64> = (1..=start).rev().take(limit).collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.hgetall(Self::key_history_entry(room_id.clone(), *id));
+        }
+        let replies: Vec<HashMap<String, String>> = pipe.query_async(&mut con).await?;
+
+        ids.into_iter()
+            .zip(replies)
+            //Gone if the room was deleted while it's read
+            .filter(|(_, data)| !data.is_empty())
+            .map(|(id, data)| {
+                Ok(HistoryEntry {
+                    id,
+                    ..HistoryEntry::try_from(data)?
+                })
+            })
+            .collect()
+    }
+
+    async fn skip_history(&self, room_id: String, start_time: u128) -> Result<bool> {
+        let mut con = self.con();
+        let newest: Option<u64> = con.get(Self::key_history_seq(room_id.clone())).await?;
+        let Some(newest) = newest else {
+            return Ok(false);
+        };
+
+        let key = Self::key_history_entry(room_id, newest);
+        let started: Option<String> = con.hget(key.clone(), "start_time").await?;
+        if started != Some(start_time.to_string()) {
+            return Ok(false);
+        }
+        let _: () = con.hset(key, "skipped", "true").await?;
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub track_id: String,
+    //Who picked it, missing for autoplay
+    pub user_id: Option<String>,
+    pub start_time: u128,
+    pub skipped: bool,
+}
+
+impl From<HistoryEntry> for Vec<(String, String)> {
+    fn from(entry: HistoryEntry) -> Self {
+        let mut data = vec![
+            ("track_id".to_string(), entry.track_id),
+            ("start_time".to_string(), entry.start_time.to_string()),
+            ("skipped".to_string(), entry.skipped.to_string()),
+        ];
+        if let Some(user_id) = entry.user_id {
+            data.push(("user_id".to_string(), user_id));
+        }
+
+        data
+    }
+}
+
+//The id isn't stored with the entry, it's filled in as 0
+impl TryFrom<HashMap<String, String>> for HistoryEntry {
+    type Error = Error;
+
+    fn try_from(data: HashMap<String, String>) -> Result<Self> {
+        let field = |name: &str| {
+            data.get(name)
+                .ok_or_else(|| Error::Decode(format!("history field {}", name)))
+        };
+
+        let track_id = field("track_id")?.clone();
+        let start_time = field("start_time")?
+            .parse::<u128>()
+            .map_err(|_| Error::Decode("history start_time".to_string()))?;
+
+        Ok(HistoryEntry {
+            id: 0,
+            track_id,
+            user_id: data.get("user_id").cloned(),
+            start_time,
+            skipped: data.get("skipped").is_some_and(|skipped| skipped == "true"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_entry_round_trip() {
+        for user_id in [Some("dj".to_string()), None] {
+            let entry = HistoryEntry {
+                id: 0,
+                track_id: "spotify:track:one".to_string(),
+                user_id,
+                start_time: 1_700_000_000_000,
+                skipped: true,
+            };
+            let data: Vec<(String, String)> = entry.clone().into();
+            let data: HashMap<String, String> = data.into_iter().collect();
+            assert_eq!(HistoryEntry::try_from(data).unwrap(), entry);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use redis::AsyncCommands;
+
+use crate::db;
+use crate::error::Result;
+
+//Who may join a private room, see crate::invite
+#[async_trait]
+pub trait InviteStorage {
+    //Forgotten once it expires
+    async fn add_invite(
+        &self,
+        room_id: String,
+        invite_id: String,
+        max_uses: u32,
+        expiry: Duration,
+    ) -> Result<()>;
+    //Returns whether the invite had a use left, which it takes
+    async fn use_invite(&self, room_id: String, invite_id: String) -> Result<bool>;
+    async fn add_member(&self, room_id: String, user_id: String) -> Result<()>;
+    async fn is_member(&self, room_id: String, user_id: String) -> Result<bool>;
+    //Which of the rooms the user is a member of, all in one go
+    async fn member_rooms(&self, room_ids: Vec<String>, user_id: String)
+        -> Result<HashSet<String>>;
+}
+
+//Uses left are only taken while there are some, DECR alone would go below zero
+const USE_INVITE_SCRIPT: &str = r"
+local left = tonumber(redis.call('GET', KEYS[1]))
+if not left or left <= 0 then
+    return 0
+end
+redis.call('DECR', KEYS[1])
+return 1
+";
+
+impl db::DbInternal {
+    fn key_invite(room_id: String, invite_id: String) -> String {
+        format!("room:{}:invite:{}", room_id, invite_id)
+    }
+
+    fn key_members(room_id: String) -> String {
+        format!("room:{}:members", room_id)
+    }
+}
+
+#[async_trait]
+impl InviteStorage for db::DbInternal {
+    async fn add_invite(
+        &self,
+        room_id: String,
+        invite_id: String,
+        max_uses: u32,
+        expiry: Duration,
+    ) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con
+            .set_ex(
+                Self::key_invite(room_id, invite_id),
+                max_uses,
+                expiry.as_secs() as usize,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn use_invite(&self, room_id: String, invite_id: String) -> Result<bool> {
+        let mut con = self.con();
+        let used: i32 = redis::Script::new(USE_INVITE_SCRIPT)
+            .key(Self::key_invite(room_id, invite_id))
+            .invoke_async(&mut con)
+            .await?;
+        Ok(used == 1)
+    }
+
+    async fn add_member(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.sadd(Self::key_members(room_id), user_id).await?;
+        Ok(())
+    }
+
+    async fn is_member(&self, room_id: String, user_id: String) -> Result<bool> {
+        let mut con = self.con();
+        Ok(con.sismember(Self::key_members(room_id), user_id).await?)
+    }
+
+    async fn member_rooms(
+        &self,
+        room_ids: Vec<String>,
+        user_id: String,
+    ) -> Result<HashSet<String>> {
+        if room_ids.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let mut con = self.con();
+        let mut pipe = redis::pipe();
+        for room_id in &room_ids {
+            pipe.sismember(Self::key_members(room_id.clone()), user_id.clone());
+        }
+        let members: Vec<bool> = pipe.query_async(&mut con).await?;
+
+        Ok(room_ids
+            .into_iter()
+            .zip(members)
+            .filter(|(_, member)| *member)
+            .map(|(room_id, _)| room_id)
+            .collect())
+    }
+}
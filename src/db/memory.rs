@@ -0,0 +1,1426 @@
+use crate::config::Timings;
+use crate::db::auth::{Auth, AuthStorage};
+use crate::db::device::DeviceStorage;
+use crate::db::health::HealthStorage;
+use crate::db::history::{HistoryEntry, HistoryStorage};
+use crate::db::invite::InviteStorage;
+use crate::db::message::{Message, MessageStorage};
+use crate::db::moderation::{ModerationStorage, Role};
+use crate::db::notice::NoticeStorage;
+use crate::db::playing::{Playing, PlayingStorage};
+use crate::db::pool::{PoolStorage, PoolTrack};
+use crate::db::presence::{PresenceEvent, PresenceEventActivty, PresenceStorage};
+use crate::db::queue::QueueStorage;
+use crate::db::room::{Room, RoomOverview, RoomStorage};
+use crate::db::schedule::ScheduleStorage;
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::{broadcast, mpsc, oneshot, Notify};
+use tokio::time::Instant;
+
+//In-process stand-in for Redis, used by tests and anything that can't reach a Redis server
+#[derive(Clone)]
+pub struct MemoryDb {
+    state: Arc<Mutex<State>>,
+    rooms_free: Arc<Notify>,
+    timings: Timings,
+}
+
+#[derive(Default)]
+struct State {
+    auth: HashMap<String, Auth>,
+    devices: HashMap<String, String>,
+    messages: HashMap<String, broadcast::Sender<Message>>,
+    message_seq: u64,
+    playing: HashMap<String, Playing>,
+    history: HashMap<String, Vec<HistoryEntry>>,
+    skip_votes: HashMap<String, HashSet<String>>,
+    pools: HashMap<String, Vec<PoolEntry>>,
+    pool_seq: u64,
+    presence: HashMap<(String, String), Instant>,
+    presence_subscribers: HashMap<String, Vec<mpsc::Sender<PresenceEvent>>>,
+    presences: HashMap<String, HashSet<String>>,
+    queues: HashMap<String, VecDeque<String>>,
+    user_queues: HashMap<(String, String), VecDeque<String>>,
+    stepping_down: HashSet<(String, String)>,
+    away: HashMap<String, HashMap<String, u128>>,
+    isrcs: HashMap<String, HashMap<String, String>>,
+    listening_time: HashMap<String, HashMap<String, u64>>,
+    dj_votes: HashMap<String, HashMap<String, u64>>,
+    upvoters: HashMap<String, HashSet<String>>,
+    roles: HashMap<String, HashMap<String, Role>>,
+    bans: HashMap<String, HashSet<String>>,
+    muted: HashMap<String, HashSet<String>>,
+    //Uses left and when it expires
+    invites: HashMap<(String, String), (u32, Instant)>,
+    members: HashMap<String, HashSet<String>>,
+    rooms: BTreeMap<String, Room>,
+    rooms_free: VecDeque<String>,
+    claims: HashMap<String, Instant>,
+    rooms_active: HashMap<String, u128>,
+    rooms_archived: HashSet<String>,
+    janitor_lease: Option<(String, Instant)>,
+    notices: HashMap<String, Vec<String>>,
+}
+
+//Scores are worked out from the votes when the pool is listed
+struct PoolEntry {
+    track: PoolTrack,
+    votes: HashMap<String, i8>,
+}
+
+impl MemoryDb {
+    pub fn init(timings: Timings) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::default())),
+            rooms_free: Arc::new(Notify::new()),
+            timings,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    async fn publish_presence(&self, room_id: String, event: PresenceEvent) {
+        let subscribers = {
+            let mut state = self.state();
+            let subscribers = state.presence_subscribers.entry(room_id).or_default();
+            subscribers.retain(|tx| !tx.is_closed());
+            subscribers.clone()
+        };
+
+        for tx in subscribers {
+            tx.send(event.clone()).await.unwrap_or(());
+        }
+    }
+
+    fn expire_presence(&self, room_id: String, user_id: String, deadline: Instant) {
+        //Mimics a Redis key expiring, only fires if nobody kept the presence alive in the meantime
+        let db = self.clone();
+        tokio::task::spawn(async move {
+            tokio::time::sleep_until(deadline).await;
+
+            let key = (room_id.clone(), user_id.clone());
+            let expired = {
+                let mut state = db.state();
+                if state.presence.get(&key) == Some(&deadline) {
+                    state.presence.remove(&key);
+                    true
+                } else {
+                    false
+                }
+            };
+
+            if expired {
+                let activity = PresenceEventActivty::Leave;
+                db.publish_presence(room_id, PresenceEvent { user_id, activity })
+                    .await;
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl AuthStorage for MemoryDb {
+    async fn set_auth(
+        &self,
+        user_id: String,
+        access_token: String,
+        refresh_token: String,
+    ) -> Result<()> {
+        let auth = Auth {
+            user_id: Some(user_id.clone()),
+            access_token,
+            refresh_token,
+        };
+        self.state().auth.insert(user_id, auth);
+        Ok(())
+    }
+
+    async fn get_auth(&self, user_id: String) -> Result<Option<Auth>> {
+        Ok(self.state().auth.get(&user_id).cloned())
+    }
+}
+
+#[async_trait]
+impl DeviceStorage for MemoryDb {
+    async fn set_device(&self, user_id: String, device_id: String) -> Result<()> {
+        self.state().devices.insert(user_id, device_id);
+        Ok(())
+    }
+
+    async fn get_device(&self, user_id: String) -> Result<Option<String>> {
+        Ok(self.state().devices.get(&user_id).cloned())
+    }
+}
+
+#[async_trait]
+impl HealthStorage for MemoryDb {
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    //Expiry is simulated directly, there is nothing to configure
+    async fn keyspace_events_enabled(&self) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl MessageStorage for MemoryDb {
+    async fn add_message(&self, room_id: String, mut message: Message) -> Result<()> {
+        let mut state = self.state();
+        state.message_seq += 1;
+
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        message.id = Some(format!("{}-{}", time, state.message_seq));
+
+        if let Some(tx) = state.messages.get(&room_id) {
+            //No receivers just means nobody is listening to this room right now
+            tx.send(message).unwrap_or(0);
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe_messages(&self, room_id: String) -> Result<mpsc::Receiver<Message>> {
+        let (tx, rx) = mpsc::channel(10);
+        let mut room_rx = self
+            .state()
+            .messages
+            .entry(room_id)
+            .or_insert_with(|| broadcast::channel(64).0)
+            .subscribe();
+
+        tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    message = room_rx.recv() => {
+                        match message {
+                            Ok(message) => {
+                                if tx.send(message).await.is_err() {
+                                    break;
+                                }
+                            },
+                            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                                tracing::debug!("Memory message subscription skipped {} messages", skipped);
+                            },
+                            Err(broadcast::error::RecvError::Closed) => {
+                                break;
+                            }
+                        }
+                    },
+                    _ = tx.closed() => {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+#[async_trait]
+impl PlayingStorage for MemoryDb {
+    async fn set_playing(&self, room_id: String, playing: Playing) -> Result<()> {
+        self.state().playing.insert(room_id, playing);
+        Ok(())
+    }
+
+    async fn get_playing(&self, room_id: String) -> Result<Option<Playing>> {
+        Ok(self.state().playing.get(&room_id).cloned())
+    }
+
+    async fn add_skip_vote(&self, room_id: String, user_id: String) -> Result<bool> {
+        Ok(self
+            .state()
+            .skip_votes
+            .entry(room_id)
+            .or_default()
+            .insert(user_id))
+    }
+
+    async fn list_skip_votes(&self, room_id: String) -> Result<Vec<String>> {
+        Ok(self
+            .state()
+            .skip_votes
+            .get(&room_id)
+            .map(|votes| votes.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn clear_skip_votes(&self, room_id: String) -> Result<()> {
+        self.state().skip_votes.remove(&room_id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HistoryStorage for MemoryDb {
+    async fn add_history(&self, room_id: String, entry: HistoryEntry) -> Result<HistoryEntry> {
+        let mut state = self.state();
+        let history = state.history.entry(room_id).or_default();
+        let entry = HistoryEntry {
+            id: history.len() as u64 + 1,
+            ..entry
+        };
+
+        history.push(entry.clone());
+        Ok(entry)
+    }
+
+    async fn list_history(
+        &self,
+        room_id: String,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>> {
+        let state = self.state();
+        let Some(history) = state.history.get(&room_id) else {
+            return Ok(Vec::new());
+        };
+
+        Ok(history
+            .iter()
+            .rev()
+            .filter(|entry| before.is_none_or(|before| entry.id < before))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn skip_history(&self, room_id: String, start_time: u128) -> Result<bool> {
+        let mut state = self.state();
+        match state
+            .history
+            .get_mut(&room_id)
+            .and_then(|history| history.last_mut())
+        {
+            Some(entry) if entry.start_time == start_time => {
+                entry.skipped = true;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[async_trait]
+impl PoolStorage for MemoryDb {
+    async fn add_pool_track(
+        &self,
+        room_id: String,
+        user_id: String,
+        track_id: String,
+    ) -> Result<PoolTrack> {
+        let mut state = self.state();
+        state.pool_seq += 1;
+        let track = PoolTrack {
+            id: state.pool_seq,
+            track_id,
+            user_id,
+            score: 0,
+        };
+
+        state.pools.entry(room_id).or_default().push(PoolEntry {
+            track: track.clone(),
+            votes: HashMap::new(),
+        });
+        Ok(track)
+    }
+
+    async fn list_pool(&self, room_id: String) -> Result<Vec<PoolTrack>> {
+        let state = self.state();
+        let Some(pool) = state.pools.get(&room_id) else {
+            return Ok(Vec::new());
+        };
+
+        Ok(pool
+            .iter()
+            .map(|entry| PoolTrack {
+                score: entry.votes.values().map(|vote| i64::from(*vote)).sum(),
+                ..entry.track.clone()
+            })
+            .collect())
+    }
+
+    async fn vote_pool_track(
+        &self,
+        room_id: String,
+        id: u64,
+        voter_id: String,
+        vote: i8,
+    ) -> Result<bool> {
+        let mut state = self.state();
+        let Some(entry) = state
+            .pools
+            .get_mut(&room_id)
+            .and_then(|pool| pool.iter_mut().find(|entry| entry.track.id == id))
+        else {
+            return Ok(false);
+        };
+
+        if vote == 0 {
+            entry.votes.remove(&voter_id);
+        } else {
+            entry.votes.insert(voter_id, vote);
+        }
+        Ok(true)
+    }
+
+    async fn take_pool_track(&self, room_id: String, id: u64) -> Result<bool> {
+        let mut state = self.state();
+        let Some(pool) = state.pools.get_mut(&room_id) else {
+            return Ok(false);
+        };
+
+        let len = pool.len();
+        pool.retain(|entry| entry.track.id != id);
+        Ok(pool.len() < len)
+    }
+}
+
+#[async_trait]
+impl PresenceStorage for MemoryDb {
+    async fn add_presence(&self, room_id: String, user_id: String) -> Result<()> {
+        let deadline = Instant::now() + self.timings.presence_expiry();
+        let key = (room_id.clone(), user_id.clone());
+        let new = self.state().presence.insert(key, deadline).is_none();
+        self.expire_presence(room_id.clone(), user_id.clone(), deadline);
+
+        if new {
+            let activity = PresenceEventActivty::Join;
+            self.publish_presence(room_id, PresenceEvent { user_id, activity })
+                .await;
+        }
+
+        Ok(())
+    }
+
+    async fn remove_presence(&self, room_id: String, user_id: String) -> Result<()> {
+        let key = (room_id.clone(), user_id.clone());
+        let existed = self.state().presence.remove(&key).is_some();
+
+        if existed {
+            let activity = PresenceEventActivty::Leave;
+            self.publish_presence(room_id, PresenceEvent { user_id, activity })
+                .await;
+        }
+
+        Ok(())
+    }
+
+    async fn keep_alive_presence(&self, room_id: String, user_id: String) -> Result<()> {
+        let deadline = Instant::now() + self.timings.presence_expiry();
+        let key = (room_id.clone(), user_id.clone());
+
+        if let Some(current) = self.state().presence.get_mut(&key) {
+            *current = deadline;
+        } else {
+            return Ok(());
+        }
+
+        self.expire_presence(room_id, user_id, deadline);
+        Ok(())
+    }
+
+    async fn scan_presence(&self, room_id: String) -> Result<Vec<String>> {
+        Ok(self
+            .state()
+            .presence
+            .keys()
+            .filter(|(room, _)| *room == room_id)
+            .map(|(_, user_id)| user_id.clone())
+            .collect())
+    }
+
+    async fn del_presences(&self, room_id: String) -> Result<()> {
+        self.state().presences.remove(&room_id);
+        Ok(())
+    }
+
+    async fn add_presences(&self, room_id: String, user_id: String) -> Result<()> {
+        self.state()
+            .presences
+            .entry(room_id)
+            .or_default()
+            .insert(user_id);
+        Ok(())
+    }
+
+    async fn rem_presences(&self, room_id: String, user_id: String) -> Result<()> {
+        if let Some(presences) = self.state().presences.get_mut(&room_id) {
+            presences.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    async fn list_presences(&self, room_id: String) -> Result<Vec<String>> {
+        Ok(self
+            .state()
+            .presences
+            .get(&room_id)
+            .map(|presences| presences.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn subscribe_presence(&self, room_id: String) -> Result<mpsc::Receiver<PresenceEvent>> {
+        let (tx, rx) = mpsc::channel(5);
+        self.state()
+            .presence_subscribers
+            .entry(room_id)
+            .or_default()
+            .push(tx);
+
+        Ok(rx)
+    }
+}
+
+#[async_trait]
+impl QueueStorage for MemoryDb {
+    async fn push_queue(&self, room_id: String, user_id: String) -> Result<()> {
+        self.state()
+            .queues
+            .entry(room_id)
+            .or_default()
+            .push_back(user_id);
+        Ok(())
+    }
+
+    async fn rem_queue(&self, room_id: String, user_id: String) -> Result<()> {
+        if let Some(queue) = self.state().queues.get_mut(&room_id) {
+            queue.retain(|queued| *queued != user_id);
+        }
+        Ok(())
+    }
+
+    async fn take_queue(&self, room_id: String, user_id: String) -> Result<bool> {
+        let mut state = self.state();
+        let Some(queue) = state.queues.get_mut(&room_id) else {
+            return Ok(false);
+        };
+
+        let len = queue.len();
+        queue.retain(|queued| *queued != user_id);
+        Ok(queue.len() < len)
+    }
+
+    async fn pop_queue(&self, room_id: String) -> Result<Option<String>> {
+        Ok(self
+            .state()
+            .queues
+            .get_mut(&room_id)
+            .and_then(|queue| queue.pop_front()))
+    }
+
+    async fn push_front_queue(&self, room_id: String, user_id: String) -> Result<()> {
+        self.state()
+            .queues
+            .entry(room_id)
+            .or_default()
+            .push_front(user_id);
+        Ok(())
+    }
+
+    async fn list_queue(&self, room_id: String) -> Result<Vec<String>> {
+        Ok(self
+            .state()
+            .queues
+            .get(&room_id)
+            .map(|queue| queue.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn push_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        track_id: String,
+    ) -> Result<()> {
+        self.state()
+            .user_queues
+            .entry((room_id, user_id))
+            .or_default()
+            .push_back(track_id);
+        Ok(())
+    }
+
+    async fn pop_user_queue(&self, room_id: String, user_id: String) -> Result<Option<String>> {
+        Ok(self
+            .state()
+            .user_queues
+            .get_mut(&(room_id, user_id))
+            .and_then(|queue| queue.pop_front()))
+    }
+
+    async fn push_front_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        track_id: String,
+    ) -> Result<()> {
+        self.state()
+            .user_queues
+            .entry((room_id, user_id))
+            .or_default()
+            .push_front(track_id);
+        Ok(())
+    }
+
+    async fn list_user_queue(&self, room_id: String, user_id: String) -> Result<Vec<String>> {
+        Ok(self
+            .state()
+            .user_queues
+            .get(&(room_id, user_id))
+            .map(|queue| queue.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn remove_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        index: usize,
+        track_id: String,
+    ) -> Result<bool> {
+        let mut state = self.state();
+        let Some(queue) = state.user_queues.get_mut(&(room_id, user_id)) else {
+            return Ok(false);
+        };
+        if queue.get(index) != Some(&track_id) {
+            return Ok(false);
+        }
+
+        queue.remove(index);
+        Ok(true)
+    }
+
+    async fn move_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        index: usize,
+        to: usize,
+        track_id: String,
+    ) -> Result<bool> {
+        let mut state = self.state();
+        let Some(queue) = state.user_queues.get_mut(&(room_id, user_id)) else {
+            return Ok(false);
+        };
+        if queue.get(index) != Some(&track_id) || to >= queue.len() {
+            return Ok(false);
+        }
+
+        let track = queue.remove(index).unwrap();
+        queue.insert(to, track);
+        Ok(true)
+    }
+
+    async fn clear_user_queue(&self, room_id: String, user_id: String) -> Result<()> {
+        self.state().user_queues.remove(&(room_id, user_id));
+        Ok(())
+    }
+
+    async fn list_user_queues(&self) -> Result<Vec<(String, String)>> {
+        //Redis drops lists once they're empty
+        Ok(self
+            .state()
+            .user_queues
+            .iter()
+            .filter(|(_, queue)| !queue.is_empty())
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn set_stepping_down(&self, room_id: String, user_id: String) -> Result<()> {
+        self.state().stepping_down.insert((room_id, user_id));
+        Ok(())
+    }
+
+    async fn take_stepping_down(&self, room_id: String, user_id: String) -> Result<bool> {
+        Ok(self.state().stepping_down.remove(&(room_id, user_id)))
+    }
+
+    async fn set_away(&self, room_id: String, user_id: String, until: u128) -> Result<()> {
+        self.state()
+            .away
+            .entry(room_id)
+            .or_default()
+            .insert(user_id, until);
+        Ok(())
+    }
+
+    async fn take_away(&self, room_id: String, user_id: String) -> Result<bool> {
+        Ok(self
+            .state()
+            .away
+            .get_mut(&room_id)
+            .is_some_and(|away| away.remove(&user_id).is_some()))
+    }
+
+    async fn list_away(&self, room_id: String) -> Result<Vec<(String, u128)>> {
+        Ok(self
+            .state()
+            .away
+            .get(&room_id)
+            .map(|away| {
+                away.iter()
+                    .map(|(user_id, until)| (user_id.clone(), *until))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn set_isrc(&self, room_id: String, track_id: String, isrc: String) -> Result<()> {
+        self.state()
+            .isrcs
+            .entry(room_id)
+            .or_default()
+            .insert(track_id, isrc);
+        Ok(())
+    }
+
+    async fn list_isrcs(
+        &self,
+        room_id: String,
+        track_ids: Vec<String>,
+    ) -> Result<Vec<Option<String>>> {
+        let state = self.state();
+        let isrcs = state.isrcs.get(&room_id);
+        Ok(track_ids
+            .iter()
+            .map(|track_id| isrcs.and_then(|isrcs| isrcs.get(track_id).cloned()))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ScheduleStorage for MemoryDb {
+    async fn add_listening_time(&self, room_id: String, user_id: String, ms: u64) -> Result<()> {
+        *self
+            .state()
+            .listening_time
+            .entry(room_id)
+            .or_default()
+            .entry(user_id)
+            .or_default() += ms;
+        Ok(())
+    }
+
+    async fn list_listening_time(&self, room_id: String) -> Result<HashMap<String, u64>> {
+        Ok(self
+            .state()
+            .listening_time
+            .get(&room_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn upvote_dj(&self, room_id: String, dj_id: String, voter_id: String) -> Result<bool> {
+        let mut state = self.state();
+        if !state
+            .upvoters
+            .entry(room_id.clone())
+            .or_default()
+            .insert(voter_id)
+        {
+            return Ok(false);
+        }
+
+        *state
+            .dj_votes
+            .entry(room_id)
+            .or_default()
+            .entry(dj_id)
+            .or_default() += 1;
+        Ok(true)
+    }
+
+    async fn list_dj_votes(&self, room_id: String) -> Result<HashMap<String, u64>> {
+        Ok(self
+            .state()
+            .dj_votes
+            .get(&room_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn clear_upvoters(&self, room_id: String) -> Result<()> {
+        self.state().upvoters.remove(&room_id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl InviteStorage for MemoryDb {
+    async fn add_invite(
+        &self,
+        room_id: String,
+        invite_id: String,
+        max_uses: u32,
+        expiry: std::time::Duration,
+    ) -> Result<()> {
+        self.state()
+            .invites
+            .insert((room_id, invite_id), (max_uses, Instant::now() + expiry));
+        Ok(())
+    }
+
+    async fn use_invite(&self, room_id: String, invite_id: String) -> Result<bool> {
+        let now = Instant::now();
+        let mut state = self.state();
+        match state.invites.get_mut(&(room_id, invite_id)) {
+            Some((left, expires)) if *left > 0 && *expires > now => {
+                *left -= 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn add_member(&self, room_id: String, user_id: String) -> Result<()> {
+        self.state()
+            .members
+            .entry(room_id)
+            .or_default()
+            .insert(user_id);
+        Ok(())
+    }
+
+    async fn is_member(&self, room_id: String, user_id: String) -> Result<bool> {
+        Ok(self
+            .state()
+            .members
+            .get(&room_id)
+            .is_some_and(|members| members.contains(&user_id)))
+    }
+
+    async fn member_rooms(
+        &self,
+        room_ids: Vec<String>,
+        user_id: String,
+    ) -> Result<HashSet<String>> {
+        let state = self.state();
+        Ok(room_ids
+            .into_iter()
+            .filter(|room_id| {
+                state
+                    .members
+                    .get(room_id)
+                    .is_some_and(|members| members.contains(&user_id))
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ModerationStorage for MemoryDb {
+    async fn set_role(&self, room_id: String, user_id: String, role: Role) -> Result<()> {
+        let mut state = self.state();
+        let roles = state.roles.entry(room_id).or_default();
+        if role == Role::default() {
+            roles.remove(&user_id);
+        } else {
+            roles.insert(user_id, role);
+        }
+        Ok(())
+    }
+
+    async fn get_role(&self, room_id: String, user_id: String) -> Result<Role> {
+        Ok(self
+            .state()
+            .roles
+            .get(&room_id)
+            .and_then(|roles| roles.get(&user_id))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn list_roles(&self, room_id: String) -> Result<HashMap<String, Role>> {
+        Ok(self
+            .state()
+            .roles
+            .get(&room_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn add_ban(&self, room_id: String, user_id: String) -> Result<()> {
+        self.state()
+            .bans
+            .entry(room_id)
+            .or_default()
+            .insert(user_id);
+        Ok(())
+    }
+
+    async fn remove_ban(&self, room_id: String, user_id: String) -> Result<bool> {
+        Ok(self
+            .state()
+            .bans
+            .get_mut(&room_id)
+            .is_some_and(|bans| bans.remove(&user_id)))
+    }
+
+    async fn is_banned(&self, room_id: String, user_id: String) -> Result<bool> {
+        Ok(self
+            .state()
+            .bans
+            .get(&room_id)
+            .is_some_and(|bans| bans.contains(&user_id)))
+    }
+
+    async fn set_muted(&self, room_id: String, user_id: String, muted: bool) -> Result<()> {
+        let mut state = self.state();
+        let room_muted = state.muted.entry(room_id).or_default();
+        if muted {
+            room_muted.insert(user_id);
+        } else {
+            room_muted.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    async fn is_muted(&self, room_id: String, user_id: String) -> Result<bool> {
+        Ok(self
+            .state()
+            .muted
+            .get(&room_id)
+            .is_some_and(|muted| muted.contains(&user_id)))
+    }
+}
+
+#[async_trait]
+impl RoomStorage for MemoryDb {
+    async fn create_room(&self, room: Room) -> Result<()> {
+        room.validate().map_err(Error::Validation)?;
+
+        let mut state = self.state();
+        if state.rooms.contains_key(&room.id) {
+            let errors = vec![format!("Room id {} is already taken.", room.id)];
+            Err(Error::Validation(errors))
+        } else {
+            state.rooms.insert(room.id.clone(), room);
+            Ok(())
+        }
+    }
+
+    async fn exists_room(&self, room_id: String) -> Result<bool> {
+        Ok(self.state().rooms.contains_key(&room_id))
+    }
+
+    async fn get_room(&self, room_id: String) -> Result<Option<Room>> {
+        Ok(self.state().rooms.get(&room_id).cloned())
+    }
+
+    async fn update_room(&self, room: Room) -> Result<bool> {
+        room.validate().map_err(Error::Validation)?;
+
+        match self.state().rooms.get_mut(&room.id) {
+            Some(stored) => {
+                *stored = room;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn delete_room(&self, room_id: String) -> Result<()> {
+        //Messages and presence subscriptions are left alone, like the message stream in Redis
+        let mut state = self.state();
+        state.rooms.remove(&room_id);
+        state.rooms_free.retain(|free| *free != room_id);
+        state.claims.remove(&room_id);
+        state.playing.remove(&room_id);
+        state.history.remove(&room_id);
+        state.skip_votes.remove(&room_id);
+        state.pools.remove(&room_id);
+        state.presence.retain(|(room, _), _| *room != room_id);
+        state.presences.remove(&room_id);
+        state.queues.remove(&room_id);
+        state.user_queues.retain(|(room, _), _| *room != room_id);
+        state.stepping_down.retain(|(room, _)| *room != room_id);
+        state.away.remove(&room_id);
+        state.isrcs.remove(&room_id);
+        state.listening_time.remove(&room_id);
+        state.dj_votes.remove(&room_id);
+        state.upvoters.remove(&room_id);
+        state.roles.remove(&room_id);
+        state.bans.remove(&room_id);
+        state.muted.remove(&room_id);
+        state.invites.retain(|(room, _), _| *room != room_id);
+        state.members.remove(&room_id);
+        state.rooms_active.remove(&room_id);
+        state.rooms_archived.remove(&room_id);
+        Ok(())
+    }
+
+    async fn list_rooms(&self) -> Result<Vec<Room>> {
+        Ok(self.state().rooms.values().cloned().collect())
+    }
+
+    async fn list_room_overviews(&self) -> Result<Vec<RoomOverview>> {
+        let state = self.state();
+        Ok(state
+            .rooms
+            .values()
+            .map(|room| RoomOverview {
+                room: room.clone(),
+                listeners: state.presences.get(&room.id).map_or(0, HashSet::len),
+                playing: state.playing.get(&room.id).cloned(),
+                archived: state.rooms_archived.contains(&room.id),
+                last_active: state.rooms_active.get(&room.id).copied(),
+            })
+            .collect())
+    }
+
+    async fn offer_room(&self, room_id: String) -> Result<()> {
+        self.state().rooms_free.push_front(room_id);
+        self.rooms_free.notify_one();
+        Ok(())
+    }
+
+    async fn claim_room(&self) -> Result<oneshot::Receiver<Option<String>>> {
+        let (tx, rx) = oneshot::channel();
+        let db = self.clone();
+
+        tokio::task::spawn(async move {
+            let free = tokio::time::timeout(db.timings.room_claim_timeout(), async {
+                loop {
+                    if let Some(room_id) = db.state().rooms_free.pop_front() {
+                        break room_id;
+                    }
+                    db.rooms_free.notified().await;
+                }
+            })
+            .await;
+
+            let res = match free {
+                Ok(room_id) => {
+                    let now = Instant::now();
+                    let mut state = db.state();
+                    match state.claims.get(&room_id) {
+                        Some(deadline) if *deadline > now => {
+                            //Room already claimed
+                            None
+                        }
+                        _ => {
+                            state
+                                .claims
+                                .insert(room_id.clone(), now + db.timings.room_claim_expiry());
+                            Some(room_id)
+                        }
+                    }
+                }
+                //No rooms to be claimed
+                Err(_) => None,
+            };
+
+            tx.send(res).unwrap_or(());
+        });
+
+        Ok(rx)
+    }
+
+    async fn keep_alive_room_claim(&self, room_id: String) -> Result<()> {
+        let deadline = Instant::now() + self.timings.room_claim_expiry();
+        self.state().claims.insert(room_id, deadline);
+        Ok(())
+    }
+
+    async fn release_room(&self, room_id: String) -> Result<()> {
+        let mut state = self.state();
+        state.claims.remove(&room_id);
+        state.rooms_free.push_front(room_id);
+        drop(state);
+
+        self.rooms_free.notify_one();
+        Ok(())
+    }
+
+    async fn unclaim_room(&self, room_id: String) -> Result<()> {
+        self.state().claims.remove(&room_id);
+        Ok(())
+    }
+
+    async fn lease_janitor(&self, holder: String, expiry: std::time::Duration) -> Result<bool> {
+        let mut state = self.state();
+        let now = Instant::now();
+        if state
+            .janitor_lease
+            .as_ref()
+            .is_some_and(|(_, expires)| *expires > now)
+        {
+            return Ok(false);
+        }
+        state.janitor_lease = Some((holder, now + expiry));
+        Ok(true)
+    }
+
+    async fn touch_room(&self, room_id: String, at: u128) -> Result<()> {
+        self.state().rooms_active.insert(room_id, at);
+        Ok(())
+    }
+
+    async fn archive_room(&self, room_id: String) -> Result<()> {
+        let mut state = self.state();
+        //Like a deleted stream in Redis, anyone still reading keeps their subscription
+        if state
+            .messages
+            .get(&room_id)
+            .is_some_and(|tx| tx.receiver_count() == 0)
+        {
+            state.messages.remove(&room_id);
+        }
+        state.playing.remove(&room_id);
+        state.skip_votes.remove(&room_id);
+        state.pools.remove(&room_id);
+        state.queues.remove(&room_id);
+        state.user_queues.retain(|(room, _), _| *room != room_id);
+        state.stepping_down.retain(|(room, _)| *room != room_id);
+        state.away.remove(&room_id);
+        state.presences.remove(&room_id);
+        state.listening_time.remove(&room_id);
+        state.dj_votes.remove(&room_id);
+        state.upvoters.remove(&room_id);
+        state.rooms_free.retain(|free| *free != room_id);
+        state.rooms_archived.insert(room_id);
+        Ok(())
+    }
+
+    async fn unarchive_room(&self, room_id: String) -> Result<bool> {
+        Ok(self.state().rooms_archived.remove(&room_id))
+    }
+
+    async fn is_archived_room(&self, room_id: String) -> Result<bool> {
+        Ok(self.state().rooms_archived.contains(&room_id))
+    }
+}
+
+#[async_trait]
+impl NoticeStorage for MemoryDb {
+    async fn add_notice(&self, user_id: String, notice: String) -> Result<()> {
+        self.state()
+            .notices
+            .entry(user_id)
+            .or_default()
+            .push(notice);
+        Ok(())
+    }
+
+    async fn take_notices(&self, user_id: String) -> Result<Vec<String>> {
+        Ok(self.state().notices.remove(&user_id).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::Duration;
+
+    fn room(id: &str) -> Room {
+        Room {
+            id: id.to_string(),
+            title: "Test Room".to_string(),
+            owner: "user:id:name".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_queue_reorder() {
+        let db = MemoryDb::init(Timings::default());
+        let room_id = "test-room".to_string();
+        let user_id = "a".to_string();
+        for track in ["one", "two", "three"] {
+            db.push_user_queue(room_id.clone(), user_id.clone(), track.to_string())
+                .await
+                .unwrap();
+        }
+
+        //Stale positions are left alone
+        assert!(!db
+            .remove_user_queue(room_id.clone(), user_id.clone(), 0, "two".to_string())
+            .await
+            .unwrap());
+        assert!(!db
+            .move_user_queue(room_id.clone(), user_id.clone(), 0, 3, "one".to_string())
+            .await
+            .unwrap());
+
+        assert!(db
+            .move_user_queue(room_id.clone(), user_id.clone(), 2, 0, "three".to_string())
+            .await
+            .unwrap());
+        assert!(db
+            .remove_user_queue(room_id.clone(), user_id.clone(), 1, "one".to_string())
+            .await
+            .unwrap());
+        assert_eq!(
+            db.list_user_queue(room_id.clone(), user_id.clone())
+                .await
+                .unwrap(),
+            vec!["three", "two"]
+        );
+
+        db.clear_user_queue(room_id.clone(), user_id.clone())
+            .await
+            .unwrap();
+        assert!(db
+            .list_user_queue(room_id, user_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_history_pages_newest_first() {
+        let db = MemoryDb::init(Timings::default());
+        let room_id = "test-room".to_string();
+        for start_time in 1..=5 {
+            let entry = HistoryEntry {
+                id: 0,
+                track_id: format!("spotify:track:{}", start_time),
+                user_id: Some("dj".to_string()),
+                start_time,
+                skipped: false,
+            };
+            db.add_history(room_id.clone(), entry).await.unwrap();
+        }
+
+        //Only the track that is still the newest can be skipped
+        assert!(!db.skip_history(room_id.clone(), 4).await.unwrap());
+        assert!(db.skip_history(room_id.clone(), 5).await.unwrap());
+
+        let page = db.list_history(room_id.clone(), None, 2).await.unwrap();
+        let ids: Vec<u64> = page.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![5, 4]);
+        assert!(page[0].skipped);
+        assert!(!page[1].skipped);
+
+        let page = db.list_history(room_id.clone(), Some(4), 5).await.unwrap();
+        let ids: Vec<u64> = page.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, vec![3, 2, 1]);
+        assert!(db
+            .list_history(room_id, Some(1), 5)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue_round_robin() {
+        let db = MemoryDb::init(Timings::default());
+        let room_id = "test-room".to_string();
+
+        db.push_queue(room_id.clone(), "a".to_string())
+            .await
+            .unwrap();
+        db.push_queue(room_id.clone(), "b".to_string())
+            .await
+            .unwrap();
+        db.push_queue(room_id.clone(), "c".to_string())
+            .await
+            .unwrap();
+        db.rem_queue(room_id.clone(), "b".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            db.list_queue(room_id.clone()).await.unwrap(),
+            vec!["a", "c"]
+        );
+        assert_eq!(
+            db.pop_queue(room_id.clone()).await.unwrap(),
+            Some("a".to_string())
+        );
+        assert_eq!(
+            db.pop_queue(room_id.clone()).await.unwrap(),
+            Some("c".to_string())
+        );
+        assert_eq!(db.pop_queue(room_id.clone()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_user_queue_is_per_user() {
+        let db = MemoryDb::init(Timings::default());
+        let room_id = "test-room".to_string();
+
+        db.push_user_queue(room_id.clone(), "a".to_string(), "track:1".to_string())
+            .await
+            .unwrap();
+        db.push_user_queue(room_id.clone(), "a".to_string(), "track:2".to_string())
+            .await
+            .unwrap();
+        db.push_user_queue(room_id.clone(), "b".to_string(), "track:3".to_string())
+            .await
+            .unwrap();
+
+        let queue = db
+            .list_user_queue(room_id.clone(), "a".to_string())
+            .await
+            .unwrap();
+        assert_eq!(queue, vec!["track:1", "track:2"]);
+
+        let next = db
+            .pop_user_queue(room_id.clone(), "b".to_string())
+            .await
+            .unwrap();
+        assert_eq!(next, Some("track:3".to_string()));
+        assert!(db
+            .pop_user_queue(room_id, "b".to_string())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_create_room_rejects_duplicate_id() {
+        let db = MemoryDb::init(Timings::default());
+
+        assert!(db.create_room(room("test-room")).await.is_ok());
+        assert!(db.create_room(room("test-room")).await.is_err());
+        assert!(db.create_room(room("")).await.is_err());
+
+        assert!(db.exists_room("test-room".to_string()).await.unwrap());
+        assert_eq!(db.list_rooms().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_delete_room_leaves_other_rooms_alone() {
+        let db = MemoryDb::init(Timings::default());
+        for room_id in ["gone", "kept"] {
+            db.create_room(room(room_id)).await.unwrap();
+            db.push_queue(room_id.to_string(), "a".to_string())
+                .await
+                .unwrap();
+            db.push_user_queue(room_id.to_string(), "a".to_string(), "one".to_string())
+                .await
+                .unwrap();
+        }
+
+        db.delete_room("gone".to_string()).await.unwrap();
+
+        assert!(!db.exists_room("gone".to_string()).await.unwrap());
+        assert!(db.list_queue("gone".to_string()).await.unwrap().is_empty());
+        assert!(db
+            .list_user_queue("gone".to_string(), "a".to_string())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(db.list_queue("kept".to_string()).await.unwrap(), vec!["a"]);
+        assert_eq!(db.list_rooms().await.unwrap().len(), 1);
+        assert!(!db.update_room(room("gone")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_messages_receives_new_messages() {
+        let db = MemoryDb::init(Timings::default());
+        let room_id = "test-room".to_string();
+
+        //Messages sent before subscribing aren't replayed, same as reading a stream from $
+        db.add_message(room_id.clone(), Message::queue_changed())
+            .await
+            .unwrap();
+        let mut rx = db.subscribe_messages(room_id.clone()).await.unwrap();
+        db.add_message(
+            room_id.clone(),
+            Message::chat_message("a".to_string(), "hello".to_string()),
+        )
+        .await
+        .unwrap();
+
+        let message = rx.recv().await.unwrap();
+        assert!(message.id.is_some());
+        match message.data {
+            crate::db::message::MessageType::MessageChat(chat) => {
+                assert_eq!(chat.from, "a");
+                assert_eq!(chat.message, "hello");
+            }
+            _ => panic!("Expected a chat message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_room_drops_unread_messages() {
+        let db = MemoryDb::init(Timings::default());
+        db.create_room(room("quiet")).await.unwrap();
+        db.create_room(room("listened")).await.unwrap();
+        db.state()
+            .messages
+            .insert("quiet".to_string(), broadcast::channel(64).0);
+        let mut rx = db.subscribe_messages("listened".to_string()).await.unwrap();
+
+        db.archive_room("quiet".to_string()).await.unwrap();
+        db.archive_room("listened".to_string()).await.unwrap();
+
+        assert!(!db.state().messages.contains_key("quiet"));
+        db.add_message("listened".to_string(), Message::queue_changed())
+            .await
+            .unwrap();
+        assert!(rx.recv().await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_presence_expires_without_keep_alive() {
+        let db = MemoryDb::init(Timings::default());
+        let room_id = "test-room".to_string();
+        let mut rx = db.subscribe_presence(room_id.clone()).await.unwrap();
+
+        db.add_presence(room_id.clone(), "a".to_string())
+            .await
+            .unwrap();
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.user_id, "a");
+        assert_eq!(event.activity, PresenceEventActivty::Join);
+
+        //Keeping it alive pushes the expiry back
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        db.keep_alive_presence(room_id.clone(), "a".to_string())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(db.scan_presence(room_id.clone()).await.unwrap(), vec!["a"]);
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.user_id, "a");
+        assert_eq!(event.activity, PresenceEventActivty::Leave);
+        assert!(db.scan_presence(room_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_claim_room_only_once() {
+        let db = MemoryDb::init(Timings::default());
+
+        db.offer_room("test-room".to_string()).await.unwrap();
+        db.offer_room("test-room".to_string()).await.unwrap();
+
+        let first = db.claim_room().await.unwrap().await.unwrap();
+        let second = db.claim_room().await.unwrap().await.unwrap();
+        let third = db.claim_room().await.unwrap().await.unwrap();
+
+        assert_eq!(first, Some("test-room".to_string()));
+        assert_eq!(second, None);
+        assert_eq!(third, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_released_room_can_be_claimed_again() {
+        let db = MemoryDb::init(Timings::default());
+
+        db.offer_room("test-room".to_string()).await.unwrap();
+        let first = db.claim_room().await.unwrap().await.unwrap();
+        db.release_room("test-room".to_string()).await.unwrap();
+        let second = db.claim_room().await.unwrap().await.unwrap();
+
+        assert_eq!(first, Some("test-room".to_string()));
+        assert_eq!(second, Some("test-room".to_string()));
+    }
+}
@@ -1,28 +1,40 @@
 use crate::db;
+use crate::db::moderation::Role;
+use crate::error::Result;
+use async_trait::async_trait;
 use futures_util::future;
 use redis::streams;
 use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
 
+#[async_trait]
+pub trait MessageStorage {
+    async fn add_message(&self, room_id: String, message: Message) -> Result<()>;
+    async fn subscribe_messages(&self, room_id: String) -> Result<mpsc::Receiver<Message>>;
+}
+
 impl db::DbInternal {
-    fn key_messages(room_id: String) -> String {
+    pub(super) fn key_messages(room_id: String) -> String {
         format!("room:{}:messages", room_id)
     }
+}
 
-    pub async fn add_message(&mut self, room_id: String, message: Message) {
+#[async_trait]
+impl MessageStorage for db::DbInternal {
+    async fn add_message(&self, room_id: String, message: Message) -> Result<()> {
         let args: Vec<(String, String)> = message.into();
-        let mut con = self.client.get_async_connection().await.unwrap();
+        let mut con = self.con();
         let _: () = con
             .xadd(Self::key_messages(room_id), "*", &args[..])
-            .await
-            .unwrap();
+            .await?;
+        Ok(())
     }
 
-    pub async fn subscribe_messages(&mut self, room_id: String) -> mpsc::Receiver<Message> {
+    async fn subscribe_messages(&self, room_id: String) -> Result<mpsc::Receiver<Message>> {
         let (tx, rx) = mpsc::channel(10);
-        let client = self.blockable_client();
-        let mut con = client.get_async_connection().await.unwrap();
+        let client = self.blockable_client()?;
+        let mut con = client.get_async_connection().await?;
 
         tokio::task::spawn(async move {
             let options = streams::StreamReadOptions::default().block(250).count(5);
@@ -52,8 +64,14 @@ impl db::DbInternal {
                                         .ids
                                         .iter()
                                         .map(|stream_id| {
-                                            let message = Message::try_from(stream_id).unwrap();
-                                            sends.push(tx.send(message));
+                                            match Message::try_from(stream_id) {
+                                                Ok(message) => sends.push(tx.send(message)),
+                                                Err(err) => tracing::warn!(
+                                                    "Skipping message {}: {}",
+                                                    stream_id.id,
+                                                    err
+                                                ),
+                                            }
                                             stream_id.id.clone()
                                         })
                                         .fold("$".to_string(), |_, val| val)
@@ -73,7 +91,13 @@ impl db::DbInternal {
                             );
                     }
                     Err(err) => {
-                        panic!("RedisError: {}", err);
+                        //Dropping tx lets the subscriber know the subscription died
+                        tracing::error!(
+                            "Message subscription for room {} failed: {}",
+                            room_id,
+                            err
+                        );
+                        break;
                     }
                 }
 
@@ -81,36 +105,56 @@ impl db::DbInternal {
             }
         });
 
-        rx
+        Ok(rx)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageChat {
     pub from: String,
     pub message: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageDeviceChange {
     pub user_id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageUserQueueChanged {
     pub user_id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageKicked {
+    pub user_id: String,
+    pub banned: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRoleChanged {
+    pub user_id: String,
+    pub role: Role,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MessageType {
     MessageChat(MessageChat),
     MessageDeviceChange(MessageDeviceChange),
     MessageUserQueueChanged(MessageUserQueueChanged),
     MessagePresencesChanged,
     MessageQueueChanged,
+    //Tells the room worker to count the skip votes again
+    MessageSkipVote,
+    //The owner deleted the room, everyone gets disconnected
+    MessageRoomDeleted,
+    //Disconnects every socket of the user
+    MessageKicked(MessageKicked),
+    MessageRoleChanged(MessageRoleChanged),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub id: Option<String>,
     pub data: MessageType,
@@ -134,7 +178,7 @@ impl Message {
     pub fn user_queue_changed(user_id: String) -> Self {
         Self {
             id: None,
-            data: MessageType::MessageUserQueueChanged(MessageUserQueueChanged { user_id })
+            data: MessageType::MessageUserQueueChanged(MessageUserQueueChanged { user_id }),
         }
     }
 
@@ -151,13 +195,41 @@ impl Message {
             data: MessageType::MessageQueueChanged,
         }
     }
+
+    pub fn skip_vote() -> Self {
+        Self {
+            id: None,
+            data: MessageType::MessageSkipVote,
+        }
+    }
+
+    pub fn room_deleted() -> Self {
+        Self {
+            id: None,
+            data: MessageType::MessageRoomDeleted,
+        }
+    }
+
+    pub fn kicked(user_id: String, banned: bool) -> Self {
+        Self {
+            id: None,
+            data: MessageType::MessageKicked(MessageKicked { user_id, banned }),
+        }
+    }
+
+    pub fn role_changed(user_id: String, role: Role) -> Self {
+        Self {
+            id: None,
+            data: MessageType::MessageRoleChanged(MessageRoleChanged { user_id, role }),
+        }
+    }
 }
 
-impl Into<Vec<(String, String)>> for Message {
-    fn into(self) -> Vec<(String, String)> {
+impl From<Message> for Vec<(String, String)> {
+    fn from(message: Message) -> Self {
         let mut args: Vec<(String, String)> = Vec::new();
 
-        match self.data {
+        match message.data {
             MessageType::MessageChat(data) => {
                 args.push(("type".to_string(), "MessageChat".to_string()));
                 args.push(("from".to_string(), data.from));
@@ -177,6 +249,22 @@ impl Into<Vec<(String, String)>> for Message {
             MessageType::MessageQueueChanged => {
                 args.push(("type".to_string(), "MessageQueueChanged".to_string()));
             }
+            MessageType::MessageSkipVote => {
+                args.push(("type".to_string(), "MessageSkipVote".to_string()));
+            }
+            MessageType::MessageRoomDeleted => {
+                args.push(("type".to_string(), "MessageRoomDeleted".to_string()));
+            }
+            MessageType::MessageKicked(data) => {
+                args.push(("type".to_string(), "MessageKicked".to_string()));
+                args.push(("user_id".to_string(), data.user_id));
+                args.push(("banned".to_string(), data.banned.to_string()));
+            }
+            MessageType::MessageRoleChanged(data) => {
+                args.push(("type".to_string(), "MessageRoleChanged".to_string()));
+                args.push(("user_id".to_string(), data.user_id));
+                args.push(("role".to_string(), data.role.as_str().to_string()));
+            }
         }
 
         args
@@ -186,7 +274,7 @@ impl Into<Vec<(String, String)>> for Message {
 impl TryFrom<&redis::streams::StreamId> for Message {
     type Error = &'static str;
 
-    fn try_from(stream_id: &redis::streams::StreamId) -> Result<Self, Self::Error> {
+    fn try_from(stream_id: &redis::streams::StreamId) -> std::result::Result<Self, Self::Error> {
         let id = Some(stream_id.id.clone());
 
         let message_type = db::util::read_redis_stream_data(stream_id, "type")?;
@@ -208,12 +296,32 @@ impl TryFrom<&redis::streams::StreamId> for Message {
             "MessageUserQueueChanged" => {
                 let user_id = db::util::read_redis_stream_data(stream_id, "user_id")?;
 
-                Ok(MessageType::MessageUserQueueChanged(MessageUserQueueChanged {
-                    user_id
-                }))
+                Ok(MessageType::MessageUserQueueChanged(
+                    MessageUserQueueChanged { user_id },
+                ))
             }
             "MessagePresencesChanged" => Ok(MessageType::MessagePresencesChanged),
             "MessageQueueChanged" => Ok(MessageType::MessageQueueChanged),
+            "MessageSkipVote" => Ok(MessageType::MessageSkipVote),
+            "MessageRoomDeleted" => Ok(MessageType::MessageRoomDeleted),
+            "MessageKicked" => {
+                let user_id = db::util::read_redis_stream_data(stream_id, "user_id")?;
+                let banned = db::util::read_redis_stream_data(stream_id, "banned")?;
+
+                Ok(MessageType::MessageKicked(MessageKicked {
+                    user_id,
+                    banned: banned == "true",
+                }))
+            }
+            "MessageRoleChanged" => {
+                let user_id = db::util::read_redis_stream_data(stream_id, "user_id")?;
+                let role = db::util::read_redis_stream_data(stream_id, "role")?;
+
+                Ok(MessageType::MessageRoleChanged(MessageRoleChanged {
+                    user_id,
+                    role: role.parse()?,
+                }))
+            }
             _ => Err("Tried to read non existing message data type"),
         }?;
 
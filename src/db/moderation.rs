@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::error::{Error, Result};
+
+//Who may do what in a room, see crate::moderation. The owner comes from the room itself
+#[async_trait]
+pub trait ModerationStorage {
+    //DJ is the default, so setting it drops the stored role
+    async fn set_role(&self, room_id: String, user_id: String, role: Role) -> Result<()>;
+    async fn get_role(&self, room_id: String, user_id: String) -> Result<Role>;
+    //Only the users whose role isn't the default
+    async fn list_roles(&self, room_id: String) -> Result<HashMap<String, Role>>;
+    async fn add_ban(&self, room_id: String, user_id: String) -> Result<()>;
+    //Returns whether they were banned
+    async fn remove_ban(&self, room_id: String, user_id: String) -> Result<bool>;
+    async fn is_banned(&self, room_id: String, user_id: String) -> Result<bool>;
+    async fn set_muted(&self, room_id: String, user_id: String, muted: bool) -> Result<()>;
+    async fn is_muted(&self, room_id: String, user_id: String) -> Result<bool>;
+}
+
+impl db::DbInternal {
+    fn key_roles(room_id: String) -> String {
+        format!("room:{}:roles", room_id)
+    }
+
+    fn key_bans(room_id: String) -> String {
+        format!("room:{}:bans", room_id)
+    }
+
+    fn key_muted(room_id: String) -> String {
+        format!("room:{}:muted", room_id)
+    }
+}
+
+#[async_trait]
+impl ModerationStorage for db::DbInternal {
+    async fn set_role(&self, room_id: String, user_id: String, role: Role) -> Result<()> {
+        let mut con = self.con();
+        let key = Self::key_roles(room_id);
+        if role == Role::default() {
+            let _: () = con.hdel(key, user_id).await?;
+        } else {
+            let _: () = con.hset(key, user_id, role.as_str()).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_role(&self, room_id: String, user_id: String) -> Result<Role> {
+        let mut con = self.con();
+        let role: Option<String> = con.hget(Self::key_roles(room_id), user_id).await?;
+        match role {
+            Some(role) => parse_role(&role),
+            None => Ok(Role::default()),
+        }
+    }
+
+    async fn list_roles(&self, room_id: String) -> Result<HashMap<String, Role>> {
+        let mut con = self.con();
+        let roles: HashMap<String, String> = con.hgetall(Self::key_roles(room_id)).await?;
+        roles
+            .into_iter()
+            .map(|(user_id, role)| Ok((user_id, parse_role(&role)?)))
+            .collect()
+    }
+
+    async fn add_ban(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.sadd(Self::key_bans(room_id), user_id).await?;
+        Ok(())
+    }
+
+    async fn remove_ban(&self, room_id: String, user_id: String) -> Result<bool> {
+        let mut con = self.con();
+        let removed: i32 = con.srem(Self::key_bans(room_id), user_id).await?;
+        Ok(removed > 0)
+    }
+
+    async fn is_banned(&self, room_id: String, user_id: String) -> Result<bool> {
+        let mut con = self.con();
+        Ok(con.sismember(Self::key_bans(room_id), user_id).await?)
+    }
+
+    async fn set_muted(&self, room_id: String, user_id: String, muted: bool) -> Result<()> {
+        let mut con = self.con();
+        if muted {
+            let _: () = con.sadd(Self::key_muted(room_id), user_id).await?;
+        } else {
+            let _: () = con.srem(Self::key_muted(room_id), user_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn is_muted(&self, room_id: String, user_id: String) -> Result<bool> {
+        let mut con = self.con();
+        Ok(con.sismember(Self::key_muted(room_id), user_id).await?)
+    }
+}
+
+fn parse_role(role: &str) -> Result<Role> {
+    role.parse()
+        .map_err(|_| Error::Decode(format!("room role {}", role)))
+}
+
+//Ordered by what they're allowed to do, see Role::rank
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Owner,
+    //Kicks, bans and mutes DJs and listeners, and makes them one or the other
+    Moderator,
+    //Everyone starts out a DJ, like rooms did before there were roles
+    #[default]
+    Dj,
+    //Can chat and vote, but not queue anything
+    Listener,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Moderator => "moderator",
+            Role::Dj => "dj",
+            Role::Listener => "listener",
+        }
+    }
+
+    //Users can only moderate those ranked below them
+    pub fn rank(&self) -> u8 {
+        match self {
+            Role::Owner => 2,
+            Role::Moderator => 1,
+            Role::Dj | Role::Listener => 0,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(Role::Owner),
+            "moderator" => Ok(Role::Moderator),
+            "dj" => Ok(Role::Dj),
+            "listener" => Ok(Role::Listener),
+            _ => Err("Unknown role"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_role_round_trip() {
+        for role in [Role::Owner, Role::Moderator, Role::Dj, Role::Listener] {
+            assert_eq!(role.as_str().parse::<Role>(), Ok(role));
+        }
+        assert!("admin".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn test_djs_and_listeners_rank_the_same() {
+        assert_eq!(Role::Dj.rank(), Role::Listener.rank());
+        assert!(Role::Owner.rank() > Role::Moderator.rank());
+        assert!(Role::Moderator.rank() > Role::Dj.rank());
+    }
+}
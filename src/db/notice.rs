@@ -0,0 +1,41 @@
+use async_trait::async_trait;
+use redis::AsyncCommands;
+
+use crate::db;
+use crate::error::Result;
+
+//Things to tell a user the next time they're around, e.g. that their room was archived
+#[async_trait]
+pub trait NoticeStorage {
+    async fn add_notice(&self, user_id: String, notice: String) -> Result<()>;
+    //Oldest first, each notice is only handed out once
+    async fn take_notices(&self, user_id: String) -> Result<Vec<String>>;
+}
+
+impl db::DbInternal {
+    fn key_notices(user_id: String) -> String {
+        format!("{}:notices", user_id)
+    }
+}
+
+#[async_trait]
+impl NoticeStorage for db::DbInternal {
+    async fn add_notice(&self, user_id: String, notice: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.rpush(Self::key_notices(user_id), notice).await?;
+        Ok(())
+    }
+
+    async fn take_notices(&self, user_id: String) -> Result<Vec<String>> {
+        let mut con = self.con();
+        let key = Self::key_notices(user_id);
+        let (notices,): (Vec<String>,) = redis::pipe()
+            .atomic()
+            .lrange(key.clone(), 0, -1)
+            .del(key)
+            .ignore()
+            .query_async(&mut con)
+            .await?;
+        Ok(notices)
+    }
+}
@@ -1,55 +1,141 @@
 use std::collections::HashMap;
 
+use async_trait::async_trait;
 use redis::AsyncCommands;
 
 use crate::db;
+use crate::error::{Error, Result};
+
+#[async_trait]
+pub trait PlayingStorage {
+    async fn set_playing(&self, room_id: String, playing: Playing) -> Result<()>;
+    async fn get_playing(&self, room_id: String) -> Result<Option<Playing>>;
+    //Listeners who want the playing track skipped, returns whether this vote is new
+    async fn add_skip_vote(&self, room_id: String, user_id: String) -> Result<bool>;
+    async fn list_skip_votes(&self, room_id: String) -> Result<Vec<String>>;
+    async fn clear_skip_votes(&self, room_id: String) -> Result<()>;
+}
 
 impl db::DbInternal {
-    fn key_playing(room_id: String) -> String {
+    pub(super) fn key_playing(room_id: String) -> String {
         format!("room:{}:playing", room_id)
     }
 
-    pub async fn set_playing(
-        &mut self,
-        room_id: String,
-        track_id: String,
-        start_time: u128,
-        length: u64,
-    ) {
-        let mut data = Vec::new();
-        data.push(("track_id".to_string(), track_id));
-        data.push(("start_time".to_string(), start_time.to_string()));
-        data.push(("length".to_string(), length.to_string()));
+    fn key_skip_votes(room_id: String) -> String {
+        format!("room:{}:skip_votes", room_id)
+    }
+}
 
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con
-            .hset_multiple(Self::key_playing(room_id), &data[..])
-            .await
-            .unwrap();
+#[async_trait]
+impl PlayingStorage for db::DbInternal {
+    async fn set_playing(&self, room_id: String, playing: Playing) -> Result<()> {
+        let key = Self::key_playing(room_id);
+        let data: Vec<(String, String)> = playing.into();
+
+        let mut con = self.con();
+        let _: () = redis::pipe()
+            .atomic()
+            .del(key.clone())
+            .hset_multiple(key, &data[..])
+            .query_async(&mut con)
+            .await?;
+        Ok(())
     }
 
-    pub async fn get_playing(&mut self, room_id: String) -> Option<Playing> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let data: HashMap<String, String> = con.hgetall(Self::key_playing(room_id)).await.unwrap();
+    async fn get_playing(&self, room_id: String) -> Result<Option<Playing>> {
+        let mut con = self.con();
+        let data: HashMap<String, String> = con.hgetall(Self::key_playing(room_id)).await?;
 
         if !data.is_empty() {
-            let track_id = data.get("track_id").unwrap().clone();
-            let start_time = data.get("start_time").unwrap().parse::<u128>().unwrap();
-            let length = data.get("length").unwrap().parse::<u64>().unwrap();
-
-            Some(Playing {
-                track_id,
-                start_time,
-                length,
-            })
+            Ok(Some(Playing::try_from(data)?))
         } else {
-            None
+            Ok(None)
         }
     }
+
+    async fn add_skip_vote(&self, room_id: String, user_id: String) -> Result<bool> {
+        let mut con = self.con();
+        let added: i32 = con.sadd(Self::key_skip_votes(room_id), user_id).await?;
+        Ok(added == 1)
+    }
+
+    async fn list_skip_votes(&self, room_id: String) -> Result<Vec<String>> {
+        let mut con = self.con();
+        Ok(con.smembers(Self::key_skip_votes(room_id)).await?)
+    }
+
+    async fn clear_skip_votes(&self, room_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.del(Self::key_skip_votes(room_id)).await?;
+        Ok(())
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct Playing {
+    //Whose turn it is, missing for tracks started before this was tracked
+    pub user_id: Option<String>,
     pub track_id: String,
     pub start_time: u128,
     pub length: u64,
+    //Picked by autoplay because nothing was queued, user_id is missing then
+    pub autoplay: bool,
+}
+
+impl Playing {
+    //False once the track has run its length, the key itself stays around
+    pub fn is_playing(&self) -> bool {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        now < self.start_time + u128::from(self.length)
+    }
+}
+
+impl From<Playing> for Vec<(String, String)> {
+    fn from(playing: Playing) -> Self {
+        let mut data = vec![
+            ("track_id".to_string(), playing.track_id),
+            ("start_time".to_string(), playing.start_time.to_string()),
+            ("length".to_string(), playing.length.to_string()),
+            ("autoplay".to_string(), playing.autoplay.to_string()),
+        ];
+        if let Some(user_id) = playing.user_id {
+            data.push(("user_id".to_string(), user_id));
+        }
+
+        data
+    }
+}
+
+impl TryFrom<HashMap<String, String>> for Playing {
+    type Error = Error;
+
+    fn try_from(data: HashMap<String, String>) -> Result<Self> {
+        let field = |name: &str| {
+            data.get(name)
+                .ok_or_else(|| Error::Decode(format!("playing field {}", name)))
+        };
+
+        let user_id = data.get("user_id").cloned();
+        let track_id = field("track_id")?.clone();
+        let start_time = field("start_time")?
+            .parse::<u128>()
+            .map_err(|_| Error::Decode("playing start_time".to_string()))?;
+        let length = field("length")?
+            .parse::<u64>()
+            .map_err(|_| Error::Decode("playing length".to_string()))?;
+        let autoplay = data
+            .get("autoplay")
+            .is_some_and(|autoplay| autoplay == "true");
+
+        Ok(Playing {
+            user_id,
+            track_id,
+            start_time,
+            length,
+            autoplay,
+        })
+    }
 }
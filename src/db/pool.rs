@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::error::{Error, Result};
+
+//The shared track pool of democratic rooms, see crate::pool
+#[async_trait]
+pub trait PoolStorage {
+    async fn add_pool_track(
+        &self,
+        room_id: String,
+        user_id: String,
+        track_id: String,
+    ) -> Result<PoolTrack>;
+    //Oldest first
+    async fn list_pool(&self, room_id: String) -> Result<Vec<PoolTrack>>;
+    //Vote is 1 or -1, 0 takes the vote back. Returns false if the track isn't in the pool anymore
+    async fn vote_pool_track(
+        &self,
+        room_id: String,
+        id: u64,
+        voter_id: String,
+        vote: i8,
+    ) -> Result<bool>;
+    //Returns whether the track was still in the pool
+    async fn take_pool_track(&self, room_id: String, id: u64) -> Result<bool>;
+}
+
+impl db::DbInternal {
+    fn key_pool(room_id: String) -> String {
+        format!("room:{}:pool", room_id)
+    }
+
+    fn key_pool_seq(room_id: String) -> String {
+        format!("{}:seq", Self::key_pool(room_id))
+    }
+
+    fn key_pool_track(room_id: String, id: u64) -> String {
+        format!("{}:{}", Self::key_pool(room_id), id)
+    }
+
+    fn key_pool_votes(room_id: String, id: u64) -> String {
+        format!("{}:votes", Self::key_pool_track(room_id, id))
+    }
+}
+
+#[async_trait]
+impl PoolStorage for db::DbInternal {
+    async fn add_pool_track(
+        &self,
+        room_id: String,
+        user_id: String,
+        track_id: String,
+    ) -> Result<PoolTrack> {
+        let mut con = self.con();
+        let id: u64 = con.incr(Self::key_pool_seq(room_id.clone()), 1).await?;
+        let data = [
+            ("user_id".to_string(), user_id.clone()),
+            ("track_id".to_string(), track_id.clone()),
+        ];
+
+        let _: () = redis::pipe()
+            .atomic()
+            .hset_multiple(Self::key_pool_track(room_id.clone(), id), &data[..])
+            .rpush(Self::key_pool(room_id), id)
+            .query_async(&mut con)
+            .await?;
+
+        Ok(PoolTrack {
+            id,
+            track_id,
+            user_id,
+            score: 0,
+        })
+    }
+
+    async fn list_pool(&self, room_id: String) -> Result<Vec<PoolTrack>> {
+        let mut con = self.con();
+        let ids: Vec<u64> = con.lrange(Self::key_pool(room_id.clone()), 0, -1).await?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.hgetall(Self::key_pool_track(room_id.clone(), *id))
+                .hvals(Self::key_pool_votes(room_id.clone(), *id));
+        }
+        let replies: Vec<(HashMap<String, String>, Vec<i64>)> = pipe.query_async(&mut con).await?;
+
+        let mut tracks = Vec::new();
+        for (id, (data, votes)) in ids.into_iter().zip(replies) {
+            //Taken between listing the ids and reading the track
+            if data.is_empty() {
+                continue;
+            }
+
+            let field = |name: &str| {
+                data.get(name)
+                    .cloned()
+                    .ok_or_else(|| Error::Decode(format!("pool track {} field {}", id, name)))
+            };
+            tracks.push(PoolTrack {
+                id,
+                track_id: field("track_id")?,
+                user_id: field("user_id")?,
+                score: votes.iter().sum(),
+            });
+        }
+
+        Ok(tracks)
+    }
+
+    async fn vote_pool_track(
+        &self,
+        room_id: String,
+        id: u64,
+        voter_id: String,
+        vote: i8,
+    ) -> Result<bool> {
+        let mut con = self.con();
+        let exists: bool = con
+            .exists(Self::key_pool_track(room_id.clone(), id))
+            .await?;
+        if !exists {
+            return Ok(false);
+        }
+
+        let key = Self::key_pool_votes(room_id, id);
+        if vote == 0 {
+            let _: () = con.hdel(key, voter_id).await?;
+        } else {
+            let _: () = con.hset(key, voter_id, vote).await?;
+        }
+        Ok(true)
+    }
+
+    async fn take_pool_track(&self, room_id: String, id: u64) -> Result<bool> {
+        let mut con = self.con();
+        let (removed,): (i32,) = redis::pipe()
+            .atomic()
+            .lrem(Self::key_pool(room_id.clone()), 0, id)
+            .del(&[
+                Self::key_pool_track(room_id.clone(), id),
+                Self::key_pool_votes(room_id, id),
+            ])
+            .ignore()
+            .query_async(&mut con)
+            .await?;
+        Ok(removed > 0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolTrack {
+    //Grows with every track added to the room's pool, so lower is older
+    pub id: u64,
+    pub track_id: String,
+    //Who added it
+    pub user_id: String,
+    //Upvotes minus downvotes
+    pub score: i64,
+}
@@ -1,7 +1,25 @@
 use crate::db;
+use crate::error::Result;
+use async_trait::async_trait;
 use futures_util::StreamExt;
 use redis::AsyncCommands;
 
+#[async_trait]
+pub trait PresenceStorage {
+    async fn add_presence(&self, room_id: String, user_id: String) -> Result<()>;
+    async fn remove_presence(&self, room_id: String, user_id: String) -> Result<()>;
+    async fn keep_alive_presence(&self, room_id: String, user_id: String) -> Result<()>;
+    async fn scan_presence(&self, room_id: String) -> Result<Vec<String>>;
+    async fn del_presences(&self, room_id: String) -> Result<()>;
+    async fn add_presences(&self, room_id: String, user_id: String) -> Result<()>;
+    async fn rem_presences(&self, room_id: String, user_id: String) -> Result<()>;
+    async fn list_presences(&self, room_id: String) -> Result<Vec<String>>;
+    async fn subscribe_presence(
+        &self,
+        room_id: String,
+    ) -> Result<tokio::sync::mpsc::Receiver<PresenceEvent>>;
+}
+
 impl db::DbInternal {
     fn key_presence(room_id: String, user_id: String) -> String {
         format!("room:{}:presence:{}", room_id, user_id)
@@ -11,101 +29,96 @@ impl db::DbInternal {
         key.split(":presence:").last().unwrap().to_string()
     }
 
-    pub async fn add_presence(&mut self, room_id: String, user_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con
-            .set(Self::key_presence(room_id.clone(), user_id.clone()), "")
-            .await
-            .unwrap();
-        self.keep_alive_presence(room_id, user_id).await;
+    pub(super) fn key_presences(room_id: String) -> String {
+        format!("room:{}:presences", room_id)
     }
 
-    pub async fn remove_presence(&mut self, room_id: String, user_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con.del(Self::key_presence(room_id, user_id)).await.unwrap();
+    fn key_presence_keyspace(room_id: String) -> String {
+        format!(
+            "__keyspace*__:{}",
+            Self::key_presence(room_id, "*".to_string())
+        )
     }
+}
 
-    pub async fn keep_alive_presence(&mut self, room_id: String, user_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
+#[async_trait]
+impl PresenceStorage for db::DbInternal {
+    async fn add_presence(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
         let _: () = con
-            .expire(Self::key_presence(room_id, user_id), 5)
-            .await
-            .unwrap();
+            .set(Self::key_presence(room_id.clone(), user_id.clone()), "")
+            .await?;
+        self.keep_alive_presence(room_id, user_id).await
     }
 
-    pub async fn scan_presence(&mut self, room_id: String) -> Vec<String> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let iter = con
-            .scan_match(Self::key_presence(room_id, "*".to_string()))
-            .await
-            .unwrap();
-        iter.map(|key| Self::rkey_presence(key)).collect().await
+    async fn remove_presence(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.del(Self::key_presence(room_id, user_id)).await?;
+        Ok(())
     }
 
-    fn key_presences(room_id: String) -> String {
-        format!("room:{}:presences", room_id)
+    async fn keep_alive_presence(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let expiry = self.timings.presence_expiry_secs as usize;
+        let _: () = con
+            .expire(Self::key_presence(room_id, user_id), expiry)
+            .await?;
+        Ok(())
     }
 
-    pub async fn del_presences(&mut self, room_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con.del(Self::key_presences(room_id)).await.unwrap();
+    async fn scan_presence(&self, room_id: String) -> Result<Vec<String>> {
+        let mut con = self.con();
+        let iter = con
+            .scan_match(Self::key_presence(room_id, "*".to_string()))
+            .await?;
+        Ok(iter.map(Self::rkey_presence).collect().await)
     }
 
-    pub async fn add_presences(&mut self, room_id: String, user_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con
-            .sadd(Self::key_presences(room_id), user_id)
-            .await
-            .unwrap();
+    async fn del_presences(&self, room_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.del(Self::key_presences(room_id)).await?;
+        Ok(())
     }
 
-    pub async fn rem_presences(&mut self, room_id: String, user_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con
-            .srem(Self::key_presences(room_id), user_id)
-            .await
-            .unwrap();
+    async fn add_presences(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.sadd(Self::key_presences(room_id), user_id).await?;
+        Ok(())
     }
 
-    pub async fn list_presences(&mut self, room_id: String) -> Vec<String> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let iter = con.sscan(Self::key_presences(room_id)).await.unwrap();
-        iter.collect().await
+    async fn rem_presences(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.srem(Self::key_presences(room_id), user_id).await?;
+        Ok(())
     }
 
-    fn key_presence_keyspace(room_id: String) -> String {
-        format!(
-            "__keyspace*__:{}",
-            Self::key_presence(room_id, "*".to_string())
-        )
+    async fn list_presences(&self, room_id: String) -> Result<Vec<String>> {
+        let mut con = self.con();
+        let iter = con.sscan(Self::key_presences(room_id)).await?;
+        Ok(iter.collect().await)
     }
 
-    pub async fn subscribe_presence(
-        &mut self,
+    async fn subscribe_presence(
+        &self,
         room_id: String,
-    ) -> tokio::sync::mpsc::Receiver<PresenceEvent> {
+    ) -> Result<tokio::sync::mpsc::Receiver<PresenceEvent>> {
         let (tx, rx) = tokio::sync::mpsc::channel(5);
 
-        let con = self
-            .blockable_client()
-            .get_async_connection()
-            .await
-            .unwrap();
+        let con = self.blockable_client()?.get_async_connection().await?;
+        let mut pubsub = con.into_pubsub();
+        pubsub
+            .psubscribe(Self::key_presence_keyspace(room_id))
+            .await?;
 
         tokio::task::spawn(async move {
-            let mut pubsub = con.into_pubsub();
-            pubsub
-                .psubscribe(Self::key_presence_keyspace(room_id))
-                .await
-                .unwrap();
             let mut stream = pubsub.on_message();
 
-            loop {
-                let message = stream.next().await.unwrap();
-                let activity = match message.get_payload::<String>().unwrap().as_str() {
-                    "new" => Some(PresenceEventActivty::Join),
-                    "del" => Some(PresenceEventActivty::Leave),
-                    "expired" => Some(PresenceEventActivty::Leave),
+            //Ends when the pubsub connection drops or nobody listens anymore, dropping tx lets the subscriber know
+            while let Some(message) = stream.next().await {
+                let activity = match message.get_payload::<String>().as_deref() {
+                    Ok("new") => Some(PresenceEventActivty::Join),
+                    Ok("del") => Some(PresenceEventActivty::Leave),
+                    Ok("expired") => Some(PresenceEventActivty::Leave),
                     _ => None,
                 };
 
@@ -113,22 +126,24 @@ impl db::DbInternal {
                     let user_id = Self::rkey_presence(message.get_channel_name().to_string());
                     let event = PresenceEvent { user_id, activity };
 
-                    tx.send(event).await.unwrap();
+                    if tx.send(event).await.is_err() {
+                        break;
+                    }
                 }
             }
         });
 
-        rx
+        Ok(rx)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum PresenceEventActivty {
     Join,
     Leave,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PresenceEvent {
     pub user_id: String,
     pub activity: PresenceEventActivty,
@@ -1,58 +1,320 @@
+use async_trait::async_trait;
+use futures_util::StreamExt;
 use redis::AsyncCommands;
 
+use std::collections::HashMap;
+
 use crate::db;
+use crate::error::{Error, Result};
+
+#[async_trait]
+pub trait QueueStorage {
+    async fn push_queue(&self, room_id: String, user_id: String) -> Result<()>;
+    async fn rem_queue(&self, room_id: String, user_id: String) -> Result<()>;
+    //Like rem_queue, returns whether the user was in the rotation
+    async fn take_queue(&self, room_id: String, user_id: String) -> Result<bool>;
+    async fn pop_queue(&self, room_id: String) -> Result<Option<String>>;
+    //Puts a DJ back at the head of the rotation, e.g. when their turn has to be retried
+    async fn push_front_queue(&self, room_id: String, user_id: String) -> Result<()>;
+    async fn list_queue(&self, room_id: String) -> Result<Vec<String>>;
+    async fn push_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        track_id: String,
+    ) -> Result<()>;
+    async fn pop_user_queue(&self, room_id: String, user_id: String) -> Result<Option<String>>;
+    //Undoes a pop_user_queue
+    async fn push_front_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        track_id: String,
+    ) -> Result<()>;
+    async fn list_user_queue(&self, room_id: String, user_id: String) -> Result<Vec<String>>;
+    //Only touches the queue if track_id is still at index, returns whether it did
+    async fn remove_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        index: usize,
+        track_id: String,
+    ) -> Result<bool>;
+    async fn move_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        index: usize,
+        to: usize,
+        track_id: String,
+    ) -> Result<bool>;
+    async fn clear_user_queue(&self, room_id: String, user_id: String) -> Result<()>;
+    //Every room and user with a queue, across all rooms
+    async fn list_user_queues(&self) -> Result<Vec<(String, String)>>;
+    //Users who leave the rotation once the current track has played
+    async fn set_stepping_down(&self, room_id: String, user_id: String) -> Result<()>;
+    //Clears the flag, returns whether it was set
+    async fn take_stepping_down(&self, room_id: String, user_id: String) -> Result<bool>;
+    //Users who dropped out of the room, until is when their grace period ends in ms since the epoch
+    async fn set_away(&self, room_id: String, user_id: String, until: u128) -> Result<()>;
+    //Clears the mark, returns whether it was set
+    async fn take_away(&self, room_id: String, user_id: String) -> Result<bool>;
+    async fn list_away(&self, room_id: String) -> Result<Vec<(String, u128)>>;
+    //ISRCs of tracks queued or played in the room, so re-releases can be told apart without asking Spotify
+    async fn set_isrc(&self, room_id: String, track_id: String, isrc: String) -> Result<()>;
+    //In the order of track_ids, None for tracks without a known ISRC
+    async fn list_isrcs(
+        &self,
+        room_id: String,
+        track_ids: Vec<String>,
+    ) -> Result<Vec<Option<String>>>;
+}
+
+//Rewrites the whole list, user queues are short and LINSERT can't tell duplicate tracks apart.
+//ARGV: index, track_id, and the position to move to (left out to remove the track)
+const REORDER_SCRIPT: &str = r"
+local items = redis.call('LRANGE', KEYS[1], 0, -1)
+local index = tonumber(ARGV[1]) + 1
+if items[index] ~= ARGV[2] then
+    return 0
+end
+
+local track = table.remove(items, index)
+if ARGV[3] then
+    local to = tonumber(ARGV[3]) + 1
+    if to > #items + 1 then
+        return 0
+    end
+    table.insert(items, to, track)
+end
+
+redis.call('DEL', KEYS[1])
+if #items > 0 then
+    redis.call('RPUSH', KEYS[1], unpack(items))
+end
+return 1
+";
 
 impl db::DbInternal {
     fn key_queue(room_id: String) -> String {
         format!("room:{}:queue", room_id)
     }
 
-    pub async fn push_queue(&mut self, room_id: String, user_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con.rpush(Self::key_queue(room_id), user_id).await.unwrap();
+    fn key_user_queue(room_id: String, user_id: String) -> String {
+        format!("{}:{}", Self::key_queue(room_id), user_id)
     }
 
-    pub async fn rem_queue(&mut self, room_id: String, user_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con
-            .lrem(Self::key_queue(room_id), 0, user_id)
-            .await
-            .unwrap();
+    fn key_stepping_down(room_id: String) -> String {
+        format!("room:{}:stepping_down", room_id)
     }
 
-    pub async fn pop_queue(&mut self, room_id: String) -> Option<String> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        con.lpop(Self::key_queue(room_id), None).await.unwrap()
+    fn key_away(room_id: String) -> String {
+        format!("room:{}:away", room_id)
     }
 
-    pub async fn list_queue(&mut self, room_id: String) -> Vec<String> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        con.lrange(Self::key_queue(room_id), 0, -1).await.unwrap()
+    fn key_isrcs(room_id: String) -> String {
+        format!("room:{}:isrcs", room_id)
     }
+}
 
-    fn key_user_queue(room_id: String, user_id: String) -> String {
-        format!("{}:{}", Self::key_queue(room_id), user_id)
+#[async_trait]
+impl QueueStorage for db::DbInternal {
+    async fn push_queue(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.rpush(Self::key_queue(room_id), user_id).await?;
+        Ok(())
+    }
+
+    async fn rem_queue(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.lrem(Self::key_queue(room_id), 0, user_id).await?;
+        Ok(())
+    }
+
+    async fn take_queue(&self, room_id: String, user_id: String) -> Result<bool> {
+        let mut con = self.con();
+        let removed: i32 = con.lrem(Self::key_queue(room_id), 0, user_id).await?;
+        Ok(removed > 0)
+    }
+
+    async fn pop_queue(&self, room_id: String) -> Result<Option<String>> {
+        let mut con = self.con();
+        Ok(con.lpop(Self::key_queue(room_id), None).await?)
+    }
+
+    async fn push_front_queue(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.lpush(Self::key_queue(room_id), user_id).await?;
+        Ok(())
     }
 
-    pub async fn push_user_queue(&mut self, room_id: String, user_id: String, track_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
+    async fn list_queue(&self, room_id: String) -> Result<Vec<String>> {
+        let mut con = self.con();
+        Ok(con.lrange(Self::key_queue(room_id), 0, -1).await?)
+    }
+
+    async fn push_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        track_id: String,
+    ) -> Result<()> {
+        let mut con = self.con();
         let _: () = con
             .rpush(Self::key_user_queue(room_id, user_id), track_id)
-            .await
-            .unwrap();
+            .await?;
+        Ok(())
+    }
+
+    async fn pop_user_queue(&self, room_id: String, user_id: String) -> Result<Option<String>> {
+        let mut con = self.con();
+        Ok(con
+            .lpop(Self::key_user_queue(room_id, user_id), None)
+            .await?)
     }
 
-    pub async fn pop_user_queue(&mut self, room_id: String, user_id: String) -> Option<String> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        con.lpop(Self::key_user_queue(room_id, user_id), None)
-            .await
-            .unwrap()
+    async fn push_front_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        track_id: String,
+    ) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con
+            .lpush(Self::key_user_queue(room_id, user_id), track_id)
+            .await?;
+        Ok(())
     }
 
-    pub async fn list_user_queue(&mut self, room_id: String, user_id: String) -> Vec<String> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        con.lrange(Self::key_user_queue(room_id, user_id), 0, -1)
-            .await
-            .unwrap()
+    async fn list_user_queue(&self, room_id: String, user_id: String) -> Result<Vec<String>> {
+        let mut con = self.con();
+        Ok(con
+            .lrange(Self::key_user_queue(room_id, user_id), 0, -1)
+            .await?)
+    }
+
+    async fn remove_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        index: usize,
+        track_id: String,
+    ) -> Result<bool> {
+        let mut con = self.con();
+        let removed: i32 = redis::Script::new(REORDER_SCRIPT)
+            .key(Self::key_user_queue(room_id, user_id))
+            .arg(index)
+            .arg(track_id)
+            .invoke_async(&mut con)
+            .await?;
+        Ok(removed == 1)
+    }
+
+    async fn move_user_queue(
+        &self,
+        room_id: String,
+        user_id: String,
+        index: usize,
+        to: usize,
+        track_id: String,
+    ) -> Result<bool> {
+        let mut con = self.con();
+        let moved: i32 = redis::Script::new(REORDER_SCRIPT)
+            .key(Self::key_user_queue(room_id, user_id))
+            .arg(index)
+            .arg(track_id)
+            .arg(to)
+            .invoke_async(&mut con)
+            .await?;
+        Ok(moved == 1)
+    }
+
+    async fn clear_user_queue(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.del(Self::key_user_queue(room_id, user_id)).await?;
+        Ok(())
+    }
+
+    async fn list_user_queues(&self) -> Result<Vec<(String, String)>> {
+        let mut con = self.con();
+        let keys: Vec<String> = con
+            .scan_match(Self::key_user_queue("*".to_string(), "*".to_string()))
+            .await?
+            .collect()
+            .await;
+
+        //Room ids can't contain a colon, user ids do
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| {
+                let (room_id, user_id) = key.strip_prefix("room:")?.split_once(":queue:")?;
+                Some((room_id.to_string(), user_id.to_string()))
+            })
+            .collect())
+    }
+
+    async fn set_stepping_down(&self, room_id: String, user_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.sadd(Self::key_stepping_down(room_id), user_id).await?;
+        Ok(())
+    }
+
+    async fn take_stepping_down(&self, room_id: String, user_id: String) -> Result<bool> {
+        let mut con = self.con();
+        let removed: i32 = con.srem(Self::key_stepping_down(room_id), user_id).await?;
+        Ok(removed == 1)
+    }
+
+    async fn set_away(&self, room_id: String, user_id: String, until: u128) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con
+            .hset(Self::key_away(room_id), user_id, until.to_string())
+            .await?;
+        Ok(())
+    }
+
+    async fn take_away(&self, room_id: String, user_id: String) -> Result<bool> {
+        let mut con = self.con();
+        let removed: i32 = con.hdel(Self::key_away(room_id), user_id).await?;
+        Ok(removed == 1)
+    }
+
+    async fn list_away(&self, room_id: String) -> Result<Vec<(String, u128)>> {
+        let mut con = self.con();
+        let away: HashMap<String, String> = con.hgetall(Self::key_away(room_id)).await?;
+
+        away.into_iter()
+            .map(|(user_id, until)| {
+                let until = until
+                    .parse()
+                    .map_err(|_| Error::Decode(format!("away until of {}", user_id)))?;
+                Ok((user_id, until))
+            })
+            .collect()
+    }
+
+    async fn set_isrc(&self, room_id: String, track_id: String, isrc: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.hset(Self::key_isrcs(room_id), track_id, isrc).await?;
+        Ok(())
+    }
+
+    async fn list_isrcs(
+        &self,
+        room_id: String,
+        track_ids: Vec<String>,
+    ) -> Result<Vec<Option<String>>> {
+        if track_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        //HMGET replies with a list even for one field, unlike what hget would ask for
+        let mut con = self.con();
+        let isrcs = redis::cmd("HMGET")
+            .arg(Self::key_isrcs(room_id))
+            .arg(track_ids)
+            .query_async(&mut con)
+            .await?;
+        Ok(isrcs)
     }
 }
@@ -1,8 +1,43 @@
 use crate::db;
-use redis::{AsyncCommands, Commands};
+use crate::db::playing::Playing;
+use crate::error::{self, Error};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use redis::AsyncCommands;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[async_trait]
+pub trait RoomStorage {
+    async fn create_room(&self, room: Room) -> error::Result<()>;
+    async fn exists_room(&self, room_id: String) -> error::Result<bool>;
+    async fn get_room(&self, room_id: String) -> error::Result<Option<Room>>;
+    //Replaces the stored room, returns false if there's no room with its id
+    async fn update_room(&self, room: Room) -> error::Result<bool>;
+    //Drops the room and everything kept about it, messages linger a bit so listeners hear about it
+    async fn delete_room(&self, room_id: String) -> error::Result<()>;
+    async fn list_rooms(&self) -> error::Result<Vec<Room>>;
+    //Every room with how many are listening and what's playing, for the room list
+    async fn list_room_overviews(&self) -> error::Result<Vec<RoomOverview>>;
+    async fn offer_room(&self, room_id: String) -> error::Result<()>;
+    async fn claim_room(&self) -> error::Result<tokio::sync::oneshot::Receiver<Option<String>>>;
+    async fn keep_alive_room_claim(&self, room_id: String) -> error::Result<()>;
+    async fn release_room(&self, room_id: String) -> error::Result<()>;
+    //Drops the claim without offering the room again, for rooms nobody should be serving
+    async fn unclaim_room(&self, room_id: String) -> error::Result<()>;
+    //Someone was in the room at, in ms since the epoch
+    async fn touch_room(&self, room_id: String, at: u128) -> error::Result<()>;
+    //Clears the room's queues, votes, playing track and chat and stops offering it, the room and its settings stay
+    async fn archive_room(&self, room_id: String) -> error::Result<()>;
+    //Returns whether the room was archived
+    async fn unarchive_room(&self, room_id: String) -> error::Result<bool>;
+    async fn is_archived_room(&self, room_id: String) -> error::Result<bool>;
+    //Only one instance sweeps idle rooms at a time, returns whether the holder got the lease until it expires
+    async fn lease_janitor(&self, holder: String, expiry: Duration) -> error::Result<bool>;
+}
 
 impl db::DbInternal {
     fn key_room(room_id: String) -> String {
@@ -10,150 +45,529 @@ impl db::DbInternal {
     }
 
     fn key_rooms() -> String {
-        format!("rooms")
+        "rooms".to_string()
     }
 
     fn key_rooms_free() -> String {
-        format!("rooms_free")
+        "rooms_free".to_string()
     }
 
     fn key_room_claimed(room_id: String) -> String {
         format!("{}:claimed", Self::key_room(room_id))
     }
 
-    pub async fn create_room(&mut self, room: Room) -> Result<(), Vec<String>> {
-        match room.validate() {
-            Ok(_) => {
-                let room_id = room.id.clone();
-                let key = Self::key_room(room_id.clone());
-                let args: Vec<(String, String)> = room.into();
+    fn key_rooms_active() -> String {
+        "rooms_active".to_string()
+    }
 
-                //Have to block so that no one intrudes the transaction unfortunately so have to keep it short
-                let mut con = self.client.get_connection().unwrap();
-                let _: () = redis::cmd("WATCH")
-                    .arg(key.clone())
-                    .query(&mut con)
-                    .unwrap();
-                let exists: i32 = con.exists(key.clone()).unwrap();
+    fn key_rooms_archived() -> String {
+        "rooms_archived".to_string()
+    }
 
-                let _: () = redis::cmd("MULTI").query(&mut con).unwrap();
+    fn key_janitor_lock() -> String {
+        "janitor:lock".to_string()
+    }
 
-                if exists == 0 {
-                    let _: () = con.hset_multiple(key.clone(), &args[..]).unwrap();
-                }
+    //Rooms can be deleted between listing the ids and reading them
+    fn listed_room(room_id: String, data: HashMap<String, String>) -> error::Result<Option<Room>> {
+        if data.is_empty() {
+            tracing::warn!("Room {} is listed in rooms but doesn't exist", room_id);
+            return Ok(None);
+        }
+
+        let room = Room::try_from(Some(data))
+            .map_err(|e| Error::Decode(format!("room {}: {}", room_id, e)))?;
+        Ok(Some(room))
+    }
+}
 
-                let res: Vec<()> = redis::cmd("EXEC").query(&mut con).unwrap();
+//Long enough for every socket in a deleted room to read the goodbye
+const DELETED_MESSAGES_EXPIRY_SECS: usize = 60;
 
-                if res.is_empty() {
-                    let mut errors = Vec::new();
-                    errors.push(format!("Room id {} is already taken.", room_id.clone()));
-                    Err(errors)
-                } else {
-                    let _: () = con.sadd(Self::key_rooms(), room_id).unwrap();
-                    Ok(())
-                }
-            }
-            Err(errors) => Err(errors),
+#[async_trait]
+impl RoomStorage for db::DbInternal {
+    async fn create_room(&self, room: Room) -> error::Result<()> {
+        room.validate().map_err(Error::Validation)?;
+
+        let room_id = room.id.clone();
+        let key = Self::key_room(room_id.clone());
+        let args: Vec<(String, String)> = room.into();
+
+        //WATCH state lives on the connection, so transactions can't share the multiplexed one
+        let mut con = self.transaction_connection().await?;
+        let _: () = redis::cmd("WATCH")
+            .arg(key.clone())
+            .query_async(&mut con)
+            .await?;
+        let exists: i32 = con.exists(key.clone()).await?;
+
+        let _: () = redis::cmd("MULTI").query_async(&mut con).await?;
+
+        if exists == 0 {
+            let _: () = con.hset_multiple(key.clone(), &args[..]).await?;
+        }
+
+        let res: Vec<()> = redis::cmd("EXEC").query_async(&mut con).await?;
+
+        if res.is_empty() {
+            let errors = vec![format!("Room id {} is already taken.", room_id)];
+            Err(Error::Validation(errors))
+        } else {
+            let _: () = con.sadd(Self::key_rooms(), room_id).await?;
+            Ok(())
         }
     }
 
-    pub async fn exists_room(&mut self, room_id: String) -> bool {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        con.exists(Self::key_room(room_id)).await.unwrap()
+    async fn exists_room(&self, room_id: String) -> error::Result<bool> {
+        let mut con = self.con();
+        Ok(con.exists(Self::key_room(room_id)).await?)
     }
 
-    pub async fn get_room(&mut self, room_id: String) -> Option<Room> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let data: Option<HashMap<String, String>> =
-            con.hgetall(Self::key_room(room_id)).await.unwrap();
-        match Room::try_from(data) {
-            Ok(data) => Some(data),
-            Err(e) => {
-                log::debug!("Failed to get room with '{}'", e);
-                None
+    async fn get_room(&self, room_id: String) -> error::Result<Option<Room>> {
+        let mut con = self.con();
+        let data: HashMap<String, String> = con.hgetall(Self::key_room(room_id.clone())).await?;
+
+        if data.is_empty() {
+            return Ok(None);
+        }
+
+        let room = Room::try_from(Some(data))
+            .map_err(|e| Error::Decode(format!("room {}: {}", room_id, e)))?;
+        Ok(Some(room))
+    }
+
+    async fn update_room(&self, room: Room) -> error::Result<bool> {
+        room.validate().map_err(Error::Validation)?;
+
+        let key = Self::key_room(room.id.clone());
+        let args: Vec<(String, String)> = room.into();
+
+        let mut con = self.transaction_connection().await?;
+        loop {
+            let _: () = redis::cmd("WATCH")
+                .arg(key.clone())
+                .query_async(&mut con)
+                .await?;
+            let exists: i32 = con.exists(key.clone()).await?;
+            if exists == 0 {
+                let _: () = redis::cmd("UNWATCH").query_async(&mut con).await?;
+                return Ok(false);
+            }
+
+            let _: () = redis::cmd("MULTI").query_async(&mut con).await?;
+            //Rules that were unset have to go as well
+            let _: () = con.del(key.clone()).await?;
+            let _: () = con.hset_multiple(key.clone(), &args[..]).await?;
+            let res: Vec<()> = redis::cmd("EXEC").query_async(&mut con).await?;
+
+            //Aborted when the room changed in the meantime, see if it's still there
+            if !res.is_empty() {
+                return Ok(true);
             }
         }
     }
 
-    pub async fn list_rooms(&mut self) -> Vec<Room> {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let room_ids: Vec<String> = con.smembers(Self::key_rooms()).await.unwrap();
-        std::mem::drop(con);
+    async fn delete_room(&self, room_id: String) -> error::Result<()> {
+        let mut con = self.con();
+        let key = Self::key_room(room_id.clone());
+        let messages = Self::key_messages(room_id.clone());
+        let keys: Vec<String> = con
+            .scan_match(format!("{}:*", key))
+            .await?
+            .filter(|found| std::future::ready(*found != messages))
+            .collect()
+            .await;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(key);
+        if !keys.is_empty() {
+            pipe.del(keys);
+        }
+        let _: () = pipe
+            .srem(Self::key_rooms(), room_id.clone())
+            .lrem(Self::key_rooms_free(), 0, room_id.clone())
+            .zrem(Self::key_rooms_active(), room_id.clone())
+            .srem(Self::key_rooms_archived(), room_id)
+            .expire(messages, DELETED_MESSAGES_EXPIRY_SECS)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_rooms(&self) -> error::Result<Vec<Room>> {
+        let mut con = self.con();
+        let room_ids: Vec<String> = con.smembers(Self::key_rooms()).await?;
+
+        let mut pipe = redis::pipe();
+        for room_id in &room_ids {
+            pipe.hgetall(Self::key_room(room_id.clone()));
+        }
+        let replies: Vec<HashMap<String, String>> = pipe.query_async(&mut con).await?;
 
         let mut rooms = Vec::new();
-        for room_id in room_ids {
-            //Could be kinda slow, maybe rethink this?
-            rooms.push(self.get_room(room_id).await.unwrap());
+        for (room_id, data) in room_ids.into_iter().zip(replies) {
+            if let Some(room) = Self::listed_room(room_id, data)? {
+                rooms.push(room);
+            }
         }
 
-        rooms
+        Ok(rooms)
     }
 
-    pub async fn offer_room(&mut self, room_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
-        let _: () = con.lpush(Self::key_rooms_free(), room_id).await.unwrap();
+    async fn list_room_overviews(&self) -> error::Result<Vec<RoomOverview>> {
+        let mut con = self.con();
+        let room_ids: Vec<String> = con.smembers(Self::key_rooms()).await?;
+
+        //Everything in one round trip, five replies per room
+        let mut pipe = redis::pipe();
+        for room_id in &room_ids {
+            pipe.hgetall(Self::key_room(room_id.clone()))
+                .scard(Self::key_presences(room_id.clone()))
+                .hgetall(Self::key_playing(room_id.clone()))
+                .sismember(Self::key_rooms_archived(), room_id.clone())
+                .zscore(Self::key_rooms_active(), room_id.clone());
+        }
+        #[allow(clippy::type_complexity)]
+        let replies: Vec<(
+            HashMap<String, String>,
+            usize,
+            HashMap<String, String>,
+            bool,
+            Option<u64>,
+        )> = pipe.query_async(&mut con).await?;
+
+        let mut overviews = Vec::new();
+        for (room_id, (data, listeners, playing, archived, last_active)) in
+            room_ids.into_iter().zip(replies)
+        {
+            let Some(room) = Self::listed_room(room_id, data)? else {
+                continue;
+            };
+            let playing = if playing.is_empty() {
+                None
+            } else {
+                Some(Playing::try_from(playing)?)
+            };
+            overviews.push(RoomOverview {
+                room,
+                listeners,
+                playing,
+                archived,
+                last_active: last_active.map(u128::from),
+            });
+        }
+
+        Ok(overviews)
     }
 
-    pub async fn claim_room(&mut self) -> tokio::sync::oneshot::Receiver<Option<String>> {
-        let client = self.blockable_client();
-        let mut con = client.get_async_connection().await.unwrap();
+    async fn offer_room(&self, room_id: String) -> error::Result<()> {
+        let mut con = self.con();
+        let _: () = con.lpush(Self::key_rooms_free(), room_id).await?;
+        Ok(())
+    }
+
+    async fn claim_room(&self) -> error::Result<tokio::sync::oneshot::Receiver<Option<String>>> {
+        //BLPOP blocks the whole connection, so it gets a dedicated one
+        let client = self.blockable_client()?;
+        let mut con = client.get_async_connection().await?;
+        let mut inner_con = self.transaction_connection().await?;
         let (tx, rx) = tokio::sync::oneshot::channel();
+        let timeout = self.timings.room_claim_timeout_secs as usize;
+        let expiry = self.timings.room_claim_expiry_secs as usize;
 
         tokio::task::spawn(async move {
-            let res: Vec<String> = con.blpop(Self::key_rooms_free(), 5).await.unwrap();
-
-            let res = if res.is_empty() {
-                //No rooms to be claimed
-                None
-            } else {
-                let room_id = res.get(1).unwrap().clone();
+            let res: error::Result<Option<String>> = async {
+                let res: Vec<String> = con.blpop(Self::key_rooms_free(), timeout).await?;
+
+                let room_id = match res.get(1) {
+                    Some(room_id) => room_id.clone(),
+                    //No rooms to be claimed
+                    None => return Ok(None),
+                };
                 let key = Self::key_room_claimed(room_id.clone());
 
-                //Have to block, so try to be as fast as possible
-                let mut inner_con = client.get_connection().unwrap();
                 let _: () = redis::cmd("WATCH")
                     .arg(key.clone())
-                    .query(&mut inner_con)
-                    .unwrap();
-                let exists: i32 = inner_con.exists(key.clone()).unwrap();
+                    .query_async(&mut inner_con)
+                    .await?;
+                let exists: i32 = inner_con.exists(key.clone()).await?;
 
-                let _: () = redis::cmd("MULTI").query(&mut inner_con).unwrap();
+                let _: () = redis::cmd("MULTI").query_async(&mut inner_con).await?;
 
                 if exists == 0 {
-                    let _: () = inner_con.set(key.clone(), "").unwrap();
+                    let _: () = inner_con.set(key.clone(), "").await?;
                 }
 
-                let res: Vec<()> = redis::cmd("EXEC").query(&mut inner_con).unwrap();
+                let res: Vec<()> = redis::cmd("EXEC").query_async(&mut inner_con).await?;
 
                 if res.is_empty() {
                     //Room already claimed
-                    None
+                    Ok(None)
                 } else {
-                    let _: () = con.expire(key.clone(), 5).await.unwrap();
-                    Some(room_id.clone())
+                    let _: () = con.expire(key.clone(), expiry).await?;
+                    Ok(Some(room_id))
                 }
-            };
+            }
+            .await;
 
-            tx.send(res).unwrap();
+            let res = res.unwrap_or_else(|err| {
+                tracing::error!("Failed to claim a room: {}", err);
+                None
+            });
+            tx.send(res).unwrap_or(());
         });
 
-        rx
+        Ok(rx)
     }
 
-    pub async fn keep_alive_room_claim(&mut self, room_id: String) {
-        let mut con = self.client.get_async_connection().await.unwrap();
+    async fn keep_alive_room_claim(&self, room_id: String) -> error::Result<()> {
+        let mut con = self.con();
+        let expiry = self.timings.room_claim_expiry_secs as usize;
+        let _: () = con.expire(Self::key_room_claimed(room_id), expiry).await?;
+        Ok(())
+    }
+
+    async fn release_room(&self, room_id: String) -> error::Result<()> {
+        //Drop the claim and offer the room in one go so another instance can pick it up right away
+        let mut con = self.con();
+        let _: () = redis::pipe()
+            .atomic()
+            .del(Self::key_room_claimed(room_id.clone()))
+            .lpush(Self::key_rooms_free(), room_id)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn unclaim_room(&self, room_id: String) -> error::Result<()> {
+        let mut con = self.con();
+        let _: () = con.del(Self::key_room_claimed(room_id)).await?;
+        Ok(())
+    }
+
+    async fn touch_room(&self, room_id: String, at: u128) -> error::Result<()> {
+        let mut con = self.con();
         let _: () = con
-            .expire(Self::key_room_claimed(room_id), 5)
-            .await
-            .unwrap();
+            .zadd(Self::key_rooms_active(), room_id, at as u64)
+            .await?;
+        Ok(())
+    }
+
+    async fn archive_room(&self, room_id: String) -> error::Result<()> {
+        let mut con = self.con();
+        let key = Self::key_room(room_id.clone());
+        let user_queues: Vec<String> = con
+            .scan_match(format!("{}:queue:*", key))
+            .await?
+            .collect()
+            .await;
+        //The democratic pool keeps a key per track and per track's votes
+        let pool: Vec<String> = con
+            .scan_match(format!("{}:pool*", key))
+            .await?
+            .collect()
+            .await;
+        let transient: Vec<String> = [
+            "messages",
+            "playing",
+            "skip_votes",
+            "queue",
+            "stepping_down",
+            "away",
+            "presences",
+            "listening_time",
+            "dj_votes",
+            "upvoters",
+        ]
+        .iter()
+        .map(|suffix| format!("{}:{}", key, suffix))
+        .collect();
+
+        //The claim stays, so the worker serving the room can tell it's archived and stop
+        let mut pipe = redis::pipe();
+        pipe.atomic().del(transient);
+        if !user_queues.is_empty() {
+            pipe.del(user_queues);
+        }
+        if !pool.is_empty() {
+            pipe.del(pool);
+        }
+        let _: () = pipe
+            .sadd(Self::key_rooms_archived(), room_id.clone())
+            .lrem(Self::key_rooms_free(), 0, room_id)
+            .query_async(&mut con)
+            .await?;
+        Ok(())
+    }
+
+    async fn unarchive_room(&self, room_id: String) -> error::Result<bool> {
+        let mut con = self.con();
+        let removed: i32 = con.srem(Self::key_rooms_archived(), room_id).await?;
+        Ok(removed > 0)
+    }
+
+    async fn is_archived_room(&self, room_id: String) -> error::Result<bool> {
+        let mut con = self.con();
+        Ok(con.sismember(Self::key_rooms_archived(), room_id).await?)
+    }
+
+    async fn lease_janitor(&self, holder: String, expiry: Duration) -> error::Result<bool> {
+        let mut con = self.con();
+        let res: Option<String> = redis::cmd("SET")
+            .arg(Self::key_janitor_lock())
+            .arg(holder)
+            .arg("NX")
+            .arg("EX")
+            .arg(expiry.as_secs())
+            .query_async(&mut con)
+            .await?;
+        Ok(res.is_some())
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Room {
     pub id: String,
     pub title: String,
     pub owner: String,
+    #[serde(default)]
+    pub policy: SchedulingPolicy,
+    #[serde(default)]
+    pub mode: RoomMode,
+    #[serde(default)]
+    pub rules: AdmissionRules,
+    #[serde(default)]
+    pub visibility: Visibility,
+}
+
+#[derive(Debug, Clone)]
+pub struct RoomOverview {
+    pub room: Room,
+    pub listeners: usize,
+    //Can be a track that already ended
+    pub playing: Option<Playing>,
+    pub archived: bool,
+    //Missing for rooms nobody has been in since activity was tracked
+    pub last_active: Option<u128>,
+}
+
+//Limits on what can be queued in a room, see crate::admission. The default lets everything in
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdmissionRules {
+    pub no_explicit: bool,
+    pub max_duration_ms: Option<u64>,
+    //Inclusive, by the year the album came out
+    pub min_year: Option<i32>,
+    pub max_year: Option<i32>,
+    //Country code the track has to be playable in, like SE
+    pub market: Option<String>,
+}
+
+impl AdmissionRules {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+//Unlisted rooms are left out of the room list but open to anyone with the link,
+//private ones also need an invite, see crate::invite
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Unlisted,
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Private => "private",
+        }
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Visibility::Public),
+            "unlisted" => Ok(Visibility::Unlisted),
+            "private" => Ok(Visibility::Private),
+            _ => Err("Unknown room visibility"),
+        }
+    }
+}
+
+//Whether DJs take turns playing their own queues, or everyone's tracks go into one pool the room votes on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomMode {
+    #[default]
+    Rotation,
+    Democratic,
+}
+
+impl RoomMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoomMode::Rotation => "rotation",
+            RoomMode::Democratic => "democratic",
+        }
+    }
+}
+
+impl FromStr for RoomMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rotation" => Ok(RoomMode::Rotation),
+            "democratic" => Ok(RoomMode::Democratic),
+            _ => Err("Unknown room mode"),
+        }
+    }
+}
+
+//How the room picks its next DJ out of the rotation, see crate::schedule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedulingPolicy {
+    #[default]
+    RoundRobin,
+    FairShare,
+    VoteWeighted,
+    Random,
+}
+
+impl SchedulingPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SchedulingPolicy::RoundRobin => "round_robin",
+            SchedulingPolicy::FairShare => "fair_share",
+            SchedulingPolicy::VoteWeighted => "vote_weighted",
+            SchedulingPolicy::Random => "random",
+        }
+    }
+}
+
+impl FromStr for SchedulingPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(SchedulingPolicy::RoundRobin),
+            "fair_share" => Ok(SchedulingPolicy::FairShare),
+            "vote_weighted" => Ok(SchedulingPolicy::VoteWeighted),
+            "random" => Ok(SchedulingPolicy::Random),
+            _ => Err("Unknown scheduling policy"),
+        }
+    }
 }
 
 impl Room {
@@ -161,7 +575,7 @@ impl Room {
         let mut errors = Vec::new();
         let protected_ids = HashSet::from(["new"]);
 
-        if self.id.len() == 0 {
+        if self.id.is_empty() {
             errors.push("Room id must not be empty.".to_string());
         }
 
@@ -178,7 +592,7 @@ impl Room {
             errors.push("Room id contains forbidden character.".to_string());
         }
 
-        if self.title.len() == 0 {
+        if self.title.is_empty() {
             errors.push("Room name can't be empty".to_string());
         }
 
@@ -186,6 +600,23 @@ impl Room {
             errors.push("Room title must be less than 64 characters long.".to_string());
         }
 
+        if self.rules.max_duration_ms == Some(0) {
+            errors.push("Maximum track length has to be above 0.".to_string());
+        }
+
+        if let (Some(min_year), Some(max_year)) = (self.rules.min_year, self.rules.max_year) {
+            if min_year > max_year {
+                errors.push("Earliest release year can't be after the latest.".to_string());
+            }
+        }
+
+        let market = Regex::new(r"^[A-Z]{2}$").unwrap();
+        if let Some(code) = &self.rules.market {
+            if !market.is_match(code) {
+                errors.push("Market has to be a two letter country code like SE.".to_string());
+            }
+        }
+
         if errors.is_empty() {
             Ok(())
         } else {
@@ -194,14 +625,43 @@ impl Room {
     }
 }
 
-impl Into<Vec<(String, String)>> for Room {
-    fn into(self) -> Vec<(String, String)> {
-        let mut args: Vec<(String, String)> = Vec::new();
-        args.push(("id".to_string(), self.id));
-        args.push(("title".to_string(), self.title));
-        args.push(("owner".to_string(), self.owner));
+impl From<Room> for Vec<(String, String)> {
+    fn from(room: Room) -> Self {
+        let mut data = vec![
+            ("id".to_string(), room.id),
+            ("title".to_string(), room.title),
+            ("owner".to_string(), room.owner),
+            ("policy".to_string(), room.policy.as_str().to_string()),
+            ("mode".to_string(), room.mode.as_str().to_string()),
+        ];
+
+        //Like the rules, only stored when it isn't the default
+        if room.visibility != Visibility::default() {
+            data.push((
+                "visibility".to_string(),
+                room.visibility.as_str().to_string(),
+            ));
+        }
+
+        //Rules are only stored when they're set
+        let rules = room.rules;
+        if rules.no_explicit {
+            data.push(("no_explicit".to_string(), "true".to_string()));
+        }
+        if let Some(max_duration_ms) = rules.max_duration_ms {
+            data.push(("max_duration_ms".to_string(), max_duration_ms.to_string()));
+        }
+        if let Some(min_year) = rules.min_year {
+            data.push(("min_year".to_string(), min_year.to_string()));
+        }
+        if let Some(max_year) = rules.max_year {
+            data.push(("max_year".to_string(), max_year.to_string()));
+        }
+        if let Some(market) = rules.market {
+            data.push(("market".to_string(), market));
+        }
 
-        args
+        data
     }
 }
 
@@ -214,11 +674,48 @@ impl TryFrom<Option<HashMap<String, String>>> for Room {
         let id = data.get("id").ok_or("Missing id field")?.clone();
         let title = data.get("title").ok_or("Missing title field")?.clone();
         let owner = data.get("owner").ok_or("Missing owner field")?.clone();
+        //Rooms created before policies existed stay round robin
+        let policy = match data.get("policy") {
+            Some(policy) => policy.parse()?,
+            None => SchedulingPolicy::default(),
+        };
+        let mode = match data.get("mode") {
+            Some(mode) => mode.parse()?,
+            None => RoomMode::default(),
+        };
+        let visibility = match data.get("visibility") {
+            Some(visibility) => visibility.parse()?,
+            None => Visibility::default(),
+        };
+        let rules = AdmissionRules {
+            no_explicit: data.get("no_explicit").is_some_and(|value| value == "true"),
+            max_duration_ms: parse_rule(&data, "max_duration_ms")?,
+            min_year: parse_rule(&data, "min_year")?,
+            max_year: parse_rule(&data, "max_year")?,
+            market: data.get("market").cloned(),
+        };
 
-        Ok(Room { id, title, owner })
+        Ok(Room {
+            id,
+            title,
+            owner,
+            policy,
+            mode,
+            rules,
+            visibility,
+        })
     }
 }
 
+fn parse_rule<T: FromStr>(
+    data: &HashMap<String, String>,
+    name: &str,
+) -> Result<Option<T>, &'static str> {
+    data.get(name)
+        .map(|value| value.parse().map_err(|_| "Invalid admission rule"))
+        .transpose()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -229,6 +726,7 @@ mod test {
             id: "test-room".to_string(),
             title: "Test Room".to_string(),
             owner: "user:id:name".to_string(),
+            ..Default::default()
         };
 
         assert_eq!(room.validate(), Ok(()));
@@ -240,6 +738,7 @@ mod test {
             id: "".to_string(),
             title: "".to_string(),
             owner: "".to_string(),
+            ..Default::default()
         };
 
         let res = room.validate();
@@ -253,14 +752,19 @@ mod test {
             id: "test-room".to_string(),
             title: "Test Room".to_string(),
             owner: "user:id:name".to_string(),
+            policy: SchedulingPolicy::FairShare,
+            mode: RoomMode::Democratic,
+            ..Default::default()
         };
 
         let tuple_list: Vec<(String, String)> = room.into();
-        assert_eq!(tuple_list.len(), 3);
+        assert_eq!(tuple_list.len(), 5);
 
         let mut has_id = false;
         let mut has_title = false;
         let mut has_owner = false;
+        let mut has_policy = false;
+        let mut has_mode = false;
 
         for (left, right) in tuple_list {
             //Have to do it like this in case one value appears more than once
@@ -269,7 +773,7 @@ mod test {
                     if right == "test-room" {
                         has_id = true;
                     }
-                },
+                }
                 "title" => {
                     if right == "Test Room" {
                         has_title = true;
@@ -280,9 +784,19 @@ mod test {
                         has_owner = true;
                     }
                 }
+                "policy" => {
+                    if right == "fair_share" {
+                        has_policy = true;
+                    }
+                }
+                "mode" => {
+                    if right == "democratic" {
+                        has_mode = true;
+                    }
+                }
                 _ => {
                     //Unexpected left side value
-                    assert!(false);
+                    panic!("Unexpected field {}", left);
                 }
             }
         }
@@ -290,6 +804,8 @@ mod test {
         assert!(has_id);
         assert!(has_title);
         assert!(has_owner);
+        assert!(has_policy);
+        assert!(has_mode);
     }
 
     #[test]
@@ -306,6 +822,88 @@ mod test {
         assert_eq!(room.id.as_str(), "test-room");
         assert_eq!(room.title.as_str(), "Test Room");
         assert_eq!(room.owner.as_str(), "user:id:name");
+        assert_eq!(room.policy, SchedulingPolicy::RoundRobin);
+        assert_eq!(room.mode, RoomMode::Rotation);
+    }
+
+    #[test]
+    fn test_room_from_hashmap_policy() {
+        let mut map: HashMap<String, String> = HashMap::new();
+        map.insert("id".to_string(), "test-room".to_string());
+        map.insert("owner".to_string(), "user:id:name".to_string());
+        map.insert("title".to_string(), "Test Room".to_string());
+        map.insert("policy".to_string(), "vote_weighted".to_string());
+
+        let room: Room = Some(map.clone()).try_into().unwrap();
+        assert_eq!(room.policy, SchedulingPolicy::VoteWeighted);
+
+        map.insert("policy".to_string(), "loudest".to_string());
+        let res: Result<Room, &str> = Some(map).try_into();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_room_rules_round_trip() {
+        let room = Room {
+            id: "test-room".to_string(),
+            title: "Test Room".to_string(),
+            owner: "user:id:name".to_string(),
+            rules: AdmissionRules {
+                no_explicit: true,
+                max_duration_ms: Some(420_000),
+                min_year: Some(1980),
+                max_year: Some(1989),
+                market: Some("SE".to_string()),
+            },
+            ..Default::default()
+        };
+        assert_eq!(room.validate(), Ok(()));
+
+        let tuple_list: Vec<(String, String)> = room.clone().into();
+        let map: HashMap<String, String> = tuple_list.into_iter().collect();
+        let decoded: Room = Some(map).try_into().unwrap();
+        assert_eq!(decoded.rules, room.rules);
+    }
+
+    #[test]
+    fn test_room_visibility_round_trip() {
+        for visibility in [
+            Visibility::Public,
+            Visibility::Unlisted,
+            Visibility::Private,
+        ] {
+            let room = Room {
+                id: "test-room".to_string(),
+                title: "Test Room".to_string(),
+                owner: "user:id:name".to_string(),
+                visibility,
+                ..Default::default()
+            };
+
+            let tuple_list: Vec<(String, String)> = room.into();
+            let map: HashMap<String, String> = tuple_list.into_iter().collect();
+            let decoded: Room = Some(map).try_into().unwrap();
+            assert_eq!(decoded.visibility, visibility);
+        }
+    }
+
+    #[test]
+    fn test_room_validate_rules() {
+        let room = Room {
+            id: "test-room".to_string(),
+            title: "Test Room".to_string(),
+            owner: "user:id:name".to_string(),
+            rules: AdmissionRules {
+                max_duration_ms: Some(0),
+                min_year: Some(2000),
+                max_year: Some(1990),
+                market: Some("sweden".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(room.validate().unwrap_err().len(), 3);
     }
 
     #[test]
@@ -322,4 +920,4 @@ mod test {
         let res: Result<Room, &str> = Some(map).try_into();
         assert!(res.is_err());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use redis::AsyncCommands;
+
+use crate::db;
+use crate::error::Result;
+
+//What the scheduling policies go on besides the rotation itself
+#[async_trait]
+pub trait ScheduleStorage {
+    //Milliseconds of their own tracks each DJ has played in the room
+    async fn add_listening_time(&self, room_id: String, user_id: String, ms: u64) -> Result<()>;
+    async fn list_listening_time(&self, room_id: String) -> Result<HashMap<String, u64>>;
+    //One upvote per listener for the track that is playing, returns whether this one counted
+    async fn upvote_dj(&self, room_id: String, dj_id: String, voter_id: String) -> Result<bool>;
+    async fn list_dj_votes(&self, room_id: String) -> Result<HashMap<String, u64>>;
+    //Lets everyone vote again, called when a new track starts
+    async fn clear_upvoters(&self, room_id: String) -> Result<()>;
+}
+
+impl db::DbInternal {
+    fn key_listening_time(room_id: String) -> String {
+        format!("room:{}:listening_time", room_id)
+    }
+
+    fn key_dj_votes(room_id: String) -> String {
+        format!("room:{}:dj_votes", room_id)
+    }
+
+    fn key_upvoters(room_id: String) -> String {
+        format!("room:{}:upvoters", room_id)
+    }
+}
+
+#[async_trait]
+impl ScheduleStorage for db::DbInternal {
+    async fn add_listening_time(&self, room_id: String, user_id: String, ms: u64) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con
+            .hincr(Self::key_listening_time(room_id), user_id, ms)
+            .await?;
+        Ok(())
+    }
+
+    async fn list_listening_time(&self, room_id: String) -> Result<HashMap<String, u64>> {
+        let mut con = self.con();
+        Ok(con.hgetall(Self::key_listening_time(room_id)).await?)
+    }
+
+    async fn upvote_dj(&self, room_id: String, dj_id: String, voter_id: String) -> Result<bool> {
+        let mut con = self.con();
+        let added: i32 = con
+            .sadd(Self::key_upvoters(room_id.clone()), voter_id)
+            .await?;
+        if added == 0 {
+            return Ok(false);
+        }
+
+        let _: () = con.hincr(Self::key_dj_votes(room_id), dj_id, 1).await?;
+        Ok(true)
+    }
+
+    async fn list_dj_votes(&self, room_id: String) -> Result<HashMap<String, u64>> {
+        let mut con = self.con();
+        Ok(con.hgetall(Self::key_dj_votes(room_id)).await?)
+    }
+
+    async fn clear_upvoters(&self, room_id: String) -> Result<()> {
+        let mut con = self.con();
+        let _: () = con.del(Self::key_upvoters(room_id)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use std::cmp::Reverse;
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::room::{Room, RoomOverview};
+use crate::error::{Error, Result};
+use crate::socket::data_out::NowPlaying;
+
+//The room list, searched, sorted and paged in memory since there aren't that many rooms.
+//Pages are keyed on the sort value and room id of the last room, so rooms coming and going don't shift them.
+
+pub const PAGE_DEFAULT: usize = 20;
+pub const PAGE_MAX: usize = 50;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomSort {
+    //Most listeners first
+    #[default]
+    Listeners,
+    //Most recently started track first
+    Activity,
+    Title,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomListing {
+    #[serde(flatten)]
+    pub room: Room,
+    pub listeners: usize,
+    //Missing when nothing is playing
+    pub playing: Option<NowPlaying>,
+}
+
+impl From<RoomOverview> for RoomListing {
+    fn from(overview: RoomOverview) -> Self {
+        RoomListing {
+            room: overview.room,
+            listeners: overview.listeners,
+            playing: overview
+                .playing
+                .filter(|playing| playing.is_playing())
+                .map(NowPlaying::from),
+        }
+    }
+}
+
+//Rooms get ordered by this, ties broken by id
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SortKey {
+    Descending(Reverse<u128>),
+    Ascending(String),
+}
+
+impl SortKey {
+    fn of(sort: RoomSort, overview: &RoomOverview) -> Self {
+        match sort {
+            RoomSort::Listeners => SortKey::Descending(Reverse(overview.listeners as u128)),
+            RoomSort::Activity => SortKey::Descending(Reverse(
+                overview
+                    .playing
+                    .as_ref()
+                    .map_or(0, |playing| playing.start_time),
+            )),
+            RoomSort::Title => SortKey::Ascending(overview.room.title.to_lowercase()),
+        }
+    }
+
+    fn parse(sort: RoomSort, value: &str) -> Option<Self> {
+        match sort {
+            RoomSort::Listeners | RoomSort::Activity => {
+                Some(SortKey::Descending(Reverse(value.parse().ok()?)))
+            }
+            RoomSort::Title => Some(SortKey::Ascending(value.to_string())),
+        }
+    }
+
+    fn value(&self) -> String {
+        match self {
+            SortKey::Descending(Reverse(value)) => value.to_string(),
+            SortKey::Ascending(value) => value.clone(),
+        }
+    }
+}
+
+//Case insensitive, on the title or id
+pub fn matches(room: &Room, search: &str) -> bool {
+    let search = search.to_lowercase();
+    room.title.to_lowercase().contains(&search) || room.id.to_lowercase().contains(&search)
+}
+
+//Returns the rooms after the cursor and the cursor of the next page, if there is one
+pub fn page(
+    rooms: Vec<RoomOverview>,
+    sort: RoomSort,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<(Vec<RoomOverview>, Option<String>)> {
+    if limit == 0 || limit > PAGE_MAX {
+        return Err(Error::Validation(vec![format!(
+            "Limit has to be between 1 and {}.",
+            PAGE_MAX
+        )]));
+    }
+    //Room ids can't contain a colon, titles can
+    let after = after
+        .map(|cursor| {
+            cursor
+                .rsplit_once(':')
+                .and_then(|(value, room_id)| {
+                    Some((SortKey::parse(sort, value)?, room_id.to_string()))
+                })
+                .ok_or_else(|| Error::Validation(vec!["That page doesn't exist.".to_string()]))
+        })
+        .transpose()?;
+
+    let mut rooms: Vec<_> = rooms
+        .into_iter()
+        .map(|overview| {
+            (
+                (SortKey::of(sort, &overview), overview.room.id.clone()),
+                overview,
+            )
+        })
+        .filter(|(key, _)| after.as_ref().is_none_or(|after| key > after))
+        .collect();
+    rooms.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let next = if rooms.len() > limit {
+        let (key, room_id) = &rooms[limit - 1].0;
+        Some(format!("{}:{}", key.value(), room_id))
+    } else {
+        None
+    };
+    rooms.truncate(limit);
+
+    Ok((
+        rooms.into_iter().map(|(_, overview)| overview).collect(),
+        next,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::playing::Playing;
+
+    fn overview(id: &str, title: &str, listeners: usize, start_time: Option<u128>) -> RoomOverview {
+        RoomOverview {
+            room: Room {
+                id: id.to_string(),
+                title: title.to_string(),
+                ..Default::default()
+            },
+            listeners,
+            playing: start_time.map(|start_time| Playing {
+                user_id: None,
+                track_id: "track".to_string(),
+                start_time,
+                length: 1000,
+                autoplay: false,
+            }),
+            archived: false,
+            last_active: None,
+        }
+    }
+
+    fn rooms() -> Vec<RoomOverview> {
+        vec![
+            overview("quiet", "Quiet: Room", 1, Some(300)),
+            overview("busy", "busy room", 5, Some(100)),
+            overview("empty", "Empty Room", 0, None),
+            overview("also-quiet", "Also Quiet", 1, Some(200)),
+        ]
+    }
+
+    fn ids(rooms: &[RoomOverview]) -> Vec<&str> {
+        rooms.iter().map(|overview| &overview.room.id[..]).collect()
+    }
+
+    #[test]
+    fn test_sorts() {
+        let (sorted, _) = page(rooms(), RoomSort::Listeners, None, 10).unwrap();
+        assert_eq!(ids(&sorted), vec!["busy", "also-quiet", "quiet", "empty"]);
+
+        let (sorted, _) = page(rooms(), RoomSort::Activity, None, 10).unwrap();
+        assert_eq!(ids(&sorted), vec!["quiet", "also-quiet", "busy", "empty"]);
+
+        let (sorted, _) = page(rooms(), RoomSort::Title, None, 10).unwrap();
+        assert_eq!(ids(&sorted), vec!["also-quiet", "busy", "empty", "quiet"]);
+    }
+
+    #[test]
+    fn test_pages_follow_on() {
+        for sort in [RoomSort::Listeners, RoomSort::Activity, RoomSort::Title] {
+            let (all, next) = page(rooms(), sort, None, 10).unwrap();
+            assert_eq!(next, None);
+
+            let mut paged = Vec::new();
+            let mut after = None;
+            loop {
+                let (rooms, next) = page(rooms(), sort, after.as_deref(), 1).unwrap();
+                paged.extend(rooms);
+                match next {
+                    Some(next) => after = Some(next),
+                    None => break,
+                }
+            }
+            assert_eq!(ids(&paged), ids(&all));
+        }
+    }
+
+    #[test]
+    fn test_bad_pages_are_rejected() {
+        assert!(page(rooms(), RoomSort::Listeners, None, 0).is_err());
+        assert!(page(rooms(), RoomSort::Listeners, None, PAGE_MAX + 1).is_err());
+        assert!(page(rooms(), RoomSort::Listeners, Some("busy"), 10).is_err());
+        assert!(page(rooms(), RoomSort::Listeners, Some("many:busy"), 10).is_err());
+    }
+
+    #[test]
+    fn test_search_ignores_case() {
+        let room = &rooms()[1].room;
+        assert!(matches(room, "BUSY"));
+        assert!(matches(room, "room"));
+        assert!(!matches(room, "quiet"));
+    }
+}
@@ -1,55 +1,42 @@
+use crate::config::Config;
 use crate::cookie;
 use crate::db;
 use crate::db::Db;
+use crate::directory;
+use crate::error::Error;
+use crate::health::{self, Heartbeat};
+use crate::invite;
+use crate::metrics::METRICS;
+use crate::queue;
+use crate::shutdown::Shutdown;
 use crate::socket;
 use crate::spotify;
 use crate::spotify::Spotify;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::convert::Infallible;
-use warp::{ws::Ws, Reply};
+use warp::{ws::Ws, Rejection, Reply};
 
-pub async fn get_test_endpoint(user_id: String, db: Db) -> Result<impl Reply, Infallible> {
+pub async fn get_test_endpoint(user_id: String, db: Db) -> Result<impl Reply, Rejection> {
     //Used only for testing
-    let mut db = db.lock().await;
-    let key = db.get_auth(user_id.clone()).await;
+    let key = db.get_auth(user_id.clone()).await?;
     Ok(format!("Hello, {} your token is {:?}!", user_id, key))
 }
 
-pub async fn get_token(
-    user_id: String,
-    spotify: Spotify,
-    db: Db,
-) -> Result<impl Reply, Infallible> {
+pub async fn get_token(user_id: String, spotify: Spotify, db: Db) -> Result<impl Reply, Rejection> {
     //Get users token from database
-    let mut inner_db = db.lock().await;
-    let token = inner_db.get_auth(user_id.clone()).await.unwrap();
-    std::mem::drop(inner_db);
+    let token = db.require_auth(user_id.clone()).await?;
 
     //Do a spotify request to see if token is still valid and refresh it if not
-    let spotify = spotify.lock().await;
-    spotify.request_me(token).await;
-    std::mem::drop(spotify);
+    spotify.request_me(token).await?;
 
-    let mut inner_db = db.lock().await;
-    let token = inner_db.get_auth(user_id.clone()).await.unwrap();
-    Ok(format!("{}", token.access_token))
+    let token = db.require_auth(user_id.clone()).await?;
+    Ok(token.access_token)
 }
 
-pub async fn get_login() -> Result<impl Reply, Infallible> {
-    let return_url = std::env::var("SPOTIFY_RETURN_URL").unwrap();
-    let client_id = std::env::var("SPOTIFY_CLIENT_ID").unwrap();
-
+pub async fn get_login(spotify: Spotify) -> Result<impl Reply, Infallible> {
     //Redirect user to a spotify authorization page
-    let spotify_uri = warp::http::Uri::builder()
-      .scheme("https")
-      .authority("accounts.spotify.com")
-      .path_and_query(format!("/authorize?response_type=code&client_id={}&redirect_uri={}&state=not-used&scope={}&show_dialog=true",
-          client_id,
-          return_url,
-          "user-modify-playback-state+streaming"
-      ))
-      .build()
-      .unwrap();
+    let spotify_uri: warp::http::Uri = spotify.authorize_url().parse().unwrap();
 
     Ok(warp::redirect::see_other(spotify_uri))
 }
@@ -58,42 +45,73 @@ pub async fn get_authorize(
     query: HashMap<String, String>,
     spotify: Spotify,
     db: Db,
-) -> Result<impl warp::Reply, Infallible> {
-    let return_url = std::env::var("SPOTIFY_RETURN_URL").unwrap();
-    let code = query.get("code").unwrap();
+    cookie_key: cookie::CookieKey,
+) -> Result<impl warp::Reply, Rejection> {
+    let code = query
+        .get("code")
+        .ok_or_else(|| Error::Validation(vec!["Missing code parameter".to_string()]))?;
 
     //Acquire users tokens from spotify
-    let spotify = spotify.lock().await;
-    let token = spotify.request_auth_new(code.clone(), return_url).await;
+    let token = spotify.request_auth_new(code.clone()).await?;
     let access_token = token.access_token;
-    let refresh_token = token.refresh_token.unwrap();
+    let refresh_token = token
+        .refresh_token
+        .ok_or_else(|| Error::Auth("Spotify didn't return a refresh token".to_string()))?;
     let user = spotify
         .request_me(db::auth::Auth {
             user_id: None,
             access_token: access_token.clone(),
             refresh_token: refresh_token.clone(),
         })
-        .await;
-    std::mem::drop(spotify);
+        .await?;
 
     //Save the users tokens to the database
-    let mut db = db.lock().await;
     db.set_auth(
         user.uri.clone(),
         access_token.clone(),
         refresh_token.clone(),
     )
-    .await;
-    std::mem::drop(db);
+    .await?;
 
     //Set the cookie and redirect user to /
-    let cookie = cookie::gen_user(user.uri);
+    let cookie = cookie::gen_user(&cookie_key, user.uri);
     let redirect = warp::redirect::see_other(warp::http::Uri::from_static("/"));
     let reply = warp::reply::with_header(redirect, "Set-Cookie", format!("userid={}", cookie));
 
     Ok(reply)
 }
 
+pub async fn get_healthz() -> Result<impl Reply, Infallible> {
+    //Answering at all means the process is up
+    Ok(warp::reply::json(&serde_json::json!({ "status": "ok" })))
+}
+
+pub async fn get_readyz(
+    db: Db,
+    heartbeat: Heartbeat,
+    config: Config,
+) -> Result<impl Reply, Infallible> {
+    let readiness = health::check(&db, &heartbeat, &config.timings).await;
+    let status = if readiness.ready {
+        warp::http::StatusCode::OK
+    } else {
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&readiness),
+        status,
+    ))
+}
+
+pub async fn get_metrics() -> Result<impl Reply, Infallible> {
+    Ok(warp::reply::with_header(
+        METRICS.render(),
+        "content-type",
+        prometheus::TEXT_FORMAT,
+    ))
+}
+
 pub async fn get_logout() -> Result<impl Reply, Infallible> {
     //Set an expiration for the cookie and redirect to /
     let redirect = warp::redirect::see_other(warp::http::Uri::from_static("/"));
@@ -110,77 +128,363 @@ pub async fn create_room(
     user_id: String,
     db: Db,
     body: HashMap<String, String>,
-) -> Result<impl warp::Reply, Infallible> {
+) -> Result<impl warp::Reply, Rejection> {
     //Extract room information fromm parameters
-    let id = body.get("id").unwrap_or(&"".to_string()).clone();
-    let title = body.get("title").unwrap_or(&"".to_string()).clone();
-
-    let room = db::room::Room {
-        id,
-        title,
+    let mut room = db::room::Room {
+        id: body.get("id").cloned().unwrap_or_default(),
         owner: user_id.clone(),
+        ..Default::default()
     };
+    apply_room_settings(&mut room, &body)?;
+
+    //Try to insert the room into the database, failing validation is rejected with errors
+    db.create_room(room).await?;
+
+    //If successfuly reply with no errors
+    let reply: Vec<String> = Vec::new();
+    let json = warp::reply::json(&reply);
+    Ok(warp::reply::with_status(
+        json,
+        warp::http::StatusCode::CREATED,
+    ))
+}
 
-    //Try to insert the room into the database
-    let mut db = db.lock().await;
-    match db.create_room(room).await {
-        Ok(_) => {
-            //If successfuly reply with no errors
-            let reply: Vec<String> = Vec::new();
-            let json = warp::reply::json(&reply);
-            Ok(warp::reply::with_status(
-                json,
-                warp::http::StatusCode::CREATED,
-            ))
-        }
-        Err(errors) => {
-            //If failed to insert room (i.e. failing validation) reply with errors
-            let json = warp::reply::json(&errors);
-            Ok(warp::reply::with_status(
-                json,
-                warp::http::StatusCode::BAD_REQUEST,
-            ))
-        }
+pub async fn update_room(
+    room_id: String,
+    user_id: String,
+    db: Db,
+    body: HashMap<String, String>,
+) -> Result<impl warp::Reply, Rejection> {
+    let mut room = owned_room(&db, room_id, &user_id).await?;
+    apply_room_settings(&mut room, &body)?;
+
+    if !db.update_room(room).await? {
+        return Err(room_not_found().into());
     }
+
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+pub async fn delete_room(
+    room_id: String,
+    user_id: String,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    let room = owned_room(&db, room_id, &user_id).await?;
+
+    //Sent first so everyone in the room gets disconnected before it's gone
+    db.add_message(room.id.clone(), db::message::Message::room_deleted())
+        .await?;
+    db.delete_room(room.id).await?;
+
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+async fn owned_room(db: &Db, room_id: String, user_id: &str) -> Result<db::room::Room, Error> {
+    let room = db.get_room(room_id).await?.ok_or_else(room_not_found)?;
+    if room.owner != user_id {
+        return Err(Error::Forbidden(
+            "Only the owner can change this room.".to_string(),
+        ));
+    }
+
+    Ok(room)
 }
 
-pub async fn list_rooms(_user_id: String, db: Db) -> Result<warp::reply::Response, Infallible> {
+fn room_not_found() -> Error {
+    Error::NotFound("Couldn't find room with this id.".to_string())
+}
+
+//Title and settings from a form, anything left out stays as it is
+fn apply_room_settings(
+    room: &mut db::room::Room,
+    body: &HashMap<String, String>,
+) -> Result<(), Error> {
+    if let Some(title) = body.get("title") {
+        room.title = title.clone();
+    }
+    if let Some(policy) = body.get("policy") {
+        room.policy = policy
+            .parse()
+            .map_err(|err: &str| Error::Validation(vec![format!("{}.", err)]))?;
+    }
+    if let Some(mode) = body.get("mode") {
+        room.mode = mode
+            .parse()
+            .map_err(|err: &str| Error::Validation(vec![format!("{}.", err)]))?;
+    }
+    if let Some(visibility) = body.get("visibility") {
+        room.visibility = visibility
+            .parse()
+            .map_err(|err: &str| Error::Validation(vec![format!("{}.", err)]))?;
+    }
+
+    let rules = &mut room.rules;
+    if let Some(no_explicit) = body.get("no_explicit") {
+        rules.no_explicit = no_explicit == "true";
+    }
+    if body.contains_key("max_duration_ms") {
+        rules.max_duration_ms = parse_field(body, "max_duration_ms", "Maximum track length")?;
+    }
+    if body.contains_key("min_year") {
+        rules.min_year = parse_field(body, "min_year", "Earliest release year")?;
+    }
+    if body.contains_key("max_year") {
+        rules.max_year = parse_field(body, "max_year", "Latest release year")?;
+    }
+    if let Some(market) = body.get("market") {
+        rules.market = Some(market.clone()).filter(|market| !market.is_empty());
+    }
+
+    Ok(())
+}
+
+//Optional number in a form, left empty means not set
+fn parse_field<T: std::str::FromStr>(
+    body: &HashMap<String, String>,
+    name: &str,
+    label: &str,
+) -> Result<Option<T>, Error> {
+    match body.get(name).filter(|value| !value.is_empty()) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::Validation(vec![format!("{} has to be a number.", label)])),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomsQuery {
+    q: Option<String>,
+    #[serde(default)]
+    sort: directory::RoomSort,
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct RoomPage {
+    rooms: Vec<directory::RoomListing>,
+    //Pass as after to get the next page, missing on the last one
+    next: Option<String>,
+}
+
+pub async fn list_rooms(
+    user_id: String,
+    db: Db,
+    query: RoomsQuery,
+) -> Result<warp::reply::Response, Rejection> {
     //Acquire room information from the database
-    let mut db = db.lock().await;
-    let rooms = db.list_rooms().await;
+    let rooms = db
+        .list_room_overviews()
+        .await?
+        .into_iter()
+        //Archived rooms only for their owner, joining brings them back
+        .filter(|overview| !overview.archived || overview.room.owner == user_id)
+        .filter(|overview| {
+            query
+                .q
+                .as_ref()
+                .is_none_or(|q| directory::matches(&overview.room, q))
+        })
+        .collect();
+    //Unlisted and private rooms only for those who've been in them
+    let rooms = invite::listed(&db, rooms, user_id).await?;
+
+    let (rooms, next) = directory::page(
+        rooms,
+        query.sort,
+        query.after.as_deref(),
+        query.limit.unwrap_or(directory::PAGE_DEFAULT),
+    )?;
+    let rooms = rooms
+        .into_iter()
+        .map(directory::RoomListing::from)
+        .collect();
+
+    Ok(warp::reply::json(&RoomPage { rooms, next }).into_response())
+}
 
-    Ok(warp::reply::json(&rooms).into_response())
+#[derive(Debug, Deserialize)]
+pub struct InviteQuery {
+    invite: Option<String>,
 }
 
 pub async fn get_room(
     room_id: String,
-    _user_id: String,
+    user_id: String,
     db: Db,
-) -> Result<warp::reply::Response, Infallible> {
+    cookie_key: cookie::CookieKey,
+    query: InviteQuery,
+) -> Result<warp::reply::Response, Rejection> {
     //Acquire information about the room from database
-    let mut db = db.lock().await;
-    let room = db.get_room(room_id).await;
-
-    //Differnet replies based on if the room was found
-    match room {
-        Some(room) => Ok(warp::reply::json(&room).into_response()),
-        None => Ok(warp::reply::with_status(
-            "Couldn't find room with this id.",
-            warp::http::StatusCode::NOT_FOUND,
-        )
-        .into_response()),
+    let room = accessible_room(&db, &cookie_key, room_id, user_id, query.invite).await?;
+
+    Ok(warp::reply::json(&room).into_response())
+}
+
+//Anything about a room goes through here, so a private room's id alone gets nobody in
+async fn accessible_room(
+    db: &Db,
+    cookie_key: &cookie::CookieKey,
+    room_id: String,
+    user_id: String,
+    invite: Option<String>,
+) -> Result<db::room::Room, Error> {
+    let room = db.get_room(room_id).await?.ok_or_else(room_not_found)?;
+    invite::check_access(db, cookie_key, &room, user_id, invite).await?;
+    Ok(room)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewInvite {
+    max_uses: Option<u32>,
+    expires_in_secs: Option<u64>,
+}
+
+pub async fn create_invite(
+    room_id: String,
+    user_id: String,
+    db: Db,
+    cookie_key: cookie::CookieKey,
+    body: NewInvite,
+) -> Result<impl warp::Reply, Rejection> {
+    let room = owned_room(&db, room_id, &user_id).await?;
+    let invite = invite::create(
+        &db,
+        &cookie_key,
+        room.id,
+        body.max_uses.unwrap_or(invite::DEFAULT_USES),
+        body.expires_in_secs
+            .map_or(invite::DEFAULT_EXPIRY, std::time::Duration::from_secs),
+    )
+    .await?;
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&invite),
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+//Spotify looks up at most 50 tracks at once
+const HISTORY_PAGE_MAX: usize = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    before: Option<u64>,
+    limit: Option<usize>,
+    invite: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryPage {
+    tracks: Vec<HistoryTrack>,
+    //Pass as before to get the next page, missing on the last one
+    next: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryTrack {
+    id: u64,
+    track: spotify::util::ShortTrack,
+    user_id: Option<String>,
+    start_time: u64,
+    skipped: bool,
+}
+
+pub async fn list_room_history(
+    room_id: String,
+    user_id: String,
+    db: Db,
+    spotify: Spotify,
+    cookie_key: cookie::CookieKey,
+    query: HistoryQuery,
+) -> Result<warp::reply::Response, Rejection> {
+    let limit = query.limit.unwrap_or(20);
+    if limit == 0 || limit > HISTORY_PAGE_MAX {
+        return Err(Error::Validation(vec![format!(
+            "Limit has to be between 1 and {}.",
+            HISTORY_PAGE_MAX
+        )])
+        .into());
     }
+    accessible_room(
+        &db,
+        &cookie_key,
+        room_id.clone(),
+        user_id.clone(),
+        query.invite,
+    )
+    .await?;
+
+    let entries = db.list_history(room_id, query.before, limit).await?;
+    //A short page is the last one, a full one might be too but that's for the next request to find out
+    let next = entries
+        .last()
+        .filter(|_| entries.len() == limit)
+        .map(|entry| entry.id);
+
+    let tracks = if !entries.is_empty() {
+        //Get information on the played tracks from spotify
+        let token = db.require_auth(user_id).await?;
+        let track_ids = entries.iter().map(|entry| entry.track_id.clone()).collect();
+        let results = spotify.request_tracks(token, track_ids).await?;
+        //Relinked tracks come back under another uri, the one asked for is in linked_from
+        let tracks: HashMap<&str, &spotify::tracks::Track> = results
+            .tracks
+            .iter()
+            .map(|track| {
+                let uri = track
+                    .linked_from
+                    .as_ref()
+                    .map_or(&track.uri, |linked| &linked.uri);
+                (uri.as_str(), track)
+            })
+            .collect();
+
+        entries
+            .into_iter()
+            //Anything Spotify didn't send back is left out instead of getting another entry's track
+            .filter_map(|entry| {
+                let track = tracks.get(entry.track_id.as_str())?;
+                Some(HistoryTrack {
+                    id: entry.id,
+                    track: spotify::util::shorten_track(track),
+                    user_id: entry.user_id,
+                    start_time: entry.start_time as u64,
+                    skipped: entry.skipped,
+                })
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    Ok(warp::reply::json(&HistoryPage { tracks, next }).into_response())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn ws_chat(
     room_id: String,
     user_id: String,
     db: Db,
     spotify: Spotify,
+    config: Config,
+    shutdown: Shutdown,
+    cookie_key: cookie::CookieKey,
+    query: InviteQuery,
     ws: Ws,
-) -> Result<impl warp::Reply, Infallible> {
+) -> Result<impl warp::Reply, Rejection> {
+    //Rooms that don't exist are turned away once connected, so the client gets to see why
+    if let Some(room) = db.get_room(room_id.clone()).await? {
+        invite::check_access(&db, &cookie_key, &room, user_id.clone(), query.invite).await?;
+    }
+
     //Finish connecting the websocket
-    Ok(ws.on_upgrade(move |websocket| socket::connected(websocket, room_id, user_id, db, spotify)))
+    Ok(ws.on_upgrade(move |websocket| {
+        socket::connected(websocket, room_id, user_id, db, spotify, config, shutdown)
+    }))
 }
 
 pub async fn get_search(
@@ -188,50 +492,37 @@ pub async fn get_search(
     db: Db,
     spotify: Spotify,
     query: HashMap<String, String>,
-) -> Result<impl warp::Reply, Infallible> {
+) -> Result<impl warp::Reply, Rejection> {
     //Extract query from query parameters
-    let query = query.get("q");
-
-    match query {
-        Some(query) => {
-            //Get users token from database
-            let mut db = db.lock().await;
-            let token = db.get_auth(user_id).await.unwrap();
-            std::mem::drop(db);
-
-            //Perform a search on spotify
-            let spotify = spotify.lock().await;
-            let results = spotify
-                .request_search(
-                    token,
-                    query.clone(),
-                    "track".to_string(),
-                    None,
-                    Some(20),
-                    Some(0),
-                    None,
-                )
-                .await;
-            std::mem::drop(spotify);
-
-            //Only keep the useful information from search results
-            let response: Vec<spotify::util::ShortTrack> = results
-                .tracks
-                .items
-                .iter()
-                .map(|track| {
-                    spotify::util::shorten_track(track)
-                })
-                .collect();
+    let query = query
+        .get("q")
+        .ok_or_else(|| Error::Validation(vec!["Missing query parameter".to_string()]))?;
 
-            Ok(warp::reply::json(&response).into_response())
-        }
-        None => Ok(warp::reply::with_status(
-            "Missing query parameter",
-            warp::http::StatusCode::BAD_REQUEST,
+    //Get users token from database
+    let token = db.require_auth(user_id).await?;
+
+    //Perform a search on spotify
+    let results = spotify
+        .request_search(
+            token,
+            query.clone(),
+            "track".to_string(),
+            None,
+            Some(20),
+            Some(0),
+            None,
         )
-        .into_response()),
-    }
+        .await?;
+
+    //Only keep the useful information from search results
+    let response: Vec<spotify::util::ShortTrack> = results
+        .tracks
+        .items
+        .iter()
+        .map(spotify::util::shorten_track)
+        .collect();
+
+    Ok(warp::reply::json(&response).into_response())
 }
 
 pub async fn list_user_queue(
@@ -239,25 +530,31 @@ pub async fn list_user_queue(
     user_id: String,
     db: Db,
     spotify: Spotify,
-) -> Result<warp::reply::Response, Infallible> {
+    cookie_key: cookie::CookieKey,
+    query: InviteQuery,
+) -> Result<warp::reply::Response, Rejection> {
+    accessible_room(
+        &db,
+        &cookie_key,
+        room_id.clone(),
+        user_id.clone(),
+        query.invite,
+    )
+    .await?;
+
     //Acquire list of ids from database
-    let mut db = db.lock().await;
-    let token = db.get_auth(user_id.clone()).await.unwrap();
-    let track_ids = db.list_user_queue(room_id, user_id).await;
-    std::mem::drop(db);
+    let token = db.require_auth(user_id.clone()).await?;
+    let track_ids = db.list_user_queue(room_id, user_id).await?;
 
     let response: Vec<spotify::util::ShortTrack> = if !track_ids.is_empty() {
         //Get information on these ids from spotify
-        let spotify = spotify.lock().await;
-        let results = spotify.request_tracks(token, track_ids).await;
+        let results = spotify.request_tracks(token, track_ids).await?;
 
         //Only return the useful part of the response
         let response: Vec<spotify::util::ShortTrack> = results
             .tracks
             .iter()
-            .map(|track| {
-                spotify::util::shorten_track(track)
-            })
+            .map(spotify::util::shorten_track)
             .collect();
 
         response
@@ -267,4 +564,49 @@ pub async fn list_user_queue(
     };
 
     Ok(warp::reply::json(&response).into_response())
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueuedTrack {
+    track_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveQueuedTrack {
+    track_id: String,
+    to: usize,
+}
+
+pub async fn remove_user_queue_track(
+    room_id: String,
+    index: usize,
+    user_id: String,
+    db: Db,
+    query: QueuedTrack,
+) -> Result<impl warp::Reply, Rejection> {
+    queue::remove_track(&db, room_id, user_id, index, query.track_id).await?;
+
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+pub async fn move_user_queue_track(
+    room_id: String,
+    index: usize,
+    user_id: String,
+    db: Db,
+    body: MoveQueuedTrack,
+) -> Result<impl warp::Reply, Rejection> {
+    queue::move_track(&db, room_id, user_id, index, body.to, body.track_id).await?;
+
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
+
+pub async fn clear_user_queue(
+    room_id: String,
+    user_id: String,
+    db: Db,
+) -> Result<impl warp::Reply, Rejection> {
+    queue::clear(&db, room_id, user_id).await?;
+
+    Ok(warp::http::StatusCode::NO_CONTENT)
+}
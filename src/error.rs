@@ -0,0 +1,207 @@
+use serde::Serialize;
+use std::convert::Infallible;
+use std::time::Duration;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Database error: {0}")]
+    //Converted in the db layer, which counts them
+    Redis(#[source] redis::RedisError),
+    #[error("Spotify request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Spotify responded with {status}: {message}")]
+    Spotify { status: u16, message: String },
+    #[error("Spotify is rate limited for another {}s", .retry_after.as_secs())]
+    RateLimited { retry_after: Duration },
+    #[error("Failed to decode {0}")]
+    Decode(String),
+    #[error("{0}")]
+    Auth(String),
+    //Logged in, but not allowed to do this
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("Validation failed")]
+    Validation(Vec<String>),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("Invalid configuration: {}", .0.join("; "))]
+    Config(Vec<String>),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::Redis(_) | Error::Decode(_) | Error::Config(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Error::Http(_) | Error::Spotify { .. } => StatusCode::BAD_GATEWAY,
+            Error::RateLimited { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Auth(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::Validation(_) => StatusCode::BAD_REQUEST,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+        }
+    }
+
+    //Worth trying again later, as opposed to errors that will keep failing the same way
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Http(_) | Error::RateLimited { .. } => true,
+            Error::Spotify { status, .. } => *status >= 500,
+            _ => false,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::RateLimited { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Error::Redis(_) => "database",
+            Error::Http(_) | Error::Spotify { .. } => "spotify",
+            Error::RateLimited { .. } => "rate_limited",
+            Error::Decode(_) => "decode",
+            Error::Auth(_) => "auth",
+            Error::Forbidden(_) => "forbidden",
+            Error::Validation(_) => "validation",
+            Error::NotFound(_) => "not_found",
+            Error::Config(_) => "config",
+        }
+    }
+
+    //What the client gets to see
+    pub fn messages(&self) -> Vec<String> {
+        match self {
+            Error::Validation(errors) => errors.clone(),
+            //Don't leak internals of the database or Spotify to the client
+            Error::Redis(_) | Error::Decode(_) | Error::Config(_) => {
+                vec!["Internal server error.".to_string()]
+            }
+            Error::Http(_) | Error::Spotify { .. } => {
+                vec!["Couldn't reach Spotify, try again later.".to_string()]
+            }
+            Error::RateLimited { retry_after } => vec![format!(
+                "Spotify is busy, try again in {} seconds.",
+                whole_seconds(*retry_after)
+            )],
+            _ => vec![self.to_string()],
+        }
+    }
+}
+
+impl warp::reject::Reject for Error {}
+
+//Rounded up, telling clients to come back before Spotify is ready is pointless
+fn whole_seconds(duration: Duration) -> u64 {
+    let seconds = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    seconds.max(1)
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    status: u16,
+    error: &'static str,
+    messages: Vec<String>,
+}
+
+fn reply(status: StatusCode, error: &'static str, message: &str) -> warp::reply::Response {
+    let body = ErrorBody {
+        status: status.as_u16(),
+        error,
+        messages: vec![message.to_string()],
+    };
+
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+pub async fn recover(rejection: Rejection) -> std::result::Result<impl Reply, Infallible> {
+    let response = if let Some(error) = rejection.find::<Error>() {
+        if error.status().is_server_error() {
+            tracing::error!("{}", error);
+        }
+
+        let body = ErrorBody {
+            status: error.status().as_u16(),
+            error: error.kind(),
+            messages: error.messages(),
+        };
+        let response = warp::reply::with_status(warp::reply::json(&body), error.status());
+        match error.retry_after() {
+            Some(retry_after) => warp::reply::with_header(
+                response,
+                "retry-after",
+                whole_seconds(retry_after).to_string(),
+            )
+            .into_response(),
+            None => response.into_response(),
+        }
+    } else if rejection.is_not_found() {
+        reply(StatusCode::NOT_FOUND, "not_found", "Not found.")
+    } else if rejection.find::<warp::reject::MissingCookie>().is_some() {
+        reply(StatusCode::UNAUTHORIZED, "auth", "Not logged in.")
+    } else if let Some(error) = rejection.find::<warp::body::BodyDeserializeError>() {
+        reply(StatusCode::BAD_REQUEST, "validation", &error.to_string())
+    } else if let Some(error) = rejection.find::<warp::reject::InvalidQuery>() {
+        reply(StatusCode::BAD_REQUEST, "validation", &error.to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        reply(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            "Method not allowed.",
+        )
+    } else {
+        tracing::error!("Unhandled rejection {:?}", rejection);
+        reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "Internal server error.",
+        )
+    };
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recover_validation_error() {
+        let rejection: Rejection =
+            Error::Validation(vec!["Room id must not be empty.".to_string()]).into();
+        let response = recover(rejection).await.unwrap().into_response();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], 400);
+        assert_eq!(body["error"], "validation");
+        assert_eq!(body["messages"][0], "Room id must not be empty.");
+    }
+
+    #[tokio::test]
+    async fn test_recover_hides_internal_errors() {
+        let rejection: Rejection = Error::Decode("auth hash".to_string()).into();
+        let response = recover(rejection).await.unwrap().into_response();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "decode");
+        assert_eq!(body["messages"][0], "Internal server error.");
+    }
+}
@@ -0,0 +1,122 @@
+use crate::config::Timings;
+use crate::db::Db;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use warp::Filter;
+
+//Database checks that take longer than this count as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+//Bumped by the room listener every time it goes around its loop
+#[derive(Clone, Default)]
+pub struct Heartbeat {
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.last.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn since_last(&self) -> Option<Duration> {
+        self.last.lock().unwrap().map(|last| last.elapsed())
+    }
+}
+
+pub fn with(
+    heartbeat: Heartbeat,
+) -> impl Filter<Extract = (Heartbeat,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || heartbeat.clone())
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub redis: Check,
+    pub keyspace_notifications: Check,
+    pub listener: Check,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            detail: None,
+        }
+    }
+
+    fn failed(detail: String) -> Self {
+        Self {
+            ok: false,
+            detail: Some(detail),
+        }
+    }
+}
+
+pub async fn check(db: &Db, heartbeat: &Heartbeat, timings: &Timings) -> Readiness {
+    let redis = match tokio::time::timeout(CHECK_TIMEOUT, db.ping()).await {
+        Ok(Ok(())) => Check::ok(),
+        Ok(Err(err)) => Check::failed(err.to_string()),
+        Err(_) => Check::failed("Ping timed out".to_string()),
+    };
+
+    let keyspace_notifications =
+        match tokio::time::timeout(CHECK_TIMEOUT, db.keyspace_events_enabled()).await {
+            Ok(Ok(true)) => Check::ok(),
+            Ok(Ok(false)) => Check::failed("notify-keyspace-events is missing flags".to_string()),
+            Ok(Err(err)) => Check::failed(err.to_string()),
+            Err(_) => Check::failed("Config lookup timed out".to_string()),
+        };
+
+    //The listener blocks for up to room_claim_timeout waiting for a room, so allow for one full wait and then some
+    let listener = match heartbeat.since_last() {
+        Some(since) if since <= timings.room_claim_timeout() * 2 => Check::ok(),
+        Some(since) => Check::failed(format!("Last heartbeat was {}s ago", since.as_secs())),
+        None => Check::failed("Listener hasn't started".to_string()),
+    };
+
+    Readiness {
+        ready: redis.ok && keyspace_notifications.ok && listener.ok,
+        redis,
+        keyspace_notifications,
+        listener,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::memory::MemoryDb;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ready_while_listener_beats() {
+        let timings = Timings::default();
+        let db: Db = Arc::new(MemoryDb::init(timings.clone()));
+        let heartbeat = Heartbeat::default();
+
+        let readiness = check(&db, &heartbeat, &timings).await;
+        assert!(!readiness.ready);
+        assert!(readiness.redis.ok);
+        assert!(!readiness.listener.ok);
+
+        heartbeat.beat();
+        assert!(check(&db, &heartbeat, &timings).await.ready);
+
+        tokio::time::advance(timings.room_claim_timeout() * 3).await;
+        let readiness = check(&db, &heartbeat, &timings).await;
+        assert!(!readiness.ready);
+        assert_eq!(
+            readiness.listener.detail.as_deref(),
+            Some("Last heartbeat was 15s ago")
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use sodiumoxide::crypto::{generichash, secretbox};
+
+use crate::cookie::CookieKey;
+use crate::db::room::{Room, RoomOverview, Visibility};
+use crate::db::Db;
+use crate::error::{Error, Result};
+
+//Private rooms are only open to their owner and members, anyone else needs an invite from the owner.
+//Invites are sealed with a key derived from the cookie key so they can't be forged, the uses left are counted in the database.
+
+pub const DEFAULT_USES: u32 = 10;
+pub const MAX_USES: u32 = 1000;
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+pub const MAX_EXPIRY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, Serialize)]
+pub struct Invite {
+    pub token: String,
+    //In ms since the epoch
+    pub expires: u128,
+    pub max_uses: u32,
+}
+
+pub async fn create(
+    db: &Db,
+    key: &CookieKey,
+    room_id: String,
+    max_uses: u32,
+    expiry: Duration,
+) -> Result<Invite> {
+    let mut errors = Vec::new();
+    if max_uses == 0 || max_uses > MAX_USES {
+        errors.push(format!(
+            "Invites can be used between 1 and {} times.",
+            MAX_USES
+        ));
+    }
+    if expiry.is_zero() || expiry > MAX_EXPIRY {
+        errors.push(format!(
+            "Invites can last at most {} days.",
+            MAX_EXPIRY.as_secs() / (24 * 60 * 60)
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(Error::Validation(errors));
+    }
+
+    let invite_id = base64::encode_config(
+        sodiumoxide::randombytes::randombytes(12),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let expires = now() + expiry.as_millis();
+    db.add_invite(room_id.clone(), invite_id.clone(), max_uses, expiry)
+        .await?;
+
+    Ok(Invite {
+        token: seal(key, &format!("{}:{}:{}", room_id, invite_id, expires)),
+        expires,
+        max_uses,
+    })
+}
+
+//Lets the user in, using up the invite if the room is private and they aren't a member yet
+pub async fn check_access(
+    db: &Db,
+    key: &CookieKey,
+    room: &Room,
+    user_id: String,
+    token: Option<String>,
+) -> Result<()> {
+    if is_listed(db, room, user_id.clone()).await? {
+        return Ok(());
+    }
+
+    if room.visibility == Visibility::Private {
+        let token = token.ok_or_else(|| {
+            Error::Forbidden("This room is private, you need an invite to join.".to_string())
+        })?;
+        redeem(db, key, room.id.clone(), token).await?;
+    }
+    //Unlisted rooms show up in the list of anyone who's been there
+    db.add_member(room.id.clone(), user_id).await
+}
+
+//Whether the room shows up in the user's room list
+pub async fn is_listed(db: &Db, room: &Room, user_id: String) -> Result<bool> {
+    Ok(is_open(room, &user_id) || db.is_member(room.id.clone(), user_id).await?)
+}
+
+//The rooms that show up in the user's room list, membership is looked up for all of them at once
+pub async fn listed(
+    db: &Db,
+    rooms: Vec<RoomOverview>,
+    user_id: String,
+) -> Result<Vec<RoomOverview>> {
+    let closed = rooms
+        .iter()
+        .filter(|overview| !is_open(&overview.room, &user_id))
+        .map(|overview| overview.room.id.clone())
+        .collect();
+    let members = db.member_rooms(closed, user_id.clone()).await?;
+
+    Ok(rooms
+        .into_iter()
+        .filter(|overview| is_open(&overview.room, &user_id) || members.contains(&overview.room.id))
+        .collect())
+}
+
+fn is_open(room: &Room, user_id: &str) -> bool {
+    room.visibility == Visibility::Public || room.owner == user_id
+}
+
+async fn redeem(db: &Db, key: &CookieKey, room_id: String, token: String) -> Result<()> {
+    let invalid = || Error::Forbidden("This invite isn't valid.".to_string());
+
+    let data = open(key, &token).ok_or_else(invalid)?;
+    let mut parts = data.splitn(3, ':');
+    let (Some(invite_room_id), Some(invite_id), Some(expires)) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid());
+    };
+    if invite_room_id != room_id {
+        return Err(invalid());
+    }
+    let expires: u128 = expires.parse().map_err(|_| invalid())?;
+
+    if expires <= now() || !db.use_invite(room_id, invite_id.to_string()).await? {
+        return Err(Error::Forbidden(
+            "This invite has expired or was used up.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+//Sealing with the cookie key itself would let an invite pass as a user cookie for the user "room:invite:expires"
+fn invite_key(key: &CookieKey) -> secretbox::Key {
+    let mut state = generichash::State::new(Some(secretbox::KEYBYTES), Some(&key.0))
+        .expect("cookie keys are a valid generichash key length");
+    state.update(b"invite").unwrap();
+    let digest = state.finalize().unwrap();
+    secretbox::Key::from_slice(digest.as_ref()).unwrap()
+}
+
+//Like the user cookie, but safe to put in a link
+fn seal(key: &CookieKey, data: &str) -> String {
+    let nonce = secretbox::gen_nonce();
+    let sealed = secretbox::seal(data.as_bytes(), &nonce, &invite_key(key));
+
+    format!(
+        "{}.{}",
+        base64::encode_config(nonce, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(sealed, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+fn open(key: &CookieKey, token: &str) -> Option<String> {
+    let (nonce, sealed) = token.split_once('.')?;
+    let nonce = base64::decode_config(nonce, base64::URL_SAFE_NO_PAD).ok()?;
+    let sealed = base64::decode_config(sealed, base64::URL_SAFE_NO_PAD).ok()?;
+    let nonce = secretbox::Nonce::from_slice(&nonce)?;
+
+    let data = secretbox::open(&sealed, &nonce, &invite_key(key)).ok()?;
+    String::from_utf8(data).ok()
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key() -> CookieKey {
+        secretbox::Key::from_slice(&[0; secretbox::KEYBYTES]).unwrap()
+    }
+
+    #[test]
+    fn test_sealed_token_opens() {
+        let token = seal(&key(), "room:invite:1");
+
+        assert!(!token.contains(['+', '/', '=', ':']));
+        assert_eq!(open(&key(), &token), Some("room:invite:1".to_string()));
+    }
+
+    #[test]
+    fn test_tampered_token_is_rejected() {
+        let token = seal(&key(), "room:invite:1");
+        let (nonce, sealed) = token.split_once('.').unwrap();
+        let mut sealed = base64::decode_config(sealed, base64::URL_SAFE_NO_PAD).unwrap();
+        sealed[0] ^= 1;
+        let tampered = format!(
+            "{}.{}",
+            nonce,
+            base64::encode_config(sealed, base64::URL_SAFE_NO_PAD)
+        );
+
+        assert_eq!(open(&key(), &tampered), None);
+        assert_eq!(open(&key(), "garbage"), None);
+    }
+
+    #[test]
+    fn test_token_is_not_sealed_with_the_cookie_key() {
+        let token = seal(&key(), "room:invite:1");
+        let (nonce, sealed) = token.split_once('.').unwrap();
+        let nonce = base64::decode_config(nonce, base64::URL_SAFE_NO_PAD).unwrap();
+        let sealed = base64::decode_config(sealed, base64::URL_SAFE_NO_PAD).unwrap();
+        let nonce = secretbox::Nonce::from_slice(&nonce).unwrap();
+
+        assert!(secretbox::open(&sealed, &nonce, &key()).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{IdleRoomAction, RoomConfig, Timings};
+use crate::db::message::Message;
+use crate::db::room::{Room, RoomOverview};
+use crate::db::Db;
+use crate::error::Result;
+use crate::shutdown::Shutdown;
+
+//Cleans up after rooms nobody uses anymore. A room counts as active while someone is in it or a track is playing,
+//rooms idle for longer than idle_room_secs are archived or deleted and their owner hears about it on their next visit.
+//Also drops the queues of users who are long gone from their room, which nothing else would get rid of.
+//Every instance runs one, a lease in the database makes sure only one of them sweeps per interval.
+
+pub fn start(db: Db, rooms: RoomConfig, timings: Timings, mut shutdown: Shutdown) {
+    let holder = base64::encode_config(
+        sodiumoxide::randombytes::randombytes(12),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(timings.janitor_interval()) => (),
+                _ = shutdown.recv() => break,
+            }
+
+            match db
+                .lease_janitor(holder.clone(), timings.janitor_interval())
+                .await
+            {
+                Ok(true) => (),
+                Ok(false) => {
+                    tracing::debug!("Another instance is sweeping idle rooms");
+                    continue;
+                }
+                Err(err) => {
+                    tracing::error!("Janitor failed to take the lease: {}", err);
+                    continue;
+                }
+            }
+            match sweep(&db, &rooms, now()).await {
+                Ok(swept) => tracing::info!(
+                    "Janitor swept {} idle rooms and {} orphaned queues",
+                    swept.rooms,
+                    swept.queues
+                ),
+                Err(err) => tracing::error!("Janitor failed to sweep: {}", err),
+            }
+        }
+    });
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Swept {
+    pub rooms: usize,
+    pub queues: usize,
+}
+
+//One pass over every room, now is in ms since the epoch
+pub async fn sweep(db: &Db, rooms: &RoomConfig, now: u128) -> Result<Swept> {
+    let mut swept = Swept::default();
+    let idle = rooms.idle_room();
+
+    for overview in db.list_room_overviews().await? {
+        let room_id = overview.room.id.clone();
+        if is_active(&overview) {
+            db.touch_room(room_id, now).await?;
+            continue;
+        }
+        //Nothing more to clear out of archived rooms
+        if idle.is_zero()
+            || (overview.archived && rooms.idle_room_action == IdleRoomAction::Archive)
+        {
+            continue;
+        }
+
+        match overview.last_active {
+            //Rooms from before activity was tracked start counting now
+            None => db.touch_room(room_id, now).await?,
+            Some(last_active) if now.saturating_sub(last_active) >= idle.as_millis() => {
+                collect(db, overview.room, rooms.idle_room_action, idle).await?;
+                swept.rooms += 1;
+            }
+            Some(_) => (),
+        }
+    }
+
+    swept.queues = clean_user_queues(db).await?;
+    Ok(swept)
+}
+
+//Called when someone joins a room, returns what they should be told
+pub async fn visited(db: &Db, room_id: String, user_id: String) -> Result<Vec<String>> {
+    db.touch_room(room_id.clone(), now()).await?;
+    if db.unarchive_room(room_id.clone()).await? {
+        tracing::info!("Brought back archived room {}", room_id);
+    }
+    db.take_notices(user_id).await
+}
+
+fn is_active(overview: &RoomOverview) -> bool {
+    overview.listeners > 0
+        || overview
+            .playing
+            .as_ref()
+            .is_some_and(|playing| playing.is_playing())
+}
+
+async fn collect(db: &Db, room: Room, action: IdleRoomAction, idle: Duration) -> Result<()> {
+    let notice = match action {
+        IdleRoomAction::Archive => {
+            db.archive_room(room.id.clone()).await?;
+            format!(
+                "Your room {} was archived after {} without listeners, its queues and chat were cleared. Join it to open it again.",
+                room.title,
+                describe(idle)
+            )
+        }
+        IdleRoomAction::Delete => {
+            //Same as the owner deleting it, anyone who just joined gets disconnected
+            db.add_message(room.id.clone(), Message::room_deleted())
+                .await?;
+            db.delete_room(room.id.clone()).await?;
+            format!(
+                "Your room {} was deleted after {} without listeners.",
+                room.title,
+                describe(idle)
+            )
+        }
+    };
+    tracing::info!("Collected idle room {}", room.id);
+
+    db.add_notice(room.owner, notice).await
+}
+
+//Queues of users who aren't in the room, its rotation or their reconnect grace period anymore
+async fn clean_user_queues(db: &Db) -> Result<usize> {
+    let mut by_room: HashMap<String, Vec<String>> = HashMap::new();
+    for (room_id, user_id) in db.list_user_queues().await? {
+        by_room.entry(room_id).or_default().push(user_id);
+    }
+
+    let mut cleaned = 0;
+    for (room_id, user_ids) in by_room {
+        let mut keep = HashSet::new();
+        if db.exists_room(room_id.clone()).await? {
+            keep.extend(db.list_queue(room_id.clone()).await?);
+            keep.extend(db.list_presences(room_id.clone()).await?);
+            //Connected users whose presence the room didn't pick up yet
+            keep.extend(db.scan_presence(room_id.clone()).await?);
+            keep.extend(
+                db.list_away(room_id.clone())
+                    .await?
+                    .into_iter()
+                    .map(|(user_id, _)| user_id),
+            );
+        }
+
+        for user_id in user_ids {
+            if !keep.contains(&user_id) {
+                db.clear_user_queue(room_id.clone(), user_id).await?;
+                cleaned += 1;
+            }
+        }
+    }
+
+    Ok(cleaned)
+}
+
+fn describe(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (amount, unit) = if secs >= 24 * 60 * 60 {
+        (secs / (24 * 60 * 60), "day")
+    } else if secs >= 60 * 60 {
+        (secs / (60 * 60), "hour")
+    } else {
+        (secs.div_ceil(60), "minute")
+    };
+
+    if amount == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", amount, unit)
+    }
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_describe_rounds_down_to_the_largest_unit() {
+        assert_eq!(describe(Duration::from_secs(30 * 24 * 60 * 60)), "30 days");
+        assert_eq!(describe(Duration::from_secs(25 * 60 * 60)), "1 day");
+        assert_eq!(describe(Duration::from_secs(2 * 60 * 60)), "2 hours");
+        assert_eq!(describe(Duration::from_secs(30)), "1 minute");
+    }
+}
@@ -0,0 +1,23 @@
+pub mod admission;
+pub mod autoplay;
+pub mod config;
+pub mod cookie;
+pub mod cooldown;
+pub mod db;
+pub mod directory;
+mod endpoint;
+pub mod error;
+pub mod health;
+pub mod invite;
+pub mod janitor;
+pub mod logging;
+pub mod metrics;
+pub mod moderation;
+pub mod pool;
+pub mod queue;
+pub mod room;
+pub mod routes;
+pub mod schedule;
+pub mod shutdown;
+pub mod socket;
+pub mod spotify;
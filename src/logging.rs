@@ -0,0 +1,12 @@
+use crate::config::LogFormat;
+use tracing_subscriber::EnvFilter;
+
+//Levels come from RUST_LOG like they did with env_logger, log records from dependencies are picked up too
+pub fn init(format: LogFormat) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(false).init(),
+    }
+}
@@ -1,20 +1,47 @@
-mod cookie;
-mod db;
-mod endpoint;
-mod room;
-mod routes;
-mod socket;
-mod spotify;
+use social_music::{config, db, health, janitor, logging, room, routes, shutdown, spotify};
 
 #[tokio::main]
 async fn main() {
-    env_logger::init();
+    let config = match config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    logging::init(config.log_format);
 
-    let db = db::connect_db();
-    let spotify = spotify::init(db.clone());
+    let db = db::connect_db(&config)
+        .await
+        .expect("Failed to connect to the database");
+    let spotify = spotify::init(db.clone(), &config.spotify);
 
-    room::start_listener(db.clone(), spotify.clone()).await;
-    warp::serve(routes::routes(db, spotify))
-        .run(([0, 0, 0, 0], 3030))
-        .await;
+    let controller = shutdown::Controller::new();
+    let heartbeat = health::Heartbeat::default();
+    room::start_listener(
+        db.clone(),
+        spotify.clone(),
+        config.timings.clone(),
+        config.rooms.clone(),
+        controller.handle(),
+        heartbeat.clone(),
+    )
+    .await;
+    janitor::start(
+        db.clone(),
+        config.rooms.clone(),
+        config.timings.clone(),
+        controller.handle(),
+    );
+
+    //Stops accepting connections on SIGINT/SIGTERM, websockets are taken care of by the controller below
+    let routes = routes::routes(config.clone(), db, spotify, controller.handle(), heartbeat);
+    let (_, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(config.bind, shutdown::signal());
+    server.await;
+
+    tracing::info!("Shutting down, releasing rooms and presences");
+    if !controller.shutdown(config.timings.shutdown_timeout()).await {
+        tracing::warn!("Some rooms or sockets didn't clean up in time");
+    }
 }
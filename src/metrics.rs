@@ -0,0 +1,156 @@
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+//Process wide, every instrumented module records straight into these
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::init);
+
+pub struct Metrics {
+    registry: Registry,
+    pub rooms_served: IntGauge,
+    pub songs_played: IntCounter,
+    pub playback_failures: IntCounter,
+    pub websockets_active: IntGauge,
+    pub websocket_connections: IntCounter,
+    pub spotify_requests: IntCounterVec,
+    pub spotify_request_duration: HistogramVec,
+    pub spotify_token_refreshes: IntCounterVec,
+    pub redis_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn init() -> Self {
+        let registry = Registry::new_custom(Some("social_music".to_string()), None).unwrap();
+
+        let rooms_served = IntGauge::new("rooms_served", "Rooms this instance is serving").unwrap();
+        let songs_played =
+            IntCounter::new("songs_played_total", "Songs started in a room").unwrap();
+        let playback_failures = IntCounter::new(
+            "playback_failures_total",
+            "Times a song couldn't be started for a listener",
+        )
+        .unwrap();
+        let websockets_active =
+            IntGauge::new("websockets_active", "Open websocket connections").unwrap();
+        let websocket_connections = IntCounter::new(
+            "websocket_connections_total",
+            "Websocket connections accepted",
+        )
+        .unwrap();
+        let spotify_requests = IntCounterVec::new(
+            Opts::new("spotify_requests_total", "Requests made to Spotify"),
+            &["endpoint", "status"],
+        )
+        .unwrap();
+        let spotify_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "spotify_request_duration_seconds",
+                "Time until Spotify responded",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+        let spotify_token_refreshes = IntCounterVec::new(
+            Opts::new("spotify_token_refreshes_total", "Access token refreshes"),
+            &["outcome"],
+        )
+        .unwrap();
+        let redis_errors = IntCounterVec::new(
+            Opts::new("redis_errors_total", "Failed Redis commands"),
+            &["kind"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(rooms_served.clone())).unwrap();
+        registry.register(Box::new(songs_played.clone())).unwrap();
+        registry
+            .register(Box::new(playback_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(websockets_active.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(websocket_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(spotify_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(spotify_request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(spotify_token_refreshes.clone()))
+            .unwrap();
+        registry.register(Box::new(redis_errors.clone())).unwrap();
+
+        Self {
+            registry,
+            rooms_served,
+            songs_played,
+            playback_failures,
+            websockets_active,
+            websocket_connections,
+            spotify_requests,
+            spotify_request_duration,
+            spotify_token_refreshes,
+            redis_errors,
+        }
+    }
+
+    //Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("Failed to encode metrics: {}", err);
+        }
+
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+//Keeps a gauge up for as long as it's held, so early returns can't leak
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn inc(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_lists_every_metric() {
+        METRICS
+            .spotify_requests
+            .with_label_values(&["me", "200"])
+            .inc();
+        let rendered = METRICS.render();
+
+        assert!(rendered.contains("social_music_rooms_served"));
+        assert!(rendered.contains("social_music_websockets_active"));
+        assert!(rendered
+            .contains("social_music_spotify_requests_total{endpoint=\"me\",status=\"200\"}"));
+    }
+
+    #[test]
+    fn test_gauge_guard_decrements_on_drop() {
+        let gauge = IntGauge::new("guarded", "Test gauge").unwrap();
+
+        let guard = GaugeGuard::inc(&gauge);
+        assert_eq!(gauge.get(), 1);
+        drop(guard);
+        assert_eq!(gauge.get(), 0);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::db::message::Message;
+use crate::db::moderation::Role;
+use crate::db::Db;
+use crate::error::{Error, Result};
+use crate::queue;
+use std::collections::HashMap;
+
+//Rooms are open to anyone with the URL, roles let the owner and their moderators keep order.
+//Moderators act on DJs and listeners, only the owner can appoint or act on moderators.
+
+pub async fn role(db: &Db, room_id: String, user_id: String) -> Result<Role> {
+    let room = db
+        .get_room(room_id.clone())
+        .await?
+        .ok_or_else(|| Error::NotFound("Couldn't find room with this id.".to_string()))?;
+    if room.owner == user_id {
+        return Ok(Role::Owner);
+    }
+    db.get_role(room_id, user_id).await
+}
+
+//Everyone whose role isn't the default, the owner included
+pub async fn roles(db: &Db, room_id: String) -> Result<HashMap<String, Role>> {
+    let mut roles = db.list_roles(room_id.clone()).await?;
+    if let Some(room) = db.get_room(room_id).await? {
+        roles.insert(room.owner, Role::Owner);
+    }
+    Ok(roles)
+}
+
+pub async fn set_role(
+    db: &Db,
+    room_id: String,
+    by: String,
+    user_id: String,
+    role: Role,
+) -> Result<()> {
+    if role == Role::Owner {
+        return Err(Error::Validation(vec![
+            "A room only has the one owner.".to_string()
+        ]));
+    }
+    let by_role = check_outranks(db, room_id.clone(), by, user_id.clone()).await?;
+    if role.rank() >= by_role.rank() {
+        return Err(Error::Forbidden(
+            "Only the owner can appoint moderators.".to_string(),
+        ));
+    }
+
+    db.set_role(room_id.clone(), user_id.clone(), role).await?;
+    if role == Role::Listener {
+        queue::leave_rotation(db, room_id.clone(), user_id.clone()).await?;
+        db.add_message(room_id.clone(), Message::queue_changed())
+            .await?;
+    }
+    db.add_message(room_id, Message::role_changed(user_id, role))
+        .await
+}
+
+//Out of the rotation and disconnected, they can come right back unless they're banned as well
+pub async fn kick(db: &Db, room_id: String, by: String, user_id: String) -> Result<()> {
+    check_outranks(db, room_id.clone(), by, user_id.clone()).await?;
+    remove(db, room_id, user_id, false).await
+}
+
+pub async fn ban(db: &Db, room_id: String, by: String, user_id: String) -> Result<()> {
+    check_outranks(db, room_id.clone(), by, user_id.clone()).await?;
+    db.add_ban(room_id.clone(), user_id.clone()).await?;
+    remove(db, room_id, user_id, true).await
+}
+
+pub async fn unban(db: &Db, room_id: String, by: String, user_id: String) -> Result<()> {
+    check_moderator(db, room_id.clone(), by).await?;
+    if !db.remove_ban(room_id, user_id).await? {
+        return Err(Error::NotFound("That user isn't banned.".to_string()));
+    }
+    Ok(())
+}
+
+//Muted users can still listen, queue and vote, they just can't chat
+pub async fn mute(
+    db: &Db,
+    room_id: String,
+    by: String,
+    user_id: String,
+    muted: bool,
+) -> Result<()> {
+    check_outranks(db, room_id.clone(), by, user_id.clone()).await?;
+    db.set_muted(room_id, user_id, muted).await
+}
+
+pub async fn check_can_chat(db: &Db, room_id: String, user_id: String) -> Result<()> {
+    if db.is_muted(room_id, user_id).await? {
+        return Err(Error::Forbidden("You're muted in this room.".to_string()));
+    }
+    Ok(())
+}
+
+pub async fn check_can_queue(db: &Db, room_id: String, user_id: String) -> Result<()> {
+    if role(db, room_id, user_id).await? == Role::Listener {
+        return Err(Error::Forbidden(
+            "Listeners can't queue tracks in this room.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+async fn remove(db: &Db, room_id: String, user_id: String, banned: bool) -> Result<()> {
+    queue::leave_rotation(db, room_id.clone(), user_id.clone()).await?;
+    db.add_message(room_id.clone(), Message::kicked(user_id, banned))
+        .await?;
+    db.add_message(room_id, Message::queue_changed()).await
+}
+
+async fn check_moderator(db: &Db, room_id: String, by: String) -> Result<Role> {
+    let by_role = role(db, room_id, by).await?;
+    if by_role.rank() < Role::Moderator.rank() {
+        return Err(Error::Forbidden("Only moderators can do that.".to_string()));
+    }
+    Ok(by_role)
+}
+
+//Returns the role of the one doing it
+async fn check_outranks(db: &Db, room_id: String, by: String, user_id: String) -> Result<Role> {
+    let by_role = check_moderator(db, room_id.clone(), by).await?;
+    if role(db, room_id, user_id).await?.rank() >= by_role.rank() {
+        return Err(Error::Forbidden(
+            "You can't do that to someone of your own rank or above.".to_string(),
+        ));
+    }
+    Ok(by_role)
+}
@@ -0,0 +1,142 @@
+use crate::db::message::Message;
+use crate::db::pool::PoolTrack;
+use crate::db::room::RoomMode;
+use crate::db::Db;
+use crate::error::{Error, Result};
+
+//Democratic rooms put everyone's tracks into one pool instead of per user queues.
+//Listeners vote tracks up or down and the room worker plays the best scoring one next.
+
+pub async fn mode(db: &Db, room_id: String) -> Result<RoomMode> {
+    Ok(db
+        .get_room(room_id)
+        .await?
+        .map(|room| room.mode)
+        .unwrap_or_default())
+}
+
+pub async fn add_track(db: &Db, room_id: String, user_id: String, track_id: String) -> Result<()> {
+    db.add_pool_track(room_id.clone(), user_id, track_id)
+        .await?;
+    db.add_message(room_id, Message::queue_changed()).await
+}
+
+//Vote is 1 or -1, or 0 to take it back. Voting again replaces the earlier vote
+pub async fn vote(db: &Db, room_id: String, voter_id: String, id: u64, vote: i8) -> Result<()> {
+    if !(-1..=1).contains(&vote) {
+        return Err(Error::Validation(vec![
+            "Vote has to be 1, -1 or 0.".to_string()
+        ]));
+    }
+
+    if !db
+        .vote_pool_track(room_id.clone(), id, voter_id, vote)
+        .await?
+    {
+        return Err(gone());
+    }
+    db.add_message(room_id, Message::queue_changed()).await
+}
+
+//Only whoever added the track can take it out again
+pub async fn remove_track(db: &Db, room_id: String, user_id: String, id: u64) -> Result<()> {
+    let track = db
+        .list_pool(room_id.clone())
+        .await?
+        .into_iter()
+        .find(|track| track.id == id)
+        .ok_or_else(gone)?;
+    if track.user_id != user_id {
+        return Err(Error::Validation(vec![
+            "You can only remove tracks you added.".to_string(),
+        ]));
+    }
+
+    if !db.take_pool_track(room_id.clone(), id).await? {
+        return Err(gone());
+    }
+    db.add_message(room_id, Message::queue_changed()).await
+}
+
+//Highest score wins, ties go to the track that has waited longest
+pub fn best(pool: &[PoolTrack]) -> Option<&PoolTrack> {
+    pool.iter()
+        .max_by(|a, b| a.score.cmp(&b.score).then(b.id.cmp(&a.id)))
+}
+
+fn gone() -> Error {
+    Error::NotFound("That track isn't in the pool anymore.".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn track(id: u64, score: i64) -> PoolTrack {
+        PoolTrack {
+            id,
+            track_id: format!("spotify:track:{}", id),
+            user_id: "user".to_string(),
+            score,
+        }
+    }
+
+    #[test]
+    fn test_best_picks_highest_score() {
+        let pool = vec![track(1, 0), track(2, 3), track(3, -1)];
+        assert_eq!(best(&pool).map(|track| track.id), Some(2));
+    }
+
+    #[test]
+    fn test_best_ties_go_to_oldest() {
+        let pool = vec![track(4, 1), track(2, 1), track(3, 0)];
+        assert_eq!(best(&pool).map(|track| track.id), Some(2));
+    }
+
+    #[test]
+    fn test_best_of_empty_pool() {
+        assert_eq!(best(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_votes_replace_earlier_votes() {
+        let db = crate::db::connect_memory_db(&Default::default());
+        let room_id = "room".to_string();
+        let added = db
+            .add_pool_track(
+                room_id.clone(),
+                "dj".to_string(),
+                "spotify:track:one".to_string(),
+            )
+            .await
+            .unwrap();
+
+        vote(&db, room_id.clone(), "fan".to_string(), added.id, 1)
+            .await
+            .unwrap();
+        vote(&db, room_id.clone(), "fan".to_string(), added.id, -1)
+            .await
+            .unwrap();
+        vote(&db, room_id.clone(), "other".to_string(), added.id, -1)
+            .await
+            .unwrap();
+        assert!(vote(&db, room_id.clone(), "fan".to_string(), added.id, 2)
+            .await
+            .is_err());
+
+        let pool = db.list_pool(room_id.clone()).await.unwrap();
+        assert_eq!(pool[0].score, -2);
+
+        assert!(
+            remove_track(&db, room_id.clone(), "fan".to_string(), added.id)
+                .await
+                .is_err()
+        );
+        remove_track(&db, room_id.clone(), "dj".to_string(), added.id)
+            .await
+            .unwrap();
+        assert!(vote(&db, room_id, "fan".to_string(), added.id, 1)
+            .await
+            .is_err());
+    }
+}
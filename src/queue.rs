@@ -0,0 +1,151 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::db::message::Message;
+use crate::db::Db;
+use crate::error::{Error, Result};
+
+//Changes to a user's own queue and their place in the DJ rotation, shared by the websocket messages and the REST endpoints.
+//Tracks are addressed by position and id, so a client acting on a stale list can't remove the wrong one.
+
+pub async fn remove_track(
+    db: &Db,
+    room_id: String,
+    user_id: String,
+    index: usize,
+    track_id: String,
+) -> Result<()> {
+    if !db
+        .remove_user_queue(room_id.clone(), user_id.clone(), index, track_id)
+        .await?
+    {
+        return Err(moved_on());
+    }
+
+    db.add_message(room_id, Message::user_queue_changed(user_id))
+        .await
+}
+
+pub async fn move_track(
+    db: &Db,
+    room_id: String,
+    user_id: String,
+    index: usize,
+    to: usize,
+    track_id: String,
+) -> Result<()> {
+    if !db
+        .move_user_queue(room_id.clone(), user_id.clone(), index, to, track_id)
+        .await?
+    {
+        return Err(moved_on());
+    }
+
+    db.add_message(room_id, Message::user_queue_changed(user_id))
+        .await
+}
+
+pub async fn clear(db: &Db, room_id: String, user_id: String) -> Result<()> {
+    db.clear_user_queue(room_id.clone(), user_id.clone())
+        .await?;
+    db.add_message(room_id, Message::user_queue_changed(user_id))
+        .await
+}
+
+//Out of the DJ rotation right away, a track of theirs that is already playing keeps playing
+pub async fn leave_rotation(db: &Db, room_id: String, user_id: String) -> Result<()> {
+    db.rem_queue(room_id.clone(), user_id.clone()).await?;
+    db.take_stepping_down(room_id, user_id).await?;
+    Ok(())
+}
+
+//Someone who drops out of the room keeps their place in the rotation and their queue for a while,
+//a page refresh or a flaky connection shouldn't cost them their turn
+pub async fn went_away(db: &Db, room_id: String, user_id: String, grace: Duration) -> Result<()> {
+    if grace.is_zero() {
+        return drop_out(db, room_id, user_id).await;
+    }
+    db.set_away(room_id, user_id, now() + grace.as_millis())
+        .await
+}
+
+//Returns whether they were still within their grace period
+pub async fn came_back(db: &Db, room_id: String, user_id: String) -> Result<bool> {
+    db.take_away(room_id, user_id).await
+}
+
+//Drops whoever's grace period ran out, returns whether anyone was
+pub async fn expire_away(db: &Db, room_id: String) -> Result<bool> {
+    let now = now();
+    let present = db.list_presences(room_id.clone()).await?;
+    let mut dropped = false;
+    for (user_id, until) in db.list_away(room_id.clone()).await? {
+        if until > now || !db.take_away(room_id.clone(), user_id.clone()).await? {
+            continue;
+        }
+        //Came back without the room noticing, e.g. while no instance was serving it
+        if present.contains(&user_id) {
+            continue;
+        }
+        drop_out(db, room_id.clone(), user_id).await?;
+        dropped = true;
+    }
+    Ok(dropped)
+}
+
+//The DJs that can take a turn right now, users who are away are skipped until they're back
+pub async fn active_rotation(db: &Db, room_id: String) -> Result<Vec<String>> {
+    let mut rotation = db.list_queue(room_id.clone()).await?;
+    let away = db.list_away(room_id).await?;
+    rotation.retain(|user_id| !away.iter().any(|(away_id, _)| away_id == user_id));
+    Ok(rotation)
+}
+
+async fn drop_out(db: &Db, room_id: String, user_id: String) -> Result<()> {
+    leave_rotation(db, room_id.clone(), user_id.clone()).await?;
+    db.clear_user_queue(room_id, user_id).await
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+//Leaves once the track playing now has played, whoever queued it. Returns whether that already happened
+//because nothing is playing.
+pub async fn step_down(db: &Db, room_id: String, user_id: String) -> Result<bool> {
+    if !db.list_queue(room_id.clone()).await?.contains(&user_id) {
+        return Err(Error::NotFound("You're not in the queue.".to_string()));
+    }
+
+    let playing = db
+        .get_playing(room_id.clone())
+        .await?
+        .is_some_and(|playing| playing.is_playing());
+    if !playing {
+        leave_rotation(db, room_id, user_id).await?;
+        return Ok(true);
+    }
+    db.set_stepping_down(room_id, user_id).await?;
+    Ok(false)
+}
+
+//For when a track ends, drops whoever stepped down while it played. Returns whether anyone was
+pub async fn finish_stepping_down(db: &Db, room_id: String) -> Result<bool> {
+    let mut dropped = false;
+    for user_id in db.list_queue(room_id.clone()).await? {
+        if db
+            .take_stepping_down(room_id.clone(), user_id.clone())
+            .await?
+        {
+            db.rem_queue(room_id.clone(), user_id).await?;
+            dropped = true;
+        }
+    }
+    Ok(dropped)
+}
+
+fn moved_on() -> Error {
+    Error::NotFound("That track isn't at this position in your queue anymore.".to_string())
+}
@@ -1,91 +1,131 @@
+use crate::autoplay;
+use crate::config::{RoomConfig, Timings};
 use crate::db;
+use crate::db::history::HistoryEntry;
 use crate::db::message::Message;
-use crate::db::presence::PresenceEventActivty;
-use crate::spotify;
+use crate::db::playing::Playing;
+use crate::db::presence::{PresenceEvent, PresenceEventActivty};
+use crate::db::room::{RoomMode, SchedulingPolicy};
+use crate::error::{self, Error};
+use crate::health::Heartbeat;
+use crate::metrics::{GaugeGuard, METRICS};
+use crate::pool;
+use crate::queue;
+use crate::schedule;
+use crate::shutdown::Shutdown;
+use crate::spotify::{self, tracks::Track};
+use std::time::Duration;
+use tracing::Instrument;
 
-pub async fn start_listener(db: db::Db, spotify: spotify::Spotify) {
+pub async fn start_listener(
+    db: db::Db,
+    spotify: spotify::Spotify,
+    timings: Timings,
+    rooms: RoomConfig,
+    shutdown: Shutdown,
+    heartbeat: Heartbeat,
+) {
+    //Counts as alive right away, not only once the task gets scheduled
+    heartbeat.beat();
     tokio::task::spawn(async move {
-        loop {
-            let mut inner_db = db.lock().await;
-            let rx = inner_db.claim_room().await;
-            std::mem::drop(inner_db);
+        while !shutdown.is_shutting_down() {
+            heartbeat.beat();
+            let rx = match db.claim_room().await {
+                Ok(rx) => rx,
+                Err(err) => {
+                    tracing::error!("Failed to claim a room: {}", err);
+                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
 
-            if let Some(room_id) = rx.await.unwrap() {
-                tokio::task::spawn(serve_room(db.clone(), spotify.clone(), room_id));
+            if let Ok(Some(room_id)) = rx.await {
+                if shutdown.is_shutting_down() {
+                    //Claimed while shutting down, hand it straight to another instance
+                    release_room(&db, room_id).await;
+                } else {
+                    tokio::task::spawn(serve_room(
+                        db.clone(),
+                        spotify.clone(),
+                        timings.clone(),
+                        rooms.clone(),
+                        shutdown.clone(),
+                        room_id,
+                    ));
+                }
             }
         }
     });
 }
 
-async fn serve_room(db: db::Db, spotify: spotify::Spotify, room_id: String) {
-    log::info!("Claimed room {}", room_id.clone());
+#[tracing::instrument(skip_all, fields(room_id = %room_id))]
+async fn serve_room(
+    db: db::Db,
+    spotify: spotify::Spotify,
+    timings: Timings,
+    rooms: RoomConfig,
+    mut shutdown: Shutdown,
+    room_id: String,
+) {
+    tracing::info!("Claimed room {}", room_id.clone());
+    let _serving = GaugeGuard::inc(&METRICS.rooms_served);
 
-    let (_kill_presence_tx, mut kill_presence_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let (kill_presence_tx, kill_presence_rx) = tokio::sync::mpsc::channel::<()>(1);
     let inner_db = db.clone();
     let inner_room_id = room_id.clone();
-    tokio::task::spawn(async move {
-        let mut db = inner_db.lock().await;
-        let mut presence_rx = db.subscribe_presence(inner_room_id.clone()).await;
-        let users = db.scan_presence(inner_room_id.clone()).await;
-        db.del_presences(inner_room_id.clone()).await;
-
-        for user_id in users {
-            db.add_presences(inner_room_id.clone(), user_id.clone())
-                .await;
-            db.add_message(inner_room_id.clone(), Message::presence_changed())
-                .await;
-        }
-        std::mem::drop(db);
-
-        loop {
-            tokio::select! {
-                presence_event = presence_rx.recv() => {
-                    match presence_event {
-                        Some(event) => {
-                            let mut db = inner_db.lock().await;
-                            match event.activity {
-                                PresenceEventActivty::Join => {
-                                    db.add_presences(inner_room_id.clone(), event.user_id.clone()).await;
-                                    db.add_message(inner_room_id.clone(), Message::presence_changed()).await;
-                                },
-                                PresenceEventActivty::Leave => {
-                                    db.rem_presences(inner_room_id.clone(), event.user_id.clone()).await;
-                                    db.rem_queue(inner_room_id.clone(), event.user_id).await;
-                                    db.add_message(inner_room_id.clone(), Message::presence_changed()).await;
-                                }
-                            }
-                        },
-                        None => {
-                            break;
-                        }
-                    }
-                },
-                _ = kill_presence_rx.recv() => {
-                    break;
-                }
+    let grace = rooms.reconnect_grace();
+    tokio::task::spawn(
+        async move {
+            if let Err(err) =
+                watch_presence(inner_db, inner_room_id.clone(), grace, kill_presence_rx).await
+            {
+                tracing::error!(
+                    "Stopped watching presence in room {}: {}",
+                    inner_room_id,
+                    err
+                );
             }
         }
-    });
+        .instrument(tracing::Span::current()),
+    );
 
-    let (_kill_db_tx, mut kill_db_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let (kill_db_tx, mut kill_db_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let (skip_tx, mut skip_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let (deleted_tx, mut deleted_rx) = tokio::sync::mpsc::channel::<()>(1);
     let inner_db = db.clone();
     let inner_room_id = room_id.clone();
     let inner_spotify = spotify.clone();
+    let inner_rooms = rooms.clone();
+    let device_settle = timings.device_settle();
     tokio::task::spawn(async move {
-        let mut db = inner_db.lock().await;
-        let mut db_rx = db.subscribe_messages(inner_room_id.clone()).await;
-        std::mem::drop(db);
+        let mut db_rx = match inner_db.subscribe_messages(inner_room_id.clone()).await {
+            Ok(db_rx) => db_rx,
+            Err(err) => {
+                tracing::error!("Failed to subscribe to room {}: {}", inner_room_id, err);
+                return;
+            }
+        };
 
         loop {
             tokio::select! {
                 message = db_rx.recv() => {
-                    if let Some(message) = message {
-                        match message.data {
-                            db::message::MessageType::MessageDeviceChange(device_change) => {
-                                play_song_on_join(inner_db.clone(), inner_spotify.clone(), inner_room_id.clone(), device_change.user_id).await;
-                            },
-                            _ => ()
-                        }
+                    match message.map(|message| message.data) {
+                        Some(db::message::MessageType::MessageDeviceChange(device_change)) => {
+                            if let Err(err) = play_song_on_join(inner_db.clone(), inner_spotify.clone(), inner_room_id.clone(), device_change.user_id, device_settle).await {
+                                tracing::error!("Failed to resume playback in room {}: {}", inner_room_id, err);
+                            }
+                        },
+                        Some(db::message::MessageType::MessageSkipVote) => {
+                            match skip_vote_passed(&inner_db, inner_room_id.clone(), &inner_rooms).await {
+                                Ok(true) => skip_tx.send(()).await.unwrap_or(()),
+                                Ok(false) => (),
+                                Err(err) => tracing::error!("Failed to count skip votes in room {}: {}", inner_room_id, err),
+                            }
+                        },
+                        Some(db::message::MessageType::MessageRoomDeleted) => {
+                            deleted_tx.send(()).await.unwrap_or(());
+                        },
+                        _ => ()
                     }
                 },
                 _ = kill_db_rx.recv() => {
@@ -93,102 +133,498 @@ async fn serve_room(db: db::Db, spotify: spotify::Spotify, room_id: String) {
                 }
             }
         }
-    });
+    }.instrument(tracing::Span::current()));
 
-    let refresh = tokio::time::sleep(tokio::time::Duration::from_secs(3));
+    let refresh = tokio::time::sleep(timings.room_claim_refresh());
     let play_song = tokio::time::sleep(tokio::time::Duration::from_secs(1));
 
     tokio::pin!(refresh);
     tokio::pin!(play_song);
+    //Set when the timer was cut short by a skip vote, rather than the track running out
+    let mut skipping = false;
+    //Deleted rooms aren't handed on, whatever the worker left behind is cleaned up instead
+    let mut deleted = false;
+    //Archived rooms aren't handed on either, until someone joins them again
+    let mut archived = false;
+    //Start time of the last track its DJ got listening time for, one that ended before this worker took over already was
+    let mut credited = match db.get_playing(room_id.clone()).await {
+        Ok(Some(playing)) if !playing.is_playing() => Some(playing.start_time),
+        _ => None,
+    };
 
     loop {
         tokio::select! {
             _ = &mut refresh => {
-                let mut db = db.lock().await;
-                db.keep_alive_room_claim(room_id.clone()).await;
-                refresh.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_secs(3));
+                //In case the deletion message was missed
+                match db.exists_room(room_id.clone()).await {
+                    Ok(false) => {
+                        deleted = true;
+                        break;
+                    }
+                    Ok(true) => (),
+                    Err(err) => tracing::error!("Failed to look up room {}: {}", room_id, err),
+                }
+                match db.is_archived_room(room_id.clone()).await {
+                    Ok(true) => {
+                        archived = true;
+                        break;
+                    }
+                    Ok(false) => (),
+                    Err(err) => tracing::error!("Failed to look up room {}: {}", room_id, err),
+                }
+                if let Err(err) = db.keep_alive_room_claim(room_id.clone()).await {
+                    tracing::error!("Failed to keep claim on room {}: {}", room_id, err);
+                }
+                if let Err(err) = expire_away(&db, room_id.clone()).await {
+                    tracing::error!("Failed to drop users who left room {}: {}", room_id, err);
+                }
+                refresh.as_mut().reset(tokio::time::Instant::now() + timings.room_claim_refresh());
             }
             _ = &mut play_song => {
-                let time = play_next_song(db.clone(), spotify.clone(), room_id.clone()).await.unwrap_or(1000);
+                let skipped = std::mem::take(&mut skipping);
+                //Before picking the next DJ, so the fair share policy knows how long this track went on
+                match credit_listening_time(&db, room_id.clone(), skipped, credited).await {
+                    Ok(done) => credited = done,
+                    Err(err) => tracing::error!("Failed to credit listening time in room {}: {}", room_id, err),
+                }
+                let time = match play_next_song(db.clone(), spotify.clone(), room_id.clone(), rooms.autoplay, skipped).await {
+                    Ok(time) => time.unwrap_or(1000),
+                    //The song is still queued, try again once Spotify has had a moment
+                    Err(err) if err.is_transient() => {
+                        tracing::warn!("Delaying next song in room {}: {}", room_id, err);
+                        err.retry_after().map_or(1000, |after| after.as_millis() as u64).max(1000)
+                    }
+                    Err(err) => {
+                        tracing::error!("Failed to play next song in room {}: {}", room_id, err);
+                        1000
+                    }
+                };
                 play_song.as_mut().reset(tokio::time::Instant::now() + tokio::time::Duration::from_millis(time));
             }
+            Some(()) = skip_rx.recv() => {
+                skipping = true;
+                play_song.as_mut().reset(tokio::time::Instant::now());
+            }
+            Some(()) = deleted_rx.recv() => {
+                deleted = true;
+                break;
+            }
+            _ = shutdown.recv() => {
+                break;
+            }
+        }
+    }
+
+    drop(kill_presence_tx);
+    drop(kill_db_tx);
+    if deleted {
+        tracing::info!("Stopped serving deleted room {}", room_id);
+        if let Err(err) = db.delete_room(room_id.clone()).await {
+            tracing::error!("Failed to clean up deleted room {}: {}", room_id, err);
+        }
+    } else if archived {
+        tracing::info!("Stopped serving archived room {}", room_id);
+        if let Err(err) = db.unclaim_room(room_id.clone()).await {
+            tracing::error!("Failed to drop claim on room {}: {}", room_id, err);
+        }
+    } else {
+        release_room(&db, room_id).await;
+    }
+}
+
+async fn release_room(db: &db::Db, room_id: String) {
+    match db.release_room(room_id.clone()).await {
+        Ok(()) => tracing::info!("Released room {}", room_id),
+        Err(err) => tracing::error!("Failed to release room {}: {}", room_id, err),
+    }
+}
+
+async fn expire_away(db: &db::Db, room_id: String) -> error::Result<()> {
+    if queue::expire_away(db, room_id.clone()).await? {
+        db.add_message(room_id, Message::queue_changed()).await?;
+    }
+    Ok(())
+}
+
+async fn watch_presence(
+    db: db::Db,
+    room_id: String,
+    grace: Duration,
+    mut kill_presence_rx: tokio::sync::mpsc::Receiver<()>,
+) -> error::Result<()> {
+    let mut presence_rx = db.subscribe_presence(room_id.clone()).await?;
+    let users = db.scan_presence(room_id.clone()).await?;
+    db.del_presences(room_id.clone()).await?;
+
+    for user_id in users {
+        db.add_presences(room_id.clone(), user_id.clone()).await?;
+        db.add_message(room_id.clone(), Message::presence_changed())
+            .await?;
+    }
+
+    loop {
+        tokio::select! {
+            presence_event = presence_rx.recv() => {
+                match presence_event {
+                    Some(event) => {
+                        if let Err(err) = on_presence_event(&db, room_id.clone(), event, grace).await {
+                            tracing::error!("Failed to update presence in room {}: {}", room_id, err);
+                        }
+                    },
+                    None => {
+                        break;
+                    }
+                }
+            },
+            _ = kill_presence_rx.recv() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn on_presence_event(
+    db: &db::Db,
+    room_id: String,
+    event: PresenceEvent,
+    grace: Duration,
+) -> error::Result<()> {
+    match event.activity {
+        PresenceEventActivty::Join => {
+            db.add_presences(room_id.clone(), event.user_id.clone())
+                .await?;
+            queue::came_back(db, room_id.clone(), event.user_id).await?;
         }
+        PresenceEventActivty::Leave => {
+            db.rem_presences(room_id.clone(), event.user_id.clone())
+                .await?;
+            queue::went_away(db, room_id.clone(), event.user_id, grace).await?;
+        }
+    }
+
+    db.add_message(room_id, Message::presence_changed()).await
+}
+
+//Once enough of the listeners voted the votes are used up and the room is told about the skip
+async fn skip_vote_passed(db: &db::Db, room_id: String, rooms: &RoomConfig) -> error::Result<bool> {
+    let listeners = db.list_presences(room_id.clone()).await?;
+    //Votes of listeners who left since don't count
+    let votes = db
+        .list_skip_votes(room_id.clone())
+        .await?
+        .iter()
+        .filter(|user_id| listeners.contains(user_id))
+        .count();
+    if votes < votes_needed(listeners.len(), rooms.vote_skip_threshold) {
+        return Ok(false);
+    }
+
+    db.clear_skip_votes(room_id.clone()).await?;
+    let mode = pool::mode(db, room_id.clone()).await?;
+    //Autoplay has something else to play even then
+    if !rooms.autoplay && nothing_queued(db, room_id.clone(), mode).await? {
+        let message = "Enough votes to skip, but nobody has anything queued to play instead.";
+        db.add_message(
+            room_id,
+            Message::chat_message("system".to_string(), message.to_string()),
+        )
+        .await?;
+        return Ok(false);
+    }
+
+    if let Some(playing) = db.get_playing(room_id.clone()).await? {
+        db.skip_history(room_id.clone(), playing.start_time).await?;
+    }
+    let message = format!(
+        "Skipping this track, {} of {} listeners voted to skip it.",
+        votes,
+        listeners.len()
+    );
+    db.add_message(
+        room_id,
+        Message::chat_message("system".to_string(), message),
+    )
+    .await?;
+    Ok(true)
+}
+
+//Credits the DJ of the playing track with how long it actually played, once it ran out or was skipped.
+//Returns the start time of the last track credited, which is passed back in so no track counts twice.
+async fn credit_listening_time(
+    db: &db::Db,
+    room_id: String,
+    skipped: bool,
+    credited: Option<u128>,
+) -> error::Result<Option<u128>> {
+    let Some(playing) = db.get_playing(room_id.clone()).await? else {
+        return Ok(credited);
+    };
+    if credited == Some(playing.start_time) || (!skipped && playing.is_playing()) {
+        return Ok(credited);
+    }
+
+    if let Some(user_id) = playing.user_id {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let played = now
+            .saturating_sub(playing.start_time)
+            .min(u128::from(playing.length)) as u64;
+        db.add_listening_time(room_id, user_id, played).await?;
+    }
+    Ok(Some(playing.start_time))
+}
+
+fn votes_needed(listeners: usize, threshold: f64) -> usize {
+    ((listeners as f64 * threshold).ceil() as usize).max(1)
+}
+
+async fn nothing_queued(db: &db::Db, room_id: String, mode: RoomMode) -> error::Result<bool> {
+    Ok(match mode {
+        RoomMode::Rotation => queue::active_rotation(db, room_id).await?.is_empty(),
+        RoomMode::Democratic => db.list_pool(room_id).await?.is_empty(),
+    })
+}
+
+async fn play_next_song(
+    db: db::Db,
+    spotify: spotify::Spotify,
+    room_id: String,
+    autoplay: bool,
+    skipping: bool,
+) -> error::Result<Option<u64>> {
+    let (mode, policy) = db
+        .get_room(room_id.clone())
+        .await?
+        .map(|room| (room.mode, room.policy))
+        .unwrap_or_default();
+    //Before the next DJ is picked, so nobody who stepped down gets another turn
+    if queue::finish_stepping_down(&db, room_id.clone()).await? {
+        db.add_message(room_id.clone(), Message::queue_changed())
+            .await?;
+    }
+
+    if autoplay && nothing_queued(&db, room_id.clone(), mode).await? {
+        return play_autoplay(db, spotify, room_id, skipping).await;
+    }
+
+    match mode {
+        RoomMode::Rotation => play_next_from_rotation(db, spotify, room_id, policy).await,
+        RoomMode::Democratic => play_next_from_pool(db, spotify, room_id).await,
     }
 }
 
-async fn play_next_song(db: db::Db, spotify: spotify::Spotify, room_id: String) -> Option<u64> {
-    let mut inner_db = db.lock().await;
-    if let Some(next_user_id) = inner_db.pop_queue(room_id.clone()).await {
-        if let Some(uri) = inner_db
+async fn play_next_from_rotation(
+    db: db::Db,
+    spotify: spotify::Spotify,
+    room_id: String,
+    policy: SchedulingPolicy,
+) -> error::Result<Option<u64>> {
+    if let Some(next_user_id) = next_dj(&db, room_id.clone(), policy).await? {
+        if let Some(uri) = db
             .pop_user_queue(room_id.clone(), next_user_id.clone())
-            .await
+            .await?
         {
+            //Gather all we need to play the next song
+            let token = db.require_auth(next_user_id.clone()).await?;
+            let track = match spotify.request_track(token.clone(), uri.clone()).await {
+                Ok(track) => track,
+                //Spotify is struggling, put everything back so the song isn't lost
+                Err(err) if err.is_transient() => {
+                    db.push_front_user_queue(room_id.clone(), next_user_id.clone(), uri)
+                        .await?;
+                    db.push_front_queue(room_id.clone(), next_user_id).await?;
+                    return Err(err);
+                }
+                //The song itself is the problem, skip it but keep the user's turn in rotation
+                Err(err) => {
+                    db.push_queue(room_id.clone(), next_user_id.clone()).await?;
+                    db.add_message(room_id, Message::user_queue_changed(next_user_id))
+                        .await?;
+                    return Err(err);
+                }
+            };
+
             //Notify next_user_id on their queue change
-            inner_db.add_message(room_id.clone(), Message::user_queue_changed(next_user_id.clone())).await;
+            db.add_message(
+                room_id.clone(),
+                Message::user_queue_changed(next_user_id.clone()),
+            )
+            .await?;
 
-            //Gather all we need to play the next song
-            let token = inner_db.get_auth(next_user_id.clone()).await.unwrap();
-            let users = inner_db.list_presences(room_id.clone()).await;
-            inner_db.push_queue(room_id.clone(), next_user_id).await;
-            std::mem::drop(inner_db);
-
-            let inner_spotify = spotify.lock().await;
-            let track = inner_spotify
-                .request_track(token.clone(), uri.clone())
-                .await;
-            std::mem::drop(inner_spotify);
-
-            let mut inner_db = db.lock().await;
-            let time = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-            inner_db
-                .set_playing(
-                    room_id.clone(),
-                    uri.clone(),
-                    time,
-                    track.duration_ms.clone(),
-                )
-                .await;
-            inner_db
-                .add_message(room_id.clone(), Message::queue_changed())
-                .await;
-            std::mem::drop(inner_db);
-
-            for user_id in users {
-                play_song(db.clone(), spotify.clone(), user_id, uri.clone(), 0).await;
-            }
+            db.push_queue(room_id.clone(), next_user_id.clone()).await?;
 
-            Some(track.duration_ms)
+            start_track(&db, &spotify, room_id, Some(next_user_id), uri, &track).await?;
+            Ok(Some(track.duration_ms))
         } else {
-            inner_db
-                .add_message(room_id.clone(), Message::queue_changed())
-                .await;
-            None
+            //Out of tracks, which drops the user from the rotation anyway
+            db.take_stepping_down(room_id.clone(), next_user_id).await?;
+            db.add_message(room_id.clone(), Message::queue_changed())
+                .await?;
+            Ok(None)
         }
     } else {
-        inner_db
-            .add_message(room_id.clone(), Message::queue_changed())
-            .await;
-        None
+        db.add_message(room_id.clone(), Message::queue_changed())
+            .await?;
+        Ok(None)
+    }
+}
+
+//Takes whoever the room's scheduling policy picks out of the rotation
+async fn next_dj(
+    db: &db::Db,
+    room_id: String,
+    policy: SchedulingPolicy,
+) -> error::Result<Option<String>> {
+    let rotation = queue::active_rotation(db, room_id.clone()).await?;
+    if rotation.is_empty() {
+        return Ok(None);
+    }
+
+    let user_id = schedule::scheduler(policy)
+        .pick(db, room_id.clone(), &rotation)
+        .await?;
+
+    //They might have left since the rotation was listed
+    if db.take_queue(room_id, user_id.clone()).await? {
+        Ok(Some(user_id))
+    } else {
+        Ok(None)
+    }
+}
+
+async fn play_next_from_pool(
+    db: db::Db,
+    spotify: spotify::Spotify,
+    room_id: String,
+) -> error::Result<Option<u64>> {
+    let pool = db.list_pool(room_id.clone()).await?;
+    let Some(next) = pool::best(&pool).cloned() else {
+        db.add_message(room_id.clone(), Message::queue_changed())
+            .await?;
+        return Ok(None);
+    };
+
+    let track = async {
+        let token = db.require_auth(next.user_id.clone()).await?;
+        spotify.request_track(token, next.track_id.clone()).await
+    }
+    .await;
+    let track = match track {
+        Ok(track) => track,
+        //Still in the pool, it gets another go once Spotify has had a moment
+        Err(err) if err.is_transient() => return Err(err),
+        //The track or whoever added it is the problem, drop it so it doesn't hold up the pool
+        Err(err) => {
+            db.take_pool_track(room_id.clone(), next.id).await?;
+            db.add_message(room_id, Message::queue_changed()).await?;
+            return Err(err);
+        }
+    };
+
+    db.take_pool_track(room_id.clone(), next.id).await?;
+    start_track(
+        &db,
+        &spotify,
+        room_id,
+        Some(next.user_id),
+        next.track_id,
+        &track,
+    )
+    .await?;
+    Ok(Some(track.duration_ms))
+}
+
+//Returns None while an autoplay track is playing, so a freshly queued track can take over right away
+async fn play_autoplay(
+    db: db::Db,
+    spotify: spotify::Spotify,
+    room_id: String,
+    skipping: bool,
+) -> error::Result<Option<u64>> {
+    let playing = db.get_playing(room_id.clone()).await?;
+    if !skipping && playing.is_some_and(|playing| playing.is_playing()) {
+        return Ok(None);
+    }
+
+    if let Some(track) = autoplay::pick(&db, &spotify, room_id.clone()).await? {
+        start_track(&db, &spotify, room_id, None, track.uri.clone(), &track).await?;
+    }
+    Ok(None)
+}
+
+//Plays uri for everyone in the room, user_id is who picked it and missing for autoplay.
+//track is what Spotify has on uri, which may be a relinked version of it
+async fn start_track(
+    db: &db::Db,
+    spotify: &spotify::Spotify,
+    room_id: String,
+    user_id: Option<String>,
+    uri: String,
+    track: &Track,
+) -> error::Result<()> {
+    let users = db.list_presences(room_id.clone()).await?;
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let playing = Playing {
+        user_id: user_id.clone(),
+        track_id: uri.clone(),
+        start_time: time,
+        length: track.duration_ms,
+        autoplay: user_id.is_none(),
+    };
+    db.set_playing(room_id.clone(), playing).await?;
+    let entry = HistoryEntry {
+        id: 0,
+        track_id: uri.clone(),
+        user_id: user_id.clone(),
+        start_time: time,
+        skipped: false,
+    };
+    db.add_history(room_id.clone(), entry).await?;
+    //Autoplay picks weren't queued, so the cooldown hasn't heard of them yet
+    if let Some(isrc) = track.external_ids.isrc.clone() {
+        db.set_isrc(room_id.clone(), uri.clone(), isrc).await?;
+    }
+    db.clear_upvoters(room_id.clone()).await?;
+    db.clear_skip_votes(room_id.clone()).await?;
+    db.add_message(room_id.clone(), Message::queue_changed())
+        .await?;
+    METRICS.songs_played.inc();
+
+    //One listener failing to play shouldn't stop the rest
+    for user_id in users {
+        if let Err(err) =
+            play_song(db.clone(), spotify.clone(), user_id.clone(), uri.clone(), 0).await
+        {
+            tracing::warn!("Failed to play {} for {}: {}", uri, user_id, err);
+            METRICS.playback_failures.inc();
+        }
     }
+
+    Ok(())
 }
 
+#[tracing::instrument(skip_all, fields(user_id = %user_id))]
 async fn play_song(
     db: db::Db,
     spotify: spotify::Spotify,
     user_id: String,
     uri: String,
     position: u32,
-) {
-    let mut db = db.lock().await;
-    let token = db.get_auth(user_id.clone()).await.unwrap();
-    let device_id = db.get_device(user_id.clone()).await.unwrap();
-    std::mem::drop(db);
+) -> error::Result<()> {
+    let token = db.require_auth(user_id.clone()).await?;
+    let device_id = db
+        .get_device(user_id.clone())
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No playback device for {}", user_id)))?;
 
-    let spotify = spotify.lock().await;
-    spotify.request_play(token, device_id, uri, position).await;
+    spotify.request_play(token, device_id, uri, position).await
 }
 
 async fn play_song_on_join(
@@ -196,17 +632,16 @@ async fn play_song_on_join(
     spotify: spotify::Spotify,
     room_id: String,
     user_id: String,
-) {
+    device_settle: Duration,
+) -> error::Result<()> {
     //This function is called when the device id changes (i.e. user rejoins the room in the middle of song playback)
     //since spotify systems are a bit weird there seems to be a race condition where we have a device id, but it hasn't
     //registered with the rest of the spotify systems. We have to wait a bit. Sleeping for 1 second seemed to be 50/50
-    //chance of it going through, so chose 3 seconds to be safe.
+    //chance of it going through, so the default is 3 seconds to be safe.
     //TODO: Replace with a retry loop
-    tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+    tokio::time::sleep(device_settle).await;
 
-    let mut inner_db = db.lock().await;
-    let playing = inner_db.get_playing(room_id).await;
-    std::mem::drop(inner_db);
+    let playing = db.get_playing(room_id).await?;
 
     if let Some(playing) = playing {
         let time = std::time::SystemTime::now()
@@ -216,7 +651,27 @@ async fn play_song_on_join(
 
         if time < playing.start_time + u128::from(playing.length) {
             let offset = u32::try_from(time - playing.start_time).unwrap();
-            play_song(db.clone(), spotify, user_id, playing.track_id, offset).await;
+            play_song(db.clone(), spotify, user_id, playing.track_id, offset).await?;
         }
     }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_votes_needed_rounds_up() {
+        assert_eq!(votes_needed(4, 0.5), 2);
+        assert_eq!(votes_needed(5, 0.5), 3);
+        assert_eq!(votes_needed(3, 1.0), 3);
+    }
+
+    #[test]
+    fn test_votes_needed_at_least_one() {
+        assert_eq!(votes_needed(0, 0.5), 1);
+        assert_eq!(votes_needed(1, 0.1), 1);
+    }
 }
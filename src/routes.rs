@@ -1,43 +1,77 @@
-use crate::cookie;
+use crate::config::{self, Config};
+use crate::cookie::{self, CookieKey};
 use crate::db;
 use crate::db::Db;
 use crate::endpoint;
+use crate::error;
+use crate::health::{self, Heartbeat};
+use crate::shutdown::{self, Shutdown};
 use crate::spotify;
 use crate::spotify::Spotify;
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::Path;
 use warp::filters::BoxedFilter;
 use warp::Filter;
 
 pub fn routes(
+    config: Config,
     db: Db,
     spotify: Spotify,
-) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    shutdown: Shutdown,
+    heartbeat: Heartbeat,
+) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
+    let cookie_key = cookie::init(&config);
+    //Only sockets that are actually open should keep shutdown waiting
+    let shutdown = shutdown.downgrade();
+
     let login = warp::path("login")
         .and(warp::get())
+        .and(spotify::with(spotify.clone()))
         .and_then(endpoint::get_login);
     let authorize = warp::path("authorize")
         .and(warp::get())
         .and(warp::query::<HashMap<String, String>>())
         .and(spotify::with(spotify.clone()))
         .and(db::with(db.clone()))
+        .and(cookie::with(cookie_key.clone()))
         .and_then(endpoint::get_authorize);
     let logout = warp::path("logout")
         .and(warp::get())
         .and_then(endpoint::get_logout);
     let chat = warp::path!("chat" / String)
-        .and(cookie::with_user())
+        .and(cookie::with_user(cookie_key.clone()))
         .and(db::with(db.clone()))
         .and(spotify::with(spotify.clone()))
+        .and(config::with(config.clone()))
+        .and(shutdown::with(shutdown))
+        .and(cookie::with(cookie_key.clone()))
+        .and(warp::query::<endpoint::InviteQuery>())
         .and(warp::ws())
         .and_then(endpoint::ws_chat);
+    let healthz = warp::path("healthz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(endpoint::get_healthz);
+    let readyz = warp::path("readyz")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(db::with(db.clone()))
+        .and(health::with(heartbeat))
+        .and(config::with(config.clone()))
+        .and_then(endpoint::get_readyz);
+    let metrics = warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(endpoint::get_metrics);
     let test = warp::path("test")
         .and(warp::get())
-        .and(cookie::with_user())
+        .and(cookie::with_user(cookie_key.clone()))
         .and(db::with(db.clone()))
         .and_then(endpoint::get_test_endpoint);
     let token = warp::path("token")
         .and(warp::get())
-        .and(cookie::with_user())
+        .and(cookie::with_user(cookie_key.clone()))
         .and(spotify::with(spotify.clone()))
         .and(db::with(db.clone()))
         .and_then(endpoint::get_token);
@@ -47,79 +81,152 @@ pub fn routes(
         .or(logout)
         .or(test)
         .or(token)
+        .or(healthz)
+        .or(readyz)
+        .or(metrics)
         .or(chat)
-        .or(routes_api(db.clone(), spotify.clone()))
-        .or(routes_static())
+        .or(routes_api(db.clone(), spotify.clone(), cookie_key))
+        .or(routes_static(&config.www_dir))
+        .recover(error::recover)
 }
 
-fn routes_static() -> BoxedFilter<(impl warp::Reply,)> {
-    let default = warp::get().and(warp::fs::file("./www/index.html"));
+fn routes_static(www_dir: &Path) -> BoxedFilter<(impl warp::Reply,)> {
+    let default = warp::get().and(warp::fs::file(www_dir.join("index.html")));
     let assets = warp::path("assets")
         .and(warp::get())
-        .and(warp::fs::dir("./www/assets/"));
+        .and(warp::fs::dir(www_dir.join("assets")));
     let robots = warp::path("robots.txt")
         .and(warp::path::end())
         .and(warp::get())
-        .and(warp::fs::file("./www/robots.txt"));
+        .and(warp::fs::file(www_dir.join("robots.txt")));
     let icon = warp::path("icon.svg")
         .and(warp::path::end())
         .and(warp::get())
-        .and(warp::fs::file("./www/icon.svg"));
+        .and(warp::fs::file(www_dir.join("icon.svg")));
 
     assets.or(robots).or(icon).or(default).boxed()
 }
 
-fn routes_api(db: Db, spotify: Spotify) -> BoxedFilter<(impl warp::Reply,)> {
+fn routes_api(db: Db, spotify: Spotify, cookie_key: CookieKey) -> BoxedFilter<(impl warp::Reply,)> {
     warp::path("api")
         .and(warp::path("v1"))
         .and(
-            routes_api_room(db.clone())
-                .or(routes_api_search(db.clone(), spotify.clone()))
-                .or(routes_api_queue(db.clone(), spotify.clone()))
-                .or(warp::path::end().map(|| "api")),
+            routes_api_room(db.clone(), spotify.clone(), cookie_key.clone())
+                .or(routes_api_search(
+                    db.clone(),
+                    spotify.clone(),
+                    cookie_key.clone(),
+                ))
+                .or(routes_api_queue(db.clone(), spotify.clone(), cookie_key))
+                .or(warp::path::end().map(|| "api"))
+                .recover(error::recover),
         )
         .boxed()
 }
 
-fn routes_api_room(db: Db) -> BoxedFilter<(impl warp::Reply,)> {
+fn routes_api_room(
+    db: Db,
+    spotify: Spotify,
+    cookie_key: CookieKey,
+) -> BoxedFilter<(impl warp::Reply,)> {
     //POST /api/v1/rooms
-    let post_room = warp::path::end()
-        .and(warp::post())
-        .and(cookie::with_user())
+    //Errors are answered right away on /rooms, or the fallback below would take over from them
+    let post_room = warp::path::end().and(warp::post()).and(
+        cookie::with_user(cookie_key.clone())
+            .and(db::with(db.clone()))
+            .and(warp::body::json())
+            .and_then(endpoint::create_room)
+            .recover(error::recover),
+    );
+
+    //GET /api/v1/rooms?q={search}&sort={sort}&after={cursor}&limit={limit}
+    //Sorted by listeners, activity or title, cursor is the next field of the previous page
+    let get_rooms = warp::path::end().and(warp::get()).and(
+        cookie::with_user(cookie_key.clone())
+            .and(db::with(db.clone()))
+            .and(warp::query::<endpoint::RoomsQuery>())
+            .and_then(endpoint::list_rooms)
+            .recover(error::recover),
+    );
+
+    //GET /api/v1/rooms/{id}?invite={token}
+    //Private rooms need the invite until the user is a member
+    let get_room = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(cookie::with_user(cookie_key.clone()))
+        .and(db::with(db.clone()))
+        .and(cookie::with(cookie_key.clone()))
+        .and(warp::query::<endpoint::InviteQuery>())
+        .and_then(endpoint::get_room);
+
+    //PATCH /api/v1/rooms/{id}
+    //Only the fields given are changed, owner only
+    let patch_room = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::patch())
+        .and(cookie::with_user(cookie_key.clone()))
         .and(db::with(db.clone()))
         .and(warp::body::json())
-        .and_then(endpoint::create_room);
+        .and_then(endpoint::update_room);
 
-    //GET /api/v1/rooms
-    let get_rooms = warp::path::end()
-        .and(warp::get())
-        .and(cookie::with_user())
+    //DELETE /api/v1/rooms/{id}
+    //Owner only, everyone in the room gets disconnected
+    let delete_room = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(cookie::with_user(cookie_key.clone()))
         .and(db::with(db.clone()))
-        .and_then(endpoint::list_rooms);
+        .and_then(endpoint::delete_room);
 
-    //GET /api/v1/rooms/{id}
-    let get_room = warp::path::param::<String>()
+    //POST /api/v1/rooms/{id}/invites
+    //Owner only, replies with the token to pass as invite
+    let post_invite = warp::path::param::<String>()
+        .and(warp::path("invites"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(cookie::with_user(cookie_key.clone()))
+        .and(db::with(db.clone()))
+        .and(cookie::with(cookie_key.clone()))
+        .and(warp::body::json())
+        .and_then(endpoint::create_invite);
+
+    //GET /api/v1/rooms/{id}/history?before={cursor}&limit={limit}&invite={token}
+    //Newest first, cursor is the next field of the previous page
+    let get_history = warp::path::param::<String>()
+        .and(warp::path("history"))
         .and(warp::path::end())
         .and(warp::get())
-        .and(cookie::with_user())
+        .and(cookie::with_user(cookie_key.clone()))
         .and(db::with(db.clone()))
-        .and_then(endpoint::get_room);
+        .and(spotify::with(spotify))
+        .and(cookie::with(cookie_key.clone()))
+        .and(warp::query::<endpoint::HistoryQuery>())
+        .and_then(endpoint::list_room_history);
 
     warp::path("rooms")
         .and(
             post_room
                 .or(get_rooms)
                 .or(get_room)
+                .or(patch_room)
+                .or(delete_room)
+                .or(post_invite)
+                .or(get_history)
                 .or(warp::path::end().map(|| "room")),
         )
         .boxed()
 }
 
-fn routes_api_search(db: Db, spotify: Spotify) -> BoxedFilter<(impl warp::Reply,)> {
+fn routes_api_search(
+    db: Db,
+    spotify: Spotify,
+    cookie_key: CookieKey,
+) -> BoxedFilter<(impl warp::Reply,)> {
     //GET /api/v1/search?q={query}
     let get_search = warp::path::end()
         .and(warp::get())
-        .and(cookie::with_user())
+        .and(cookie::with_user(cookie_key.clone()))
         .and(db::with(db.clone()))
         .and(spotify::with(spotify.clone()))
         .and(warp::query::<HashMap<String, String>>())
@@ -128,16 +235,52 @@ fn routes_api_search(db: Db, spotify: Spotify) -> BoxedFilter<(impl warp::Reply,
     warp::path("search").and(get_search).boxed()
 }
 
-fn routes_api_queue(db: Db, spotify: Spotify) -> BoxedFilter<(impl warp::Reply, )> {
-    //GET /api/v1/queues/{queue_id}
+fn routes_api_queue(
+    db: Db,
+    spotify: Spotify,
+    cookie_key: CookieKey,
+) -> BoxedFilter<(impl warp::Reply,)> {
+    //GET /api/v1/queues/{queue_id}?invite={token}
     //queue_id same as room_id corresponding to it
     let get_queue = warp::path::param::<String>()
         .and(warp::path::end())
         .and(warp::get())
-        .and(cookie::with_user())
+        .and(cookie::with_user(cookie_key.clone()))
         .and(db::with(db.clone()))
         .and(spotify::with(spotify.clone()))
+        .and(cookie::with(cookie_key.clone()))
+        .and(warp::query::<endpoint::InviteQuery>())
         .and_then(endpoint::list_user_queue);
 
-    warp::path("queues").and(get_queue).boxed()
-}
\ No newline at end of file
+    //DELETE /api/v1/queues/{queue_id}
+    let clear_queue = warp::path::param::<String>()
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(cookie::with_user(cookie_key.clone()))
+        .and(db::with(db.clone()))
+        .and_then(endpoint::clear_user_queue);
+
+    //DELETE /api/v1/queues/{queue_id}/{index}?track_id={track_id}
+    let remove_track = warp::path::param::<String>()
+        .and(warp::path::param::<usize>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(cookie::with_user(cookie_key.clone()))
+        .and(db::with(db.clone()))
+        .and(warp::query::<endpoint::QueuedTrack>())
+        .and_then(endpoint::remove_user_queue_track);
+
+    //PATCH /api/v1/queues/{queue_id}/{index} with {"track_id": ..., "to": ...}
+    let move_track = warp::path::param::<String>()
+        .and(warp::path::param::<usize>())
+        .and(warp::path::end())
+        .and(warp::patch())
+        .and(cookie::with_user(cookie_key))
+        .and(db::with(db))
+        .and(warp::body::json())
+        .and_then(endpoint::move_user_queue_track);
+
+    warp::path("queues")
+        .and(get_queue.or(clear_queue).or(remove_track).or(move_track))
+        .boxed()
+}
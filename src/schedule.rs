@@ -0,0 +1,181 @@
+use crate::db::room::SchedulingPolicy;
+use crate::db::Db;
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use std::collections::HashMap;
+
+//Picks which DJ in the rotation plays next, each room chooses one through its SchedulingPolicy.
+//The room worker takes the picked DJ out of the rotation and puts them at the back once their track starts.
+
+#[async_trait]
+pub trait Scheduler: Send + Sync {
+    //The rotation is in the order users joined or last played, and never empty
+    async fn pick(&self, db: &Db, room_id: String, rotation: &[String]) -> Result<String>;
+}
+
+pub fn scheduler(policy: SchedulingPolicy) -> Box<dyn Scheduler> {
+    match policy {
+        SchedulingPolicy::RoundRobin => Box::new(RoundRobin),
+        SchedulingPolicy::FairShare => Box::new(FairShare),
+        SchedulingPolicy::VoteWeighted => Box::new(VoteWeighted),
+        SchedulingPolicy::Random => Box::new(Random),
+    }
+}
+
+//Everyone takes turns in order
+pub struct RoundRobin;
+
+//Whoever has had the least of their own music played in the room, long tracks use up more of a turn
+pub struct FairShare;
+
+//Random, but every upvote a DJ got for their tracks counts as another ticket
+pub struct VoteWeighted;
+
+pub struct Random;
+
+#[async_trait]
+impl Scheduler for RoundRobin {
+    async fn pick(&self, _db: &Db, _room_id: String, rotation: &[String]) -> Result<String> {
+        Ok(rotation[0].clone())
+    }
+}
+
+#[async_trait]
+impl Scheduler for FairShare {
+    async fn pick(&self, db: &Db, room_id: String, rotation: &[String]) -> Result<String> {
+        let listening_time = db.list_listening_time(room_id).await?;
+        Ok(least_listened(rotation, &listening_time))
+    }
+}
+
+#[async_trait]
+impl Scheduler for VoteWeighted {
+    async fn pick(&self, db: &Db, room_id: String, rotation: &[String]) -> Result<String> {
+        let votes = db.list_dj_votes(room_id).await?;
+        let total: u64 = rotation.iter().map(|user_id| weight(&votes, user_id)).sum();
+        let total = u32::try_from(total).unwrap_or(u32::MAX);
+        let roll = sodiumoxide::randombytes::randombytes_uniform(total);
+
+        Ok(weighted(rotation, &votes, u64::from(roll)))
+    }
+}
+
+#[async_trait]
+impl Scheduler for Random {
+    async fn pick(&self, _db: &Db, _room_id: String, rotation: &[String]) -> Result<String> {
+        let roll = sodiumoxide::randombytes::randombytes_uniform(rotation.len() as u32);
+        Ok(rotation[roll as usize].clone())
+    }
+}
+
+//Ties go to whoever is earlier in the rotation
+fn least_listened(rotation: &[String], listening_time: &HashMap<String, u64>) -> String {
+    rotation
+        .iter()
+        .min_by_key(|user_id| listening_time.get(*user_id).copied().unwrap_or(0))
+        .unwrap()
+        .clone()
+}
+
+fn weight(votes: &HashMap<String, u64>, user_id: &str) -> u64 {
+    1 + votes.get(user_id).copied().unwrap_or(0)
+}
+
+//Roll is below the sum of all weights
+fn weighted(rotation: &[String], votes: &HashMap<String, u64>, mut roll: u64) -> String {
+    for user_id in rotation {
+        let weight = weight(votes, user_id);
+        if roll < weight {
+            return user_id.clone();
+        }
+        roll -= weight;
+    }
+
+    rotation[rotation.len() - 1].clone()
+}
+
+//Credits the DJ of the track that is playing right now
+pub async fn upvote(db: &Db, room_id: String, voter_id: String) -> Result<bool> {
+    let dj_id = db
+        .get_playing(room_id.clone())
+        .await?
+        .filter(|playing| playing.is_playing())
+        .and_then(|playing| playing.user_id)
+        .ok_or_else(|| Error::NotFound("Nothing is playing right now.".to_string()))?;
+
+    if dj_id == voter_id {
+        return Err(Error::Validation(vec![
+            "You can't upvote your own track.".to_string()
+        ]));
+    }
+
+    db.upvote_dj(room_id, dj_id, voter_id).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::playing::Playing;
+
+    fn rotation() -> Vec<String> {
+        vec!["a".to_string(), "b".to_string(), "c".to_string()]
+    }
+
+    #[test]
+    fn test_least_listened_prefers_newcomers() {
+        let listening_time = HashMap::from([("a".to_string(), 300), ("b".to_string(), 200)]);
+        assert_eq!(least_listened(&rotation(), &listening_time), "c");
+    }
+
+    #[test]
+    fn test_least_listened_ties_keep_rotation_order() {
+        let listening_time = HashMap::from([("a".to_string(), 300)]);
+        assert_eq!(least_listened(&rotation(), &listening_time), "b");
+    }
+
+    #[test]
+    fn test_weighted_counts_votes_as_tickets() {
+        //Tickets: a 1, b 3, c 1
+        let votes = HashMap::from([("b".to_string(), 2)]);
+        let picks: Vec<String> = (0..5)
+            .map(|roll| weighted(&rotation(), &votes, roll))
+            .collect();
+        assert_eq!(picks, vec!["a", "b", "b", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_upvote_once_per_track() {
+        let db = crate::db::connect_memory_db(&Default::default());
+        let room_id = "room".to_string();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let playing = Playing {
+            user_id: Some("dj".to_string()),
+            track_id: "spotify:track:one".to_string(),
+            start_time: now,
+            length: 60_000,
+            autoplay: false,
+        };
+        db.set_playing(room_id.clone(), playing).await.unwrap();
+
+        assert!(upvote(&db, room_id.clone(), "fan".to_string())
+            .await
+            .unwrap());
+        assert!(!upvote(&db, room_id.clone(), "fan".to_string())
+            .await
+            .unwrap());
+        assert!(upvote(&db, room_id.clone(), "dj".to_string())
+            .await
+            .is_err());
+
+        //A new track lets everyone vote again
+        db.clear_upvoters(room_id.clone()).await.unwrap();
+        assert!(upvote(&db, room_id.clone(), "fan".to_string())
+            .await
+            .unwrap());
+        let votes = db.list_dj_votes(room_id).await.unwrap();
+        assert_eq!(votes.get("dj"), Some(&2));
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use warp::Filter;
+
+//Owned by main, tells every Shutdown handle to wrap up and waits until all of them are dropped
+pub struct Controller {
+    signal: watch::Sender<bool>,
+    done_tx: mpsc::Sender<()>,
+    done_rx: mpsc::Receiver<()>,
+}
+
+//Held by long running tasks (room workers, sockets) until they have cleaned up after themselves
+#[derive(Clone)]
+pub struct Shutdown {
+    signal: watch::Receiver<bool>,
+    done: Option<mpsc::Sender<()>>,
+}
+
+//Doesn't hold shutdown up, for things that only hand out handles like the route filters
+#[derive(Clone)]
+pub struct WeakShutdown {
+    signal: watch::Receiver<bool>,
+    done: Option<mpsc::WeakSender<()>>,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        let (signal, _) = watch::channel(false);
+        let (done_tx, done_rx) = mpsc::channel(1);
+
+        Self {
+            signal,
+            done_tx,
+            done_rx,
+        }
+    }
+
+    pub fn handle(&self) -> Shutdown {
+        Shutdown {
+            signal: self.signal.subscribe(),
+            done: Some(self.done_tx.clone()),
+        }
+    }
+
+    //Returns false if some task didn't finish cleaning up in time
+    pub async fn shutdown(self, timeout: Duration) -> bool {
+        let Self {
+            signal,
+            done_tx,
+            mut done_rx,
+        } = self;
+
+        signal.send_replace(true);
+        drop(done_tx);
+
+        //Nothing is ever sent, recv only returns once every handle is gone
+        tokio::time::timeout(timeout, done_rx.recv()).await.is_ok()
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn downgrade(&self) -> WeakShutdown {
+        WeakShutdown {
+            signal: self.signal.clone(),
+            done: self.done.as_ref().map(mpsc::Sender::downgrade),
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.signal.borrow()
+    }
+
+    pub async fn recv(&mut self) {
+        while !*self.signal.borrow_and_update() {
+            if self.signal.changed().await.is_err() {
+                //Controller was dropped without shutting down, so this never happens
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+impl WeakShutdown {
+    //Once shutdown is done the handle won't hold anything up, but still reports shutting down
+    pub fn upgrade(&self) -> Shutdown {
+        Shutdown {
+            signal: self.signal.clone(),
+            done: self.done.as_ref().and_then(mpsc::WeakSender::upgrade),
+        }
+    }
+}
+
+pub fn with(
+    shutdown: WeakShutdown,
+) -> impl Filter<Extract = (Shutdown,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || shutdown.upgrade())
+}
+
+//Resolves on the first SIGINT or SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_waits_for_handles() {
+        let controller = Controller::new();
+        let mut handle = controller.handle();
+
+        let task = tokio::task::spawn(async move {
+            handle.recv().await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            drop(handle);
+        });
+
+        assert!(controller.shutdown(Duration::from_secs(5)).await);
+        assert!(task.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_weak_handles_dont_hold_shutdown_up() {
+        let controller = Controller::new();
+        let weak = controller.handle().downgrade();
+
+        assert!(controller.shutdown(Duration::from_secs(5)).await);
+        assert!(weak.upgrade().is_shutting_down());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown_gives_up_after_timeout() {
+        let controller = Controller::new();
+        let _stuck = controller.handle();
+
+        assert!(!controller.shutdown(Duration::from_secs(5)).await);
+    }
+}
@@ -1,16 +1,42 @@
+use crate::admission;
+use crate::config::{Config, RoomConfig};
+use crate::cooldown;
 use crate::db;
 use crate::db::message::Message;
+use crate::db::room::RoomMode;
+use crate::error::{self, Error};
+use crate::janitor;
+use crate::metrics::{GaugeGuard, METRICS};
+use crate::moderation;
+use crate::pool;
+use crate::queue;
+use crate::schedule;
+use crate::shutdown::Shutdown;
 use crate::spotify;
 use futures_util::{SinkExt, StreamExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::Instrument;
 use warp::ws::WebSocket;
 
+//Tells apart several connections of the same user in the logs
+static CONNECTION_IDS: AtomicU64 = AtomicU64::new(1);
+
+#[tracing::instrument(
+    name = "socket",
+    skip_all,
+    fields(room_id = %room_id, user_id = %user_id, connection_id = CONNECTION_IDS.fetch_add(1, Ordering::Relaxed))
+)]
 pub async fn connected(
     ws: WebSocket,
     room_id: String,
     user_id: String,
     db: db::Db,
     spotify: spotify::Spotify,
+    config: Config,
+    mut shutdown: Shutdown,
 ) {
+    METRICS.websocket_connections.inc();
+    let _active = GaugeGuard::inc(&METRICS.websockets_active);
     let (mut ws_tx, mut ws_rx) = ws.split();
 
     //Send out messages to the client
@@ -19,13 +45,29 @@ pub async fn connected(
     let inner_user_id = user_id.clone();
     let (kill_db_tx, mut kill_db_rx) = tokio::sync::mpsc::channel(1);
     let (system_tx, mut system_rx) = tokio::sync::mpsc::channel(1);
+    //Stops receiving from the client once they're kicked or the room is gone
+    let (leave_tx, mut leave_rx) = tokio::sync::mpsc::channel(1);
+    let mut outgoing_shutdown = shutdown.clone();
     tokio::task::spawn(async move {
-        let mut db = inner_db.lock().await;
-        let mut db_rx = db.subscribe_messages(inner_room_id.clone()).await;
-        std::mem::drop(db);
+        let mut db_rx = match inner_db.subscribe_messages(inner_room_id.clone()).await {
+            Ok(db_rx) => db_rx,
+            Err(err) => {
+                tracing::error!("Failed to subscribe to room {}: {}", inner_room_id, err);
+                return;
+            }
+        };
 
         loop {
             tokio::select! {
+                //Checked first so the goodbye isn't lost to kill_db_rx when both are ready
+                biased;
+                _ = outgoing_shutdown.recv() => {
+                    let message = system_message("Server is shutting down, please rejoin the room in a moment.".to_string());
+                    let json = serde_json::to_string(&message).unwrap();
+                    ws_tx.send(warp::ws::Message::text(json)).await.unwrap_or(());
+                    ws_tx.close().await.unwrap_or(());
+                    break;
+                },
                 msg = db_rx.recv() => {
                     match msg {
                         Some(message) => {
@@ -41,31 +83,76 @@ pub async fn connected(
                                     ws_tx.send(warp::ws::Message::text(json)).await.unwrap();
                                 },
                                 db::message::MessageType::MessagePresencesChanged | db::message::MessageType::MessageQueueChanged => {
-                                    let mut db = inner_db.lock().await;
-                                    let presences = db.list_presences(inner_room_id.clone()).await;
-                                    let queue = db.list_queue(inner_room_id.clone()).await;
-                                    std::mem::drop(db);
+                                    let presences = inner_db.list_presences(inner_room_id.clone()).await;
+                                    let queue = inner_db.list_queue(inner_room_id.clone()).await;
+                                    let pool = inner_db.list_pool(inner_room_id.clone()).await;
+                                    let playing = inner_db.get_playing(inner_room_id.clone()).await;
+                                    let (presences, queue, pool, playing) = match (presences, queue, pool, playing) {
+                                        (Ok(presences), Ok(queue), Ok(pool), Ok(playing)) => (presences, queue, pool, playing),
+                                        (Err(err), _, _, _) | (_, Err(err), _, _) | (_, _, Err(err), _) | (_, _, _, Err(err)) => {
+                                            tracing::error!("Failed to list room {}: {}", inner_room_id, err);
+                                            continue;
+                                        }
+                                    };
+
+                                    let roles = match moderation::roles(&inner_db, inner_room_id.clone()).await {
+                                        Ok(roles) => roles,
+                                        Err(err) => {
+                                            tracing::error!("Failed to list roles of room {}: {}", inner_room_id, err);
+                                            continue;
+                                        }
+                                    };
 
                                     let data = data_out::PresencesQueueMessage {
                                         queue,
-                                        presences
+                                        presences,
+                                        pool,
+                                        playing: playing.map(data_out::NowPlaying::from),
+                                        roles,
                                     };
                                     let message = data_out::Message::PresencesQueueMessage(data);
                                     let json = serde_json::to_string(&message).unwrap();
                                     ws_tx.send(warp::ws::Message::text(json)).await.unwrap();
                                 },
-                                db::message::MessageType::MessageUserQueueChanged(data) => {
-                                    if data.user_id == inner_user_id.clone() {
-                                        let message = data_out::Message::UserQueueChange;
-                                        let json = serde_json::to_string(&message).unwrap();
-                                        ws_tx.send(warp::ws::Message::text(json)).await.unwrap();
-                                    }
+                                db::message::MessageType::MessageUserQueueChanged(data) if data.user_id == inner_user_id => {
+                                    let message = data_out::Message::UserQueueChange;
+                                    let json = serde_json::to_string(&message).unwrap();
+                                    ws_tx.send(warp::ws::Message::text(json)).await.unwrap();
+                                },
+                                db::message::MessageType::MessageRoomDeleted => {
+                                    let message = system_message("This room was deleted.".to_string());
+                                    let json = serde_json::to_string(&message).unwrap();
+                                    ws_tx.send(warp::ws::Message::text(json)).await.unwrap_or(());
+                                    ws_tx.close().await.unwrap_or(());
+                                    leave_tx.send(()).await.unwrap_or(());
+                                    break;
+                                },
+                                db::message::MessageType::MessageKicked(data) if data.user_id == inner_user_id => {
+                                    let message = if data.banned {
+                                        "You were banned from this room."
+                                    } else {
+                                        "You were removed from this room."
+                                    };
+                                    let json = serde_json::to_string(&system_message(message.to_string())).unwrap();
+                                    ws_tx.send(warp::ws::Message::text(json)).await.unwrap_or(());
+                                    ws_tx.close().await.unwrap_or(());
+                                    leave_tx.send(()).await.unwrap_or(());
+                                    break;
+                                },
+                                db::message::MessageType::MessageRoleChanged(data) => {
+                                    let data = data_out::RoleChange {
+                                        user_id: data.user_id,
+                                        role: data.role,
+                                    };
+                                    let message = data_out::Message::RoleChange(data);
+                                    let json = serde_json::to_string(&message).unwrap();
+                                    ws_tx.send(warp::ws::Message::text(json)).await.unwrap();
                                 },
                                 _ => ()
                             }
                         },
                         _ => {
-                            log::info!("Database subscription in handle_chat_connected died");
+                            tracing::info!("Database subscription in handle_chat_connected died");
                             break;
                         }
                     }
@@ -86,89 +173,165 @@ pub async fn connected(
                 },
             };
         }
-    });
+    }.instrument(tracing::Span::current()));
+
+    let exists = match db.exists_room(room_id.clone()).await {
+        Ok(exists) => exists,
+        Err(err) => {
+            tracing::error!("Failed to look up room {}: {}", room_id, err);
+            false
+        }
+    };
+
+    let banned = exists
+        && match db.is_banned(room_id.clone(), user_id.clone()).await {
+            Ok(banned) => banned,
+            Err(err) => {
+                tracing::error!("Failed to look up bans of room {}: {}", room_id, err);
+                true
+            }
+        };
 
-    let mut inner_db = db.lock().await;
-    if inner_db.exists_room(room_id.clone()).await {
-        inner_db.offer_room(room_id.clone()).await;
-        std::mem::drop(inner_db);
+    if exists && !banned {
+        match janitor::visited(&db, room_id.clone(), user_id.clone()).await {
+            Ok(notices) => {
+                for notice in notices {
+                    if !send_system(&system_tx, system_message(notice)).await {
+                        break;
+                    }
+                }
+            }
+            Err(err) => tracing::error!("Failed to note visit to room {}: {}", room_id, err),
+        }
+        if let Err(err) = db.offer_room(room_id.clone()).await {
+            tracing::error!("Failed to offer room {}: {}", room_id, err);
+        }
 
         //Track user presence, own task in case ws task dies
         let (kill_presence_tx, mut kill_presence_rx) = tokio::sync::mpsc::channel(1);
         let inner_db = db.clone();
         let inner_user_id = user_id.clone();
         let inner_room_id = room_id.clone();
+        //Keeps shutdown waiting until the presence is removed
+        let presence_shutdown = shutdown.clone();
         tokio::task::spawn(async move {
-            let mut db = inner_db.lock().await;
-            db.add_presence(inner_room_id.clone(), inner_user_id.clone())
-                .await;
-            std::mem::drop(db);
+            let _presence_shutdown = presence_shutdown;
+            if let Err(err) = inner_db
+                .add_presence(inner_room_id.clone(), inner_user_id.clone())
+                .await
+            {
+                tracing::error!("Failed to add presence of {}: {}", inner_user_id, err);
+            }
 
             loop {
-                let duration = tokio::time::Duration::from_secs(3);
+                let duration = config.timings.presence_heartbeat();
                 tokio::select! {
                     exit = kill_presence_rx.recv() => {
-                        match exit {
-                            None => {
-                                log::info!("User presence removed because task exited unexepctedly");
-                            },
-                            _ => (),
+                        if exit.is_none() {
+                            tracing::info!("User presence removed because task exited unexepctedly");
                         }
                         break;
                     },
                     _ = tokio::time::sleep(duration) => {
-                        let mut db = inner_db.lock().await;
-                        db.keep_alive_presence(inner_room_id.clone(), inner_user_id.clone()).await;
+                        if let Err(err) = inner_db.keep_alive_presence(inner_room_id.clone(), inner_user_id.clone()).await {
+                            tracing::error!("Failed to keep presence of {} alive: {}", inner_user_id, err);
+                        }
                     }
                 }
             }
 
-            let mut db = inner_db.lock().await;
-            db.remove_presence(inner_room_id.clone(), inner_user_id.clone())
-                .await;
-        });
+            if let Err(err) = inner_db
+                .remove_presence(inner_room_id.clone(), inner_user_id.clone())
+                .await
+            {
+                tracing::error!("Failed to remove presence of {}: {}", inner_user_id, err);
+            }
+        }.instrument(tracing::Span::current()));
 
         //Receive messages from the client
-        while let Some(result) = ws_rx.next().await {
+        loop {
+            let result = tokio::select! {
+                result = ws_rx.next() => result,
+                _ = shutdown.recv() => break,
+                Some(()) = leave_rx.recv() => break,
+            };
+            let Some(result) = result else {
+                break;
+            };
             let message_ws = match result {
                 Ok(msg) => msg,
                 Err(e) => {
-                    log::debug!("Websocket Error {}", e);
+                    tracing::debug!("Websocket Error {}", e);
                     break;
                 }
             };
             if message_ws.is_text() {
-                on_message(
+                let result = on_message(
                     &system_tx,
                     db.clone(),
                     room_id.clone(),
                     user_id.clone(),
                     spotify.clone(),
+                    &config.rooms,
                     message_ws.to_str().unwrap().to_string(),
                 )
                 .await;
+
+                if let Err(err) = result {
+                    tracing::warn!("Failed to handle message from {}: {}", user_id, err);
+                    //Let the user know what they got wrong, anything else is on us
+                    if err.status().is_client_error() {
+                        for message in err.messages() {
+                            if !send_system(&system_tx, system_message(message)).await {
+                                break;
+                            }
+                        }
+                    }
+                }
+                //Nothing more can reach the user once the outgoing task is gone
+                if system_tx.is_closed() {
+                    break;
+                }
             } else {
-                log::debug!("Websocket non-text message {:?}", message_ws);
+                tracing::debug!("Websocket non-text message {:?}", message_ws);
             }
         }
 
         kill_presence_tx.send(()).await.unwrap();
     } else {
-        let message_text = format!("Room {} does not exist!", room_id);
-        let data = data_out::ChatMessage{
-            id: "".to_string(),
-            from: "system".to_string(),
-            message: message_text,
+        let message = if banned {
+            system_message("You're banned from this room.".to_string())
+        } else {
+            system_message(format!("Room {} does not exist!", room_id))
         };
-        let message = data_out::Message::ChatMessage(data);
 
-        system_tx
-            .send(message)
-            .await
-            .unwrap();
+        send_system(&system_tx, message).await;
     }
 
-    kill_db_tx.send(()).await.unwrap();
+    //The outgoing task might already be gone, e.g. after saying goodbye on shutdown
+    kill_db_tx.send(()).await.unwrap_or(());
+}
+
+//Returns whether the message was handed to the outgoing task, which is gone once the socket is
+async fn send_system(
+    system_tx: &tokio::sync::mpsc::Sender<data_out::Message>,
+    message: data_out::Message,
+) -> bool {
+    if system_tx.send(message).await.is_err() {
+        tracing::debug!("Dropped a system message, the socket is already closed");
+        return false;
+    }
+    true
+}
+
+fn system_message(message: String) -> data_out::Message {
+    let data = data_out::ChatMessage {
+        id: "".to_string(),
+        from: "system".to_string(),
+        message,
+    };
+
+    data_out::Message::ChatMessage(data)
 }
 
 async fn on_message(
@@ -176,48 +339,154 @@ async fn on_message(
     db: db::Db,
     room_id: String,
     user_id: String,
-    _spotify: spotify::Spotify,
+    spotify: spotify::Spotify,
+    rooms: &RoomConfig,
     message: String,
-) {
-    let message: data_in::Message = serde_json::from_str(&message).unwrap();
+) -> error::Result<()> {
+    let message: data_in::Message = serde_json::from_str(&message)
+        .map_err(|err| Error::Decode(format!("client message: {}", err)))?;
 
     match message {
         data_in::Message::ChatMessage(chat_message) => {
+            moderation::check_can_chat(&db, room_id.clone(), user_id.clone()).await?;
             let message = Message::chat_message(user_id.clone(), chat_message.message);
 
-            let mut db = db.lock().await;
-            db.add_message(room_id.clone(), message).await;
+            db.add_message(room_id.clone(), message).await?;
         }
         data_in::Message::SetDevice(set_device) => {
             let message = Message::device_change(user_id.clone());
 
-            let mut db = db.lock().await;
-            db.set_device(user_id, set_device.device_id).await;
-            db.add_message(room_id, message).await;
+            db.set_device(user_id, set_device.device_id).await?;
+            db.add_message(room_id, message).await?;
         }
         data_in::Message::QueueSong(queue_song) => {
-            let mut db = db.lock().await;
-            db.push_user_queue(room_id.clone(), user_id.clone(), queue_song.track_id)
-                .await;
+            moderation::check_can_queue(&db, room_id.clone(), user_id.clone()).await?;
+            //Both checks need the track, looked up once for them
+            let token = db.require_auth(user_id.clone()).await?;
+            let track = spotify
+                .request_track(token, queue_song.track_id.clone())
+                .await?;
+            admission::check(&db, room_id.clone(), &track).await?;
+            cooldown::check(
+                &db,
+                room_id.clone(),
+                user_id.clone(),
+                queue_song.track_id.clone(),
+                &track,
+                rooms.duplicate_cooldown(),
+            )
+            .await?;
+            if let Some(isrc) = track.external_ids.isrc {
+                db.set_isrc(room_id.clone(), queue_song.track_id.clone(), isrc)
+                    .await?;
+            }
+
+            match pool::mode(&db, room_id.clone()).await? {
+                RoomMode::Rotation => {
+                    db.push_user_queue(room_id.clone(), user_id.clone(), queue_song.track_id)
+                        .await?;
+                }
+                RoomMode::Democratic => {
+                    pool::add_track(&db, room_id, user_id, queue_song.track_id).await?;
+                }
+            }
+        }
+        data_in::Message::VotePoolTrack(vote) => {
+            pool::vote(&db, room_id, user_id, vote.id, vote.vote).await?;
+        }
+        data_in::Message::RemovePoolTrack(remove) => {
+            pool::remove_track(&db, room_id, user_id, remove.id).await?;
+        }
+        data_in::Message::RemoveQueuedTrack(remove) => {
+            queue::remove_track(&db, room_id, user_id, remove.index, remove.track_id).await?;
+        }
+        data_in::Message::MoveQueuedTrack(move_track) => {
+            queue::move_track(
+                &db,
+                room_id,
+                user_id,
+                move_track.index,
+                move_track.to,
+                move_track.track_id,
+            )
+            .await?;
+        }
+        data_in::Message::ClearMyQueue => {
+            queue::clear(&db, room_id, user_id).await?;
+        }
+        data_in::Message::StepDown => {
+            if queue::step_down(&db, room_id.clone(), user_id).await? {
+                db.add_message(room_id, Message::queue_changed()).await?;
+            } else {
+                let message = system_message(
+                    "You'll leave the queue once the current track has played.".to_string(),
+                );
+                send_system(system_tx, message).await;
+            }
+        }
+        data_in::Message::LeaveQueue => {
+            queue::leave_rotation(&db, room_id.clone(), user_id).await?;
+            db.add_message(room_id, Message::queue_changed()).await?;
+        }
+        data_in::Message::VoteSkip => {
+            let playing = db.get_playing(room_id.clone()).await?;
+            if !playing.is_some_and(|playing| playing.is_playing()) {
+                return Err(Error::NotFound("Nothing is playing right now.".to_string()));
+            }
+
+            if db.add_skip_vote(room_id.clone(), user_id).await? {
+                db.add_message(room_id, Message::skip_vote()).await?;
+            }
+        }
+        data_in::Message::Upvote => {
+            if !schedule::upvote(&db, room_id, user_id).await? {
+                let message = system_message("You already upvoted this track.".to_string());
+                send_system(system_tx, message).await;
+            }
         }
         data_in::Message::KeepAlivePing(ping) => {
-            let data = data_out::KeepAlivePong {
-                data: ping.data
-            };
+            let data = data_out::KeepAlivePong { data: ping.data };
             let message = data_out::Message::KeepAlivePong(data);
 
-            system_tx.send(message).await.unwrap();
+            send_system(system_tx, message).await;
         }
         data_in::Message::JoinQueue => {
-            let mut db = db.lock().await;
-            db.rem_queue(room_id.clone(), user_id.clone()).await;
-            db.push_queue(room_id.clone(), user_id.clone()).await;
-            db.add_message(room_id, Message::queue_changed()).await;
+            if pool::mode(&db, room_id.clone()).await? == RoomMode::Democratic {
+                return Err(Error::Validation(vec![
+                    "This room plays whatever gets the most votes, there's no DJ queue to join."
+                        .to_string(),
+                ]));
+            }
+            moderation::check_can_queue(&db, room_id.clone(), user_id.clone()).await?;
+            //Also takes back stepping down
+            queue::leave_rotation(&db, room_id.clone(), user_id.clone()).await?;
+            db.push_queue(room_id.clone(), user_id.clone()).await?;
+            db.add_message(room_id, Message::queue_changed()).await?;
+        }
+        data_in::Message::Kick(target) => {
+            moderation::kick(&db, room_id, user_id, target.user_id).await?;
+        }
+        data_in::Message::Ban(target) => {
+            moderation::ban(&db, room_id, user_id, target.user_id).await?;
+        }
+        data_in::Message::Unban(target) => {
+            moderation::unban(&db, room_id, user_id, target.user_id).await?;
+        }
+        data_in::Message::Mute(target) => {
+            moderation::mute(&db, room_id, user_id, target.user_id, true).await?;
+        }
+        data_in::Message::Unmute(target) => {
+            moderation::mute(&db, room_id, user_id, target.user_id, false).await?;
+        }
+        data_in::Message::SetRole(set_role) => {
+            moderation::set_role(&db, room_id, user_id, set_role.user_id, set_role.role).await?;
         }
     };
+
+    Ok(())
 }
 
-mod data_out {
+pub(crate) mod data_out {
     use serde::{Deserialize, Serialize};
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -231,6 +500,33 @@ mod data_out {
     pub struct PresencesQueueMessage {
         pub queue: Vec<String>,
         pub presences: Vec<String>,
+        //Only used by democratic rooms, oldest first
+        pub pool: Vec<crate::db::pool::PoolTrack>,
+        pub playing: Option<NowPlaying>,
+        //Everyone who isn't a plain DJ
+        pub roles: std::collections::HashMap<String, crate::db::moderation::Role>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct NowPlaying {
+        pub track_id: String,
+        //Missing for autoplay tracks
+        pub user_id: Option<String>,
+        pub autoplay: bool,
+        pub start_time: u64,
+        pub length: u64,
+    }
+
+    impl From<crate::db::playing::Playing> for NowPlaying {
+        fn from(playing: crate::db::playing::Playing) -> Self {
+            NowPlaying {
+                track_id: playing.track_id,
+                user_id: playing.user_id,
+                autoplay: playing.autoplay,
+                start_time: playing.start_time as u64,
+                length: playing.length,
+            }
+        }
     }
 
     #[derive(Debug, Serialize, Deserialize)]
@@ -238,12 +534,19 @@ mod data_out {
         pub data: String,
     }
 
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct RoleChange {
+        pub user_id: String,
+        pub role: crate::db::moderation::Role,
+    }
+
     #[derive(Debug, Serialize, Deserialize)]
     pub enum Message {
         ChatMessage(ChatMessage),
         PresencesQueueMessage(PresencesQueueMessage),
         KeepAlivePong(KeepAlivePong),
         UserQueueChange,
+        RoleChange(RoleChange),
     }
 }
 
@@ -265,17 +568,73 @@ mod data_in {
         pub track_id: String,
     }
 
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct RemoveQueuedTrack {
+        pub index: usize,
+        pub track_id: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct MoveQueuedTrack {
+        pub index: usize,
+        pub to: usize,
+        pub track_id: String,
+    }
+
+    //Vote is 1 or -1, 0 takes it back
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct VotePoolTrack {
+        pub id: u64,
+        pub vote: i8,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct RemovePoolTrack {
+        pub id: u64,
+    }
+
     #[derive(Debug, Serialize, Deserialize)]
     pub struct KeepAlivePing {
         pub data: String,
     }
 
+    //Who a moderator is kicking, banning or muting
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct TargetUser {
+        pub user_id: String,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct SetRole {
+        pub user_id: String,
+        pub role: crate::db::moderation::Role,
+    }
+
     #[derive(Debug, Serialize, Deserialize)]
     pub enum Message {
         ChatMessage(ChatMessage),
         SetDevice(SetDevice),
         QueueSong(QueueSong),
+        RemoveQueuedTrack(RemoveQueuedTrack),
+        MoveQueuedTrack(MoveQueuedTrack),
+        ClearMyQueue,
+        VotePoolTrack(VotePoolTrack),
+        RemovePoolTrack(RemovePoolTrack),
         KeepAlivePing(KeepAlivePing),
         JoinQueue,
+        LeaveQueue,
+        //Leaves the queue once the track playing now ends, or right away if nothing is playing
+        StepDown,
+        //For the DJ of the track that is playing, counts in vote weighted rooms
+        Upvote,
+        //Skips the playing track once enough of the room agrees, see RoomConfig
+        VoteSkip,
+        //Moderation, see crate::moderation
+        Kick(TargetUser),
+        Ban(TargetUser),
+        Unban(TargetUser),
+        Mute(TargetUser),
+        Unmute(TargetUser),
+        SetRole(SetRole),
     }
 }
@@ -1,22 +1,27 @@
+use crate::config::SpotifyConfig;
 use crate::db::{auth::Auth, Db};
-use reqwest::Client;
+use crate::error::{Error, Result};
+use crate::metrics::METRICS;
+use reqwest::{Client, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
 use warp::Filter;
 
 mod auth;
+mod budget;
 mod me;
 mod play;
+mod recommendations;
 mod search;
-mod tracks;
+pub mod tracks;
 pub mod util;
 
-pub type Spotify = Arc<Mutex<SpotifyInternal>>;
+pub type Spotify = Arc<SpotifyInternal>;
 
-pub fn init(db: Db) -> Spotify {
-    Arc::new(Mutex::new(SpotifyInternal::init(db)))
+pub fn init(db: Db, config: &SpotifyConfig) -> Spotify {
+    Arc::new(SpotifyInternal::init(db, config.clone()))
 }
 
 pub fn with(
@@ -26,59 +31,149 @@ pub fn with(
 }
 
 pub struct SpotifyInternal {
-    client_id: String,
-    client_secret: String,
+    config: SpotifyConfig,
     http_client: Client,
     db: Db,
+    budget: budget::Budget,
 }
 
 impl SpotifyInternal {
-    fn init(db: Db) -> Self {
-        let client_id = std::env::var("SPOTIFY_CLIENT_ID").unwrap();
-        let client_secret = std::env::var("SPOTIFY_CLIENT_SECRET").unwrap();
-
+    fn init(db: Db, config: SpotifyConfig) -> Self {
         let http_client = reqwest::ClientBuilder::new()
             .connection_verbose(true)
             .build()
             .unwrap();
 
+        let budget = budget::Budget::new(
+            config.budget_window(),
+            config.global_budget,
+            config.token_budget,
+        );
+
         Self {
-            client_id,
-            client_secret,
+            config,
             http_client,
             db,
+            budget,
         }
     }
 
-    async fn request<T: DeserializeOwned>(&self, request: impl SpotifyRequest) -> Option<T> {
-        let response = self.perform_request(&request).await;
+    #[tracing::instrument(name = "spotify", skip_all, fields(endpoint = request.name()))]
+    async fn request<T: DeserializeOwned>(
+        &self,
+        request: impl SpotifyRequest,
+    ) -> Result<Option<T>> {
+        let token = request.token();
+        let response = self.send(&request, token.clone()).await?;
 
-        let response = if request.token().is_some() && response.status() == 401 {
-            //Bad or expired token, try refreshing it
-            log::info!("Refreshing token!!!!!");
-            self.refresh_token(request.token().unwrap()).await;
-            self.perform_request(&request).await
-        } else {
-            response
+        let response = match token {
+            Some(token) if response.status() == StatusCode::UNAUTHORIZED => {
+                //Bad or expired token, try refreshing it
+                tracing::info!("Access token was rejected, refreshing it");
+                let token = self.refresh_token(token).await;
+                let outcome = if token.is_ok() { "ok" } else { "error" };
+                METRICS
+                    .spotify_token_refreshes
+                    .with_label_values(&[outcome])
+                    .inc();
+                let token = token?;
+                self.send(&request, Some(token)).await?
+            }
+            _ => response,
         };
 
         if request.has_result() {
-            let text = response.text().await.unwrap();
-            let json = serde_json::from_str(&text).or_else(|err| {
-                log::debug!("{}", text);
-                Err(err)
-            });
-
-            Some(json.unwrap())
+            Ok(Some(Self::read_response(response).await?))
         } else {
-            None
+            Self::check_status(response).await?;
+            Ok(None)
+        }
+    }
+
+    async fn request_json<T: DeserializeOwned>(&self, request: impl SpotifyRequest) -> Result<T> {
+        self.request(request)
+            .await?
+            .ok_or_else(|| Error::Decode("Spotify returned no response body".to_string()))
+    }
+
+    //Retries rate limits, server errors and network errors, anything else is up to the caller
+    async fn send(
+        &self,
+        request: &impl SpotifyRequest,
+        token: Option<Auth>,
+    ) -> Result<reqwest::Response> {
+        //Refreshing changes the access token but not the user, so budget by user when we know them
+        let budget_key = token
+            .as_ref()
+            .map(|token| token.user_id.clone().unwrap_or(token.access_token.clone()));
+        let mut attempt = 0;
+
+        loop {
+            self.budget.spend(budget_key.as_deref())?;
+            let result = self.perform_request(request, token.clone()).await;
+
+            let delay = match &result {
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    let retry_after =
+                        Self::retry_after(response).unwrap_or_else(|| self.backoff(attempt));
+                    if attempt >= self.config.max_retries
+                        || retry_after > self.config.max_retry_after()
+                    {
+                        return Err(Error::RateLimited { retry_after });
+                    }
+                    retry_after
+                }
+                Ok(response) if response.status().is_server_error() => self.backoff(attempt),
+                Ok(_) => return result,
+                Err(_) => self.backoff(attempt),
+            };
+
+            if attempt >= self.config.max_retries {
+                return result;
+            }
+            match &result {
+                Ok(response) => tracing::warn!(
+                    "Spotify responded with {}, retrying in {}ms",
+                    response.status(),
+                    delay.as_millis()
+                ),
+                Err(err) => tracing::warn!("{}, retrying in {}ms", err, delay.as_millis()),
+            }
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
         }
     }
 
-    async fn perform_request(&self, request: &impl SpotifyRequest) -> reqwest::Response {
+    //Exponential with jitter, so everyone who failed at once doesn't retry at once
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.config.retry_base_delay() * 2u32.saturating_pow(attempt);
+        let half = u32::try_from(delay.as_millis() / 2).unwrap_or(u32::MAX);
+        let jitter = sodiumoxide::randombytes::randombytes_uniform(half.max(1));
+
+        Duration::from_millis(u64::from(half) + u64::from(jitter))
+    }
+
+    fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+        let seconds = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()?;
+
+        Some(Duration::from_secs(seconds))
+    }
+
+    async fn perform_request(
+        &self,
+        request: &impl SpotifyRequest,
+        token: Option<Auth>,
+    ) -> Result<reqwest::Response> {
         let http_client = &self.http_client;
 
-        let endpoint = request.endpoint();
+        let endpoint = request.endpoint(&self.config);
         let mut builder = match request.method() {
             SpotifyMethod::Get => http_client.get(endpoint),
             SpotifyMethod::Post => http_client.post(endpoint),
@@ -86,10 +181,13 @@ impl SpotifyInternal {
         };
 
         if request.basic_auth() {
-            builder = builder.basic_auth(self.client_id.clone(), Some(self.client_secret.clone()))
+            builder = builder.basic_auth(
+                self.config.client_id.clone(),
+                Some(self.config.client_secret.clone()),
+            )
         }
 
-        if let Some(token) = request.token() {
+        if let Some(token) = token {
             builder = builder.bearer_auth(token.access_token);
         }
 
@@ -101,18 +199,67 @@ impl SpotifyInternal {
             builder = builder.json(&data);
         }
 
-        builder.send().await.unwrap()
+        let timer = METRICS
+            .spotify_request_duration
+            .with_label_values(&[request.name()])
+            .start_timer();
+        let response = builder.send().await;
+        timer.observe_duration();
+
+        let status = match &response {
+            Ok(response) => response.status().as_u16().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        tracing::debug!(status = %status, "Spotify responded to {}", request.name());
+        METRICS
+            .spotify_requests
+            .with_label_values(&[request.name(), &status])
+            .inc();
+
+        Ok(response?)
     }
 
-    async fn refresh_token(&self, token: Auth) {
-        let data = Self::request_auth_refresh(&self, token.refresh_token.clone()).await;
+    async fn check_status(response: reqwest::Response) -> Result<reqwest::Response> {
+        let status = response.status();
 
+        if status.is_success() {
+            Ok(response)
+        } else {
+            let message = response.text().await.unwrap_or_default();
+            Err(Error::Spotify {
+                status: status.as_u16(),
+                message,
+            })
+        }
+    }
+
+    async fn read_response<T: DeserializeOwned>(response: reqwest::Response) -> Result<T> {
+        let text = Self::check_status(response).await?.text().await?;
+
+        serde_json::from_str(&text).map_err(|err| {
+            tracing::debug!("{}", text);
+            Error::Decode(format!("Spotify response: {}", err))
+        })
+    }
+
+    async fn refresh_token(&self, token: Auth) -> Result<Auth> {
+        let data = Self::request_auth_refresh(self, token.refresh_token.clone()).await?;
+
+        let user_id = token
+            .user_id
+            .ok_or_else(|| Error::Auth("Can't refresh a token without a user".to_string()))?;
         let access_token = data.access_token;
         let refresh_token = data.refresh_token.unwrap_or(token.refresh_token);
 
-        let mut db = self.db.lock().await;
-        db.set_auth(token.user_id.unwrap(), access_token, refresh_token)
-            .await;
+        self.db
+            .set_auth(user_id.clone(), access_token.clone(), refresh_token.clone())
+            .await?;
+
+        Ok(Auth {
+            user_id: Some(user_id),
+            access_token,
+            refresh_token,
+        })
     }
 }
 
@@ -125,7 +272,9 @@ enum SpotifyMethod {
 trait SpotifyRequest {
     type JSONDataType: Serialize;
 
-    fn endpoint(&self) -> String;
+    //Label for metrics, the endpoint itself contains ids
+    fn name(&self) -> &'static str;
+    fn endpoint(&self, config: &SpotifyConfig) -> String;
     fn method(&self) -> SpotifyMethod;
     fn basic_auth(&self) -> bool;
     fn token(&self) -> Option<Auth>;
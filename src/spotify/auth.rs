@@ -1,4 +1,6 @@
+use crate::config::SpotifyConfig;
 use crate::db::auth::Auth;
+use crate::error::Result;
 use crate::spotify;
 use serde::{Deserialize, Serialize};
 
@@ -10,8 +12,12 @@ struct SpotifyRequestAuthNew {
 impl spotify::SpotifyRequest for SpotifyRequestAuthNew {
     type JSONDataType = ();
 
-    fn endpoint(&self) -> String {
-        "https://accounts.spotify.com/api/token".to_string()
+    fn name(&self) -> &'static str {
+        "auth_new"
+    }
+
+    fn endpoint(&self, config: &SpotifyConfig) -> String {
+        format!("{}/api/token", config.accounts_url)
     }
 
     fn method(&self) -> spotify::SpotifyMethod {
@@ -27,11 +33,11 @@ impl spotify::SpotifyRequest for SpotifyRequestAuthNew {
     }
 
     fn form_data(&self) -> Option<Vec<(&str, &str)>> {
-        let mut data = Vec::new();
-
-        data.push(("code", &self.code[..]));
-        data.push(("redirect_uri", &self.return_url[..]));
-        data.push(("grant_type", "authorization_code"));
+        let data = vec![
+            ("code", &self.code[..]),
+            ("redirect_uri", &self.return_url[..]),
+            ("grant_type", "authorization_code"),
+        ];
 
         Some(data)
     }
@@ -52,8 +58,12 @@ pub struct SpotifyRequestAuthRefresh {
 impl spotify::SpotifyRequest for SpotifyRequestAuthRefresh {
     type JSONDataType = ();
 
-    fn endpoint(&self) -> String {
-        "https://accounts.spotify.com/api/token".to_string()
+    fn name(&self) -> &'static str {
+        "auth_refresh"
+    }
+
+    fn endpoint(&self, config: &SpotifyConfig) -> String {
+        format!("{}/api/token", config.accounts_url)
     }
 
     fn method(&self) -> spotify::SpotifyMethod {
@@ -69,10 +79,10 @@ impl spotify::SpotifyRequest for SpotifyRequestAuthRefresh {
     }
 
     fn form_data(&self) -> Option<Vec<(&str, &str)>> {
-        let mut data = Vec::new();
-
-        data.push(("refresh_token", &self.refresh_token[..]));
-        data.push(("grant_type", "refresh_token"));
+        let data = vec![
+            ("refresh_token", &self.refresh_token[..]),
+            ("grant_type", "refresh_token"),
+        ];
 
         Some(data)
     }
@@ -87,16 +97,31 @@ impl spotify::SpotifyRequest for SpotifyRequestAuthRefresh {
 }
 
 impl spotify::SpotifyInternal {
-    pub async fn request_auth_new(&self, code: String, return_url: String) -> AccessToken {
-        let req = SpotifyRequestAuthNew { code, return_url };
+    pub fn authorize_url(&self) -> String {
+        format!(
+            "{}/authorize?response_type=code&client_id={}&redirect_uri={}&state=not-used&scope={}&show_dialog=true",
+            self.config.accounts_url,
+            self.config.client_id,
+            self.config.return_url,
+            "user-modify-playback-state+streaming"
+        )
+    }
+
+    pub async fn request_auth_new(&self, code: String) -> Result<AccessToken> {
+        let req = SpotifyRequestAuthNew {
+            code,
+            return_url: self.config.return_url.clone(),
+        };
 
-        self.request(req).await.unwrap()
+        self.request_json(req).await
     }
 
-    pub async fn request_auth_refresh(&self, refresh_token: String) -> AccessToken {
+    pub async fn request_auth_refresh(&self, refresh_token: String) -> Result<AccessToken> {
         let req = SpotifyRequestAuthRefresh { refresh_token };
 
-        self.perform_request(&req).await.json().await.unwrap()
+        //Skips the refresh-on-401 handling of request, since this is the refresh
+        let response = self.send(&req, None).await?;
+        Self::read_response(response).await
     }
 }
 
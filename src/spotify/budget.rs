@@ -0,0 +1,113 @@
+use crate::error::{Error, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+//Sliding window request counts, so one busy user (or the instance as a whole) can't get us banned by Spotify
+pub struct Budget {
+    window: Duration,
+    global_limit: usize,
+    token_limit: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    global: VecDeque<Instant>,
+    tokens: HashMap<String, VecDeque<Instant>>,
+}
+
+impl Budget {
+    pub fn new(window: Duration, global_limit: u32, token_limit: u32) -> Self {
+        Self {
+            window,
+            global_limit: global_limit as usize,
+            token_limit: token_limit as usize,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    //Takes one request out of the budget, or says how long until there is room again
+    pub fn spend(&self, token: Option<&str>) -> Result<()> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let window = self.window;
+
+        let expired = |spent: &mut VecDeque<Instant>| {
+            while spent.front().is_some_and(|at| *at + window <= now) {
+                spent.pop_front();
+            }
+        };
+        expired(&mut state.global);
+        state.tokens.retain(|_, spent| {
+            expired(spent);
+            !spent.is_empty()
+        });
+
+        let exhausted = |spent: &VecDeque<Instant>, limit: usize| {
+            (spent.len() >= limit).then(|| Error::RateLimited {
+                retry_after: (spent[0] + window).saturating_duration_since(now),
+            })
+        };
+        if let Some(err) = exhausted(&state.global, self.global_limit) {
+            return Err(err);
+        }
+        if let Some(token) = token {
+            if let Some(err) = state
+                .tokens
+                .get(token)
+                .and_then(|spent| exhausted(spent, self.token_limit))
+            {
+                return Err(err);
+            }
+            state
+                .tokens
+                .entry(token.to_string())
+                .or_default()
+                .push_back(now);
+        }
+        state.global.push_back(now);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_budget_is_per_token() {
+        let budget = Budget::new(Duration::from_secs(30), 10, 2);
+
+        budget.spend(Some("a")).unwrap();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        budget.spend(Some("a")).unwrap();
+
+        match budget.spend(Some("a")) {
+            Err(Error::RateLimited { retry_after }) => {
+                assert_eq!(retry_after, Duration::from_secs(20))
+            }
+            other => panic!("Expected to be rate limited, got {:?}", other),
+        }
+        budget.spend(Some("b")).unwrap();
+        budget.spend(None).unwrap();
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        budget.spend(Some("a")).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_global_budget_covers_everyone() {
+        let budget = Budget::new(Duration::from_secs(30), 2, 10);
+
+        budget.spend(Some("a")).unwrap();
+        budget.spend(None).unwrap();
+
+        assert!(matches!(
+            budget.spend(Some("b")),
+            Err(Error::RateLimited { .. })
+        ));
+    }
+}
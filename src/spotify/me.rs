@@ -1,4 +1,6 @@
+use crate::config::SpotifyConfig;
 use crate::db::auth::Auth;
+use crate::error::Result;
 use crate::spotify;
 use serde::{Deserialize, Serialize};
 
@@ -9,8 +11,12 @@ struct SpotifyRequestMe {
 impl spotify::SpotifyRequest for SpotifyRequestMe {
     type JSONDataType = ();
 
-    fn endpoint(&self) -> String {
-        "https://api.spotify.com/v1/me".to_string()
+    fn name(&self) -> &'static str {
+        "me"
+    }
+
+    fn endpoint(&self, config: &SpotifyConfig) -> String {
+        format!("{}/v1/me", config.api_url)
     }
 
     fn method(&self) -> spotify::SpotifyMethod {
@@ -39,10 +45,10 @@ impl spotify::SpotifyRequest for SpotifyRequestMe {
 }
 
 impl spotify::SpotifyInternal {
-    pub async fn request_me(&self, token: Auth) -> User {
+    pub async fn request_me(&self, token: Auth) -> Result<User> {
         let req = SpotifyRequestMe { token };
 
-        self.request(req).await.unwrap()
+        self.request_json(req).await
     }
 }
 
@@ -1,4 +1,6 @@
+use crate::config::SpotifyConfig;
 use crate::db::auth::Auth;
+use crate::error::Result;
 use crate::spotify;
 use serde::Serialize;
 
@@ -12,9 +14,14 @@ struct SpotifyRequestPlay {
 impl spotify::SpotifyRequest for SpotifyRequestPlay {
     type JSONDataType = PlayerPlayData;
 
-    fn endpoint(&self) -> String {
+    fn name(&self) -> &'static str {
+        "play"
+    }
+
+    fn endpoint(&self, config: &SpotifyConfig) -> String {
         format!(
-            "https://api.spotify.com/v1/me/player/play?device_id={}",
+            "{}/v1/me/player/play?device_id={}",
+            config.api_url,
             self.device_id.clone()
         )
     }
@@ -39,7 +46,7 @@ impl spotify::SpotifyRequest for SpotifyRequestPlay {
         Some(PlayerPlayData {
             context_uri: None,
             uris: Some(vec![self.uri.clone()]),
-            position_ms: self.position.clone(),
+            position_ms: self.position,
         })
     }
 
@@ -49,7 +56,13 @@ impl spotify::SpotifyRequest for SpotifyRequestPlay {
 }
 
 impl spotify::SpotifyInternal {
-    pub async fn request_play(&self, token: Auth, device_id: String, uri: String, position: u32) {
+    pub async fn request_play(
+        &self,
+        token: Auth,
+        device_id: String,
+        uri: String,
+        position: u32,
+    ) -> Result<()> {
         let req = SpotifyRequestPlay {
             token,
             device_id,
@@ -57,7 +70,9 @@ impl spotify::SpotifyInternal {
             position,
         };
 
-        self.request(req).await.unwrap_or(());
+        self.request::<()>(req).await?;
+
+        Ok(())
     }
 }
 
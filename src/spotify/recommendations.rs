@@ -0,0 +1,76 @@
+use crate::config::SpotifyConfig;
+use crate::db::auth::Auth;
+use crate::error::Result;
+use crate::spotify;
+use crate::spotify::tracks::TrackList;
+
+struct SpotifyRequestRecommendations {
+    token: Auth,
+    seed_track_ids: Vec<String>,
+    limit: u32,
+}
+
+impl spotify::SpotifyRequest for SpotifyRequestRecommendations {
+    type JSONDataType = ();
+
+    fn name(&self) -> &'static str {
+        "recommendations"
+    }
+
+    fn endpoint(&self, config: &SpotifyConfig) -> String {
+        let short_ids: Vec<String> = self
+            .seed_track_ids
+            .iter()
+            .map(|track_id| track_id.split(":").last().unwrap().to_string())
+            .collect();
+
+        format!(
+            "{}/v1/recommendations?seed_tracks={}&limit={}",
+            config.api_url,
+            short_ids.join(","),
+            self.limit
+        )
+    }
+
+    fn method(&self) -> spotify::SpotifyMethod {
+        spotify::SpotifyMethod::Get
+    }
+
+    fn basic_auth(&self) -> bool {
+        false
+    }
+
+    fn token(&self) -> Option<Auth> {
+        Some(self.token.clone())
+    }
+
+    fn form_data(&self) -> Option<Vec<(&str, &str)>> {
+        None
+    }
+
+    fn json_data(&self) -> Option<Self::JSONDataType> {
+        None
+    }
+
+    fn has_result(&self) -> bool {
+        true
+    }
+}
+
+impl spotify::SpotifyInternal {
+    //Spotify takes at most 5 seeds
+    pub async fn request_recommendations(
+        &self,
+        token: Auth,
+        seed_track_ids: Vec<String>,
+        limit: u32,
+    ) -> Result<TrackList> {
+        let req = SpotifyRequestRecommendations {
+            token,
+            seed_track_ids,
+            limit,
+        };
+
+        self.request_json(req).await
+    }
+}
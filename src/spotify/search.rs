@@ -1,4 +1,6 @@
+use crate::config::SpotifyConfig;
 use crate::db::auth::Auth;
+use crate::error::Result;
 use crate::spotify;
 use serde::Deserialize;
 
@@ -15,18 +17,22 @@ struct SpotifyRequestSearch {
 impl spotify::SpotifyRequest for SpotifyRequestSearch {
     type JSONDataType = ();
 
-    fn endpoint(&self) -> String {
+    fn name(&self) -> &'static str {
+        "search"
+    }
+
+    fn endpoint(&self, config: &SpotifyConfig) -> String {
         let market = match self.market.clone() {
             Some(market) => format!("&market={}", market),
             None => "".to_string(),
         };
 
-        let limit = match self.limit.clone() {
+        let limit = match self.limit {
             Some(limit) => format!("&limit={}", limit),
             None => "".to_string(),
         };
 
-        let offset = match self.offset.clone() {
+        let offset = match self.offset {
             Some(offset) => format!("&offset={}", offset),
             None => "".to_string(),
         };
@@ -37,8 +43,8 @@ impl spotify::SpotifyRequest for SpotifyRequestSearch {
         };
 
         format!(
-            "https://api.spotify.com/v1/search?q={}&type={}{}{}{}{}",
-            self.query, self.search_type, market, limit, offset, include_external
+            "{}/v1/search?q={}&type={}{}{}{}{}",
+            config.api_url, self.query, self.search_type, market, limit, offset, include_external
         )
     }
 
@@ -68,6 +74,7 @@ impl spotify::SpotifyRequest for SpotifyRequestSearch {
 }
 
 impl spotify::SpotifyInternal {
+    #[allow(clippy::too_many_arguments)]
     pub async fn request_search(
         &self,
         token: Auth,
@@ -77,7 +84,7 @@ impl spotify::SpotifyInternal {
         limit: Option<u32>,
         offset: Option<u32>,
         include_external: Option<bool>,
-    ) -> SearchResult {
+    ) -> Result<SearchResult> {
         let req = SpotifyRequestSearch {
             token,
             query,
@@ -88,7 +95,7 @@ impl spotify::SpotifyInternal {
             include_external,
         };
 
-        self.request(req).await.unwrap()
+        self.request_json(req).await
     }
 }
 
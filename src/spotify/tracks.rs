@@ -1,4 +1,6 @@
+use crate::config::SpotifyConfig;
 use crate::db::auth::Auth;
+use crate::error::Result;
 use crate::spotify;
 use serde::Deserialize;
 
@@ -10,9 +12,13 @@ struct SpotfiyRequestTrack {
 impl spotify::SpotifyRequest for SpotfiyRequestTrack {
     type JSONDataType = ();
 
-    fn endpoint(&self) -> String {
+    fn name(&self) -> &'static str {
+        "track"
+    }
+
+    fn endpoint(&self, config: &SpotifyConfig) -> String {
         let short_id = self.track_id.split(":").last().unwrap();
-        format!("https://api.spotify.com/v1/tracks/{}", short_id)
+        format!("{}/v1/tracks/{}", config.api_url, short_id)
     }
 
     fn method(&self) -> spotify::SpotifyMethod {
@@ -41,10 +47,10 @@ impl spotify::SpotifyRequest for SpotfiyRequestTrack {
 }
 
 impl spotify::SpotifyInternal {
-    pub async fn request_track(&self, token: Auth, track_id: String) -> Track {
+    pub async fn request_track(&self, token: Auth, track_id: String) -> Result<Track> {
         let req = SpotfiyRequestTrack { token, track_id };
 
-        Self::request(&self, req).await.unwrap()
+        self.request_json(req).await
     }
 }
 
@@ -56,14 +62,18 @@ struct SpotfiyRequestTracks {
 impl spotify::SpotifyRequest for SpotfiyRequestTracks {
     type JSONDataType = ();
 
-    fn endpoint(&self) -> String {
+    fn name(&self) -> &'static str {
+        "tracks"
+    }
+
+    fn endpoint(&self, config: &SpotifyConfig) -> String {
         let short_ids: Vec<String> = self
             .track_ids
             .iter()
             .map(|track_id| track_id.split(":").last().unwrap().to_string())
             .collect();
 
-        format!("https://api.spotify.com/v1/tracks?ids={}", short_ids.join(","))
+        format!("{}/v1/tracks?ids={}", config.api_url, short_ids.join(","))
     }
 
     fn method(&self) -> spotify::SpotifyMethod {
@@ -92,10 +102,10 @@ impl spotify::SpotifyRequest for SpotfiyRequestTracks {
 }
 
 impl spotify::SpotifyInternal {
-    pub async fn request_tracks(&self, token: Auth, track_ids: Vec<String>) -> TrackList {
+    pub async fn request_tracks(&self, token: Auth, track_ids: Vec<String>) -> Result<TrackList> {
         let req = SpotfiyRequestTracks { token, track_ids };
 
-        Self::request(&self, req).await.unwrap()
+        self.request_json(req).await
     }
 }
 
@@ -10,21 +10,20 @@ pub fn shorten_track(track: &spotify::tracks::Track) -> ShortTrack {
         .collect();
 
     //Extract smallest album cover image
-    let mut images: Vec<(u32, String)> = track
+    let cover = track
         .album
         .images
         .iter()
-        .map(|image| (image.width * image.height, image.url.clone()))
-        .collect();
-    images.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
-    let (_, image) = &images[0];
+        .min_by_key(|image| image.width * image.height)
+        .map(|image| image.url.clone())
+        .unwrap_or_default();
 
     ShortTrack {
         name: track.name.clone(),
-        preview_url: track.preview_url.clone().unwrap(),
+        preview_url: track.preview_url.clone().unwrap_or_default(),
         uri: track.uri.clone(),
-        artists: artists,
-        cover: image.clone(),
+        artists,
+        cover,
     }
 }
 
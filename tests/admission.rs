@@ -0,0 +1,40 @@
+mod support;
+
+use social_music::db::room::{AdmissionRules, Room};
+use support::app::setup;
+use support::socket::queue_song;
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "admission-room";
+
+#[tokio::test]
+async fn test_queue_rejects_tracks_breaking_room_rules() {
+    let app = setup(USER_URI).await;
+    app.fake.add_track("short", "Short Song", 180_000);
+    app.fake.add_track("long", "Long Song", 600_000);
+    app.login(USER_URI).await;
+    app.create_room(Room {
+        rules: AdmissionRules {
+            max_duration_ms: Some(300_000),
+            //The fake catalog is all from 2022
+            min_year: Some(2020),
+            ..Default::default()
+        },
+        ..app.room(ROOM_ID)
+    })
+    .await;
+    let mut ws = app.connect(ROOM_ID, USER_URI).await;
+
+    assert_eq!(
+        queue_song(&mut ws, "spotify:track:long").await,
+        vec!["Tracks can be at most 5:00 long here, this one is 10:00."]
+    );
+    assert!(queue_song(&mut ws, "spotify:track:short").await.is_empty());
+    assert_eq!(
+        app.db
+            .list_user_queue(ROOM_ID.to_string(), USER_URI.to_string())
+            .await
+            .unwrap(),
+        vec!["spotify:track:short"]
+    );
+}
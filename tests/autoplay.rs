@@ -0,0 +1,49 @@
+mod support;
+
+use support::app::{setup_with, wait_until};
+use support::fake_spotify::Route;
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "autoplay-room";
+
+#[tokio::test]
+async fn test_autoplay_takes_over_when_queue_runs_dry() {
+    let app = setup_with(USER_URI, |config| config.rooms.autoplay = true).await;
+    app.fake.add_track("one", "First Song", 1_000);
+    app.fake.add_track("two", "Recommended Song", 60_000);
+    app.login(USER_URI).await;
+    app.create_room(app.room(ROOM_ID)).await;
+    let db = &app.db;
+    db.push_user_queue(
+        ROOM_ID.to_string(),
+        USER_URI.to_string(),
+        "spotify:track:one".to_string(),
+    )
+    .await
+    .unwrap();
+    db.push_queue(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap();
+
+    app.serve().await;
+    //Autoplay borrows the token of someone listening
+    let _ws = app.connect(ROOM_ID, USER_URI).await;
+
+    let autoplaying = || async {
+        let playing = db.get_playing(ROOM_ID.to_string()).await.unwrap();
+        playing.is_some_and(|playing| playing.autoplay)
+    };
+    wait_until(autoplaying).await;
+
+    let playing = db.get_playing(ROOM_ID.to_string()).await.unwrap().unwrap();
+    assert_eq!(playing.track_id, "spotify:track:two");
+    assert_eq!(playing.user_id, None);
+    assert_eq!(app.fake.requests(Route::Recommendations), 1);
+    let history = db
+        .list_history(ROOM_ID.to_string(), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].user_id, None);
+    assert_eq!(history[1].user_id.as_deref(), Some(USER_URI));
+}
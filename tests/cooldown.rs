@@ -0,0 +1,63 @@
+mod support;
+
+use support::app::{setup, setup_with};
+use support::fake_spotify::Route;
+use support::socket::queue_song;
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "cooldown-room";
+
+#[tokio::test]
+async fn test_queue_rejects_recent_duplicates() {
+    let app = setup(USER_URI).await;
+    app.fake.add_track("one", "First Song", 60_000);
+    app.fake.add_track("two", "Second Song", 60_000);
+    app.fake.add_release(
+        "one-remastered",
+        "First Song (Remastered)",
+        60_000,
+        "FAKEone",
+    );
+    app.login(USER_URI).await;
+    app.create_room(app.room(ROOM_ID)).await;
+    let mut ws = app.connect(ROOM_ID, USER_URI).await;
+
+    assert!(queue_song(&mut ws, "spotify:track:one").await.is_empty());
+    let rejections = queue_song(&mut ws, "spotify:track:one").await;
+    assert!(rejections[0].starts_with("That track is already queued"));
+    assert_eq!(
+        queue_song(&mut ws, "spotify:track:one-remastered").await,
+        rejections
+    );
+    assert!(queue_song(&mut ws, "spotify:track:two").await.is_empty());
+    //One lookup per queued track, shared by the admission rules and the cooldown,
+    //the ISRCs of what's already queued are stored so nothing else is looked up
+    assert_eq!(app.fake.requests(Route::Track), 4);
+    assert_eq!(app.fake.requests(Route::Tracks), 0);
+
+    assert_eq!(
+        app.db
+            .list_user_queue(ROOM_ID.to_string(), USER_URI.to_string())
+            .await
+            .unwrap(),
+        vec!["spotify:track:one", "spotify:track:two"]
+    );
+}
+
+#[tokio::test]
+async fn test_queue_rejects_queued_duplicates_without_cooldown() {
+    let app = setup_with(USER_URI, |config| {
+        config.rooms.duplicate_cooldown_secs = 0;
+    })
+    .await;
+    app.fake.add_track("one", "First Song", 60_000);
+    app.login(USER_URI).await;
+    app.create_room(app.room(ROOM_ID)).await;
+    let mut ws = app.connect(ROOM_ID, USER_URI).await;
+
+    assert!(queue_song(&mut ws, "spotify:track:one").await.is_empty());
+    assert_eq!(
+        queue_song(&mut ws, "spotify:track:one").await,
+        vec!["That track is already queued here."]
+    );
+}
@@ -0,0 +1,54 @@
+mod support;
+
+use serde_json::json;
+use social_music::db::room::{Room, RoomMode};
+use support::app::{setup, wait_until};
+use support::socket::{queue_song, send};
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "democratic-room";
+
+#[tokio::test]
+async fn test_most_voted_track_plays_first() {
+    let app = setup(USER_URI).await;
+    app.fake.add_track("one", "First Song", 60_000);
+    app.fake.add_track("two", "Second Song", 60_000);
+    app.login(USER_URI).await;
+    app.create_room(Room {
+        mode: RoomMode::Democratic,
+        ..app.room(ROOM_ID)
+    })
+    .await;
+    let db = &app.db;
+    let mut ws = app.connect(ROOM_ID, USER_URI).await;
+
+    for track in ["spotify:track:one", "spotify:track:two"] {
+        assert!(queue_song(&mut ws, track).await.is_empty());
+    }
+
+    let pool = db.list_pool(ROOM_ID.to_string()).await.unwrap();
+    assert_eq!(pool.len(), 2);
+    assert!(db
+        .list_user_queue(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap()
+        .is_empty());
+    //The newer track overtakes the older one
+    assert!(send(
+        &mut ws,
+        json!({ "VotePoolTrack": { "id": pool[1].id, "vote": 1 } })
+    )
+    .await
+    .is_empty());
+    assert_eq!(db.list_pool(ROOM_ID.to_string()).await.unwrap()[1].score, 1);
+
+    app.serve().await;
+    wait_until(|| async { db.get_playing(ROOM_ID.to_string()).await.unwrap().is_some() }).await;
+
+    let playing = db.get_playing(ROOM_ID.to_string()).await.unwrap().unwrap();
+    assert_eq!(playing.track_id, "spotify:track:two");
+    assert_eq!(playing.user_id.as_deref(), Some(USER_URI));
+    let pool = db.list_pool(ROOM_ID.to_string()).await.unwrap();
+    assert_eq!(pool.len(), 1);
+    assert_eq!(pool[0].track_id, "spotify:track:one");
+}
@@ -0,0 +1,68 @@
+mod support;
+
+use serde_json::Value;
+use social_music::db::history::HistoryEntry;
+use support::app::setup;
+use warp::http::StatusCode;
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "history-room";
+
+#[tokio::test]
+async fn test_history_pages_with_cursor() {
+    let app = setup(USER_URI).await;
+    app.login(USER_URI).await;
+    app.create_room(app.room(ROOM_ID)).await;
+    for (start_time, id) in ["one", "two", "three"].into_iter().enumerate() {
+        app.fake.add_track(id, id, 60_000);
+        let entry = HistoryEntry {
+            id: 0,
+            track_id: format!("spotify:track:{}", id),
+            user_id: Some(USER_URI.to_string()),
+            start_time: start_time as u128,
+            skipped: false,
+        };
+        app.db
+            .add_history(ROOM_ID.to_string(), entry)
+            .await
+            .unwrap();
+    }
+    app.db.skip_history(ROOM_ID.to_string(), 2).await.unwrap();
+
+    let routes = app.routes();
+    let cookie = app.cookie(USER_URI);
+    let page = |query: String| {
+        warp::test::request()
+            .path(&format!("/api/v1/rooms/{}/history{}", ROOM_ID, query))
+            .header("cookie", &cookie)
+            .reply(&routes)
+    };
+
+    let res = page("?limit=2".to_string()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["tracks"][0]["track"]["uri"], "spotify:track:three");
+    assert_eq!(body["tracks"][0]["skipped"], true);
+    assert_eq!(body["tracks"][1]["track"]["uri"], "spotify:track:two");
+    assert_eq!(body["tracks"][1]["user_id"], USER_URI);
+    assert_eq!(body["next"], 2);
+
+    let res = page("?limit=2&before=2".to_string()).await;
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["tracks"].as_array().unwrap().len(), 1);
+    assert_eq!(body["tracks"][0]["track"]["uri"], "spotify:track:one");
+    assert_eq!(body["next"], Value::Null);
+
+    //A full page can't tell it's the last one, the page after it is empty
+    let res = page("?limit=3".to_string()).await;
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["tracks"][2]["track"]["uri"], "spotify:track:one");
+    assert_eq!(body["next"], 1);
+    let res = page("?before=1".to_string()).await;
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert!(body["tracks"].as_array().unwrap().is_empty());
+    assert_eq!(body["next"], Value::Null);
+
+    let res = page("?limit=0".to_string()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,112 @@
+mod support;
+
+use serde_json::{json, Value};
+use social_music::db::room::{Room, Visibility};
+use support::app::setup;
+use warp::http::StatusCode;
+
+const OWNER_URI: &str = "spotify:user:fake";
+const GUEST_URI: &str = "spotify:user:guest";
+const LATECOMER_URI: &str = "spotify:user:latecomer";
+
+#[tokio::test]
+async fn test_private_room_needs_an_invite() {
+    let app = setup(OWNER_URI).await;
+    for (id, visibility) in [
+        ("private-room", Visibility::Private),
+        ("unlisted-room", Visibility::Unlisted),
+        ("public-room", Visibility::Public),
+    ] {
+        app.create_room(Room {
+            visibility,
+            ..app.room(id)
+        })
+        .await;
+    }
+
+    let routes = app.routes();
+    let get = |user_id: &str, path: String| {
+        warp::test::request()
+            .path(&path)
+            .header("cookie", app.cookie(user_id))
+            .reply(&routes)
+    };
+    let listed = |body: &[u8]| -> Vec<String> {
+        let body: Value = serde_json::from_slice(body).unwrap();
+        body["rooms"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|room| room["id"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    let res = get(OWNER_URI, "/api/v1/rooms".to_string()).await;
+    assert_eq!(listed(res.body()).len(), 3);
+    let res = get(GUEST_URI, "/api/v1/rooms".to_string()).await;
+    assert_eq!(listed(res.body()), vec!["public-room"]);
+
+    //Unlisted rooms are open to anyone with the link
+    let res = get(GUEST_URI, "/api/v1/rooms/unlisted-room".to_string()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = get(GUEST_URI, "/api/v1/rooms/private-room".to_string()).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    for path in [
+        "/api/v1/rooms/private-room/history",
+        "/api/v1/queues/private-room",
+    ] {
+        let res = get(GUEST_URI, path.to_string()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+    assert!(app.handshake("private-room", GUEST_URI).await.is_err());
+
+    let invite = |user_id: &str, body: Value| {
+        warp::test::request()
+            .method("POST")
+            .path("/api/v1/rooms/private-room/invites")
+            .header("cookie", app.cookie(user_id))
+            .json(&body)
+            .reply(&routes)
+    };
+    let res = invite(GUEST_URI, json!({})).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = invite(OWNER_URI, json!({ "max_uses": 0 })).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = invite(OWNER_URI, json!({ "max_uses": 1 })).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    let token = body["token"].as_str().unwrap().to_string();
+
+    let res = get(
+        GUEST_URI,
+        "/api/v1/rooms/private-room?invite=garbage".to_string(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = get(
+        GUEST_URI,
+        format!("/api/v1/rooms/private-room?invite={}", token),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    //Members don't need the invite anymore and see the room in their list
+    assert!(app.handshake("private-room", GUEST_URI).await.is_ok());
+    let res = get(GUEST_URI, "/api/v1/rooms".to_string()).await;
+    let mut rooms = listed(res.body());
+    rooms.sort();
+    assert_eq!(rooms, vec!["private-room", "public-room", "unlisted-room"]);
+
+    let res = get(
+        LATECOMER_URI,
+        format!("/api/v1/rooms/private-room?invite={}", token),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(
+        body["messages"][0],
+        "This invite has expired or was used up."
+    );
+}
@@ -0,0 +1,168 @@
+mod support;
+
+use serde_json::Value;
+use social_music::config::IdleRoomAction;
+use social_music::db::room::{Room, RoomMode};
+use social_music::janitor;
+use support::app::{setup, setup_with};
+use support::socket::system_message;
+
+const OWNER_URI: &str = "spotify:user:fake";
+const GUEST_URI: &str = "spotify:user:guest";
+const GONE_URI: &str = "spotify:user:gone";
+const DAY_MS: u128 = 24 * 60 * 60 * 1000;
+
+#[tokio::test]
+async fn test_idle_rooms_are_archived_until_visited() {
+    let app = setup_with(OWNER_URI, |config| {
+        config.rooms.idle_room_secs = 7 * 24 * 60 * 60;
+    })
+    .await;
+    let (config, db) = (&app.config, &app.db);
+    for id in ["idle-room", "busy-room"] {
+        app.create_room(app.room(id)).await;
+    }
+    db.add_presences("busy-room".to_string(), GUEST_URI.to_string())
+        .await
+        .unwrap();
+    for (room_id, user_id) in [("busy-room", GUEST_URI), ("busy-room", GONE_URI)] {
+        db.push_user_queue(
+            room_id.to_string(),
+            user_id.to_string(),
+            "spotify:track:one".to_string(),
+        )
+        .await
+        .unwrap();
+    }
+
+    //Idle rooms start counting once the janitor first sees them
+    let start = 100 * DAY_MS;
+    let swept = janitor::sweep(db, &config.rooms, start).await.unwrap();
+    assert_eq!(swept.rooms, 0);
+    assert_eq!(swept.queues, 1);
+    assert_eq!(
+        db.list_user_queues().await.unwrap(),
+        vec![("busy-room".to_string(), GUEST_URI.to_string())]
+    );
+
+    let swept = janitor::sweep(db, &config.rooms, start + 6 * DAY_MS)
+        .await
+        .unwrap();
+    assert_eq!(swept.rooms, 0);
+    let swept = janitor::sweep(db, &config.rooms, start + 7 * DAY_MS)
+        .await
+        .unwrap();
+    assert_eq!(swept.rooms, 1);
+    assert!(db.is_archived_room("idle-room".to_string()).await.unwrap());
+    assert!(!db.is_archived_room("busy-room".to_string()).await.unwrap());
+
+    let routes = app.routes();
+    let listed = |user_id: &str| {
+        let request = warp::test::request()
+            .path("/api/v1/rooms")
+            .header("cookie", app.cookie(user_id));
+        let routes = routes.clone();
+        async move {
+            let res = request.reply(&routes).await;
+            let body: Value = serde_json::from_slice(res.body()).unwrap();
+            body["rooms"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|room| room["id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(listed(GUEST_URI).await, vec!["busy-room"]);
+    assert_eq!(listed(OWNER_URI).await.len(), 2);
+
+    //The owner hears about it when they're back, which brings the room back too
+    let mut owner = app.connect("idle-room", OWNER_URI).await;
+    assert_eq!(
+        system_message(&mut owner).await,
+        "Your room idle-room was archived after 7 days without listeners, its queues and chat were cleared. Join it to open it again."
+    );
+    assert!(!db.is_archived_room("idle-room".to_string()).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_idle_rooms_can_be_deleted() {
+    let app = setup_with(OWNER_URI, |config| {
+        config.rooms.idle_room_secs = 60 * 60;
+        config.rooms.idle_room_action = IdleRoomAction::Delete;
+    })
+    .await;
+    let (config, db) = (&app.config, &app.db);
+    app.create_room(Room {
+        title: "Idle".to_string(),
+        ..app.room("idle-room")
+    })
+    .await;
+    db.push_user_queue(
+        "idle-room".to_string(),
+        OWNER_URI.to_string(),
+        "spotify:track:one".to_string(),
+    )
+    .await
+    .unwrap();
+    db.touch_room("idle-room".to_string(), DAY_MS)
+        .await
+        .unwrap();
+
+    let swept = janitor::sweep(db, &config.rooms, 2 * DAY_MS).await.unwrap();
+    assert_eq!(swept.rooms, 1);
+    assert!(!db.exists_room("idle-room".to_string()).await.unwrap());
+    assert!(db.list_user_queues().await.unwrap().is_empty());
+    assert_eq!(
+        db.take_notices(OWNER_URI.to_string()).await.unwrap(),
+        vec!["Your room Idle was deleted after 1 hour without listeners."]
+    );
+}
+
+#[tokio::test]
+async fn test_archiving_empties_the_pool() {
+    let app = setup_with(OWNER_URI, |config| {
+        config.rooms.idle_room_secs = 60 * 60;
+    })
+    .await;
+    let (config, db) = (&app.config, &app.db);
+    app.create_room(Room {
+        mode: RoomMode::Democratic,
+        ..app.room("idle-room")
+    })
+    .await;
+    let track = db
+        .add_pool_track(
+            "idle-room".to_string(),
+            GUEST_URI.to_string(),
+            "spotify:track:one".to_string(),
+        )
+        .await
+        .unwrap();
+    db.vote_pool_track("idle-room".to_string(), track.id, OWNER_URI.to_string(), 1)
+        .await
+        .unwrap();
+    db.touch_room("idle-room".to_string(), DAY_MS)
+        .await
+        .unwrap();
+
+    let swept = janitor::sweep(db, &config.rooms, 2 * DAY_MS).await.unwrap();
+    assert_eq!(swept.rooms, 1);
+    assert!(db.is_archived_room("idle-room".to_string()).await.unwrap());
+    assert!(db
+        .list_pool("idle-room".to_string())
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_one_instance_sweeps_at_a_time() {
+    let app = setup(OWNER_URI).await;
+    let db = &app.db;
+    let interval = std::time::Duration::from_secs(60 * 60);
+
+    assert!(db.lease_janitor("one".to_string(), interval).await.unwrap());
+    assert!(!db.lease_janitor("two".to_string(), interval).await.unwrap());
+    assert!(!db.lease_janitor("one".to_string(), interval).await.unwrap());
+}
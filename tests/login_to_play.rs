@@ -0,0 +1,170 @@
+mod support;
+
+use serde_json::{json, Value};
+use support::app::setup;
+use support::fake_spotify::Route;
+use warp::http::StatusCode;
+
+const USER_URI: &str = "spotify:user:fake";
+
+#[tokio::test]
+async fn test_login_create_room_queue_and_play() {
+    let app = setup(USER_URI).await;
+    let (fake, db) = (&app.fake, &app.db);
+    fake.add_track("one", "First Song", 60_000);
+    fake.add_track("two", "Second Song", 60_000);
+
+    app.serve().await;
+    let routes = app.routes();
+
+    //Ready once the listener is going
+    let res = warp::test::request().path("/readyz").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let readiness: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(readiness["ready"], true);
+
+    //Login redirects to the (fake) accounts service
+    let res = warp::test::request().path("/login").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    let location = res.headers()["location"].to_str().unwrap();
+    assert!(location.starts_with(&format!("{}/authorize?", fake.config.accounts_url)));
+
+    //Spotify sends the user back with a code, which gets exchanged for tokens
+    let res = warp::test::request()
+        .path("/authorize?code=fake-code&state=not-used")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+    let cookie = res.headers()["set-cookie"].to_str().unwrap().to_string();
+    let auth = db.get_auth(USER_URI.to_string()).await.unwrap().unwrap();
+    assert_eq!(auth.refresh_token, "fake-refresh-token");
+
+    //Create a room
+    let res = warp::test::request()
+        .method("POST")
+        .path("/api/v1/rooms")
+        .header("cookie", &cookie)
+        .json(&json!({ "id": "fake-room", "title": "Fake Room" }))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    //Search goes through spotify
+    let res = warp::test::request()
+        .path("/api/v1/search?q=second")
+        .header("cookie", &cookie)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let results: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(results[0]["uri"], "spotify:track:two");
+
+    //Join the room, register a device and queue a song
+    let mut ws = warp::test::ws()
+        .path("/chat/fake-room")
+        .header("cookie", &cookie)
+        .handshake(routes.clone())
+        .await
+        .unwrap();
+    let messages = [
+        json!({ "SetDevice": { "device_id": "fake-device" } }),
+        json!({ "QueueSong": { "track_id": "spotify:track:two" } }),
+        json!({ "JoinQueue": null }),
+    ];
+    for message in messages {
+        ws.send_text(message.to_string()).await;
+    }
+
+    //The room worker should pick the song up and start it on the device
+    let play = tokio::time::timeout(tokio::time::Duration::from_secs(10), async {
+        loop {
+            if let Some(play) = fake.plays().pop() {
+                return play;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("Song was never played");
+
+    assert_eq!(play.device_id, "fake-device");
+    assert_eq!(play.uris, vec!["spotify:track:two".to_string()]);
+    assert_eq!(play.position_ms, 0);
+    assert_eq!(play.access_token, auth.access_token);
+    //Looked up once when queued and once more to play it
+    assert_eq!(fake.requests(Route::Track), 2);
+
+    //All of it shows up in the metrics
+    let res = warp::test::request().path("/metrics").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let metrics = String::from_utf8(res.body().to_vec()).unwrap();
+    assert!(
+        metrics.contains("social_music_spotify_requests_total{endpoint=\"track\",status=\"200\"}")
+    );
+    assert!(metrics.contains("social_music_websockets_active 1"));
+    assert!(metrics.contains("social_music_rooms_served 1"));
+}
+
+#[tokio::test]
+async fn test_expired_token_is_refreshed() {
+    let app = setup(USER_URI).await;
+    let (fake, db, spotify) = (&app.fake, &app.db, &app.spotify);
+    fake.add_track("one", "First Song", 60_000);
+    db.set_auth(
+        USER_URI.to_string(),
+        "expired-token".to_string(),
+        "fake-refresh-token".to_string(),
+    )
+    .await
+    .unwrap();
+    let token = db.require_auth(USER_URI.to_string()).await.unwrap();
+
+    fake.script(
+        Route::Track,
+        401,
+        json!({ "error": { "status": 401, "message": "The access token expired" } }),
+    );
+    let track = spotify
+        .request_track(token, "spotify:track:one".to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(track.name, "First Song");
+    assert_eq!(fake.requests(Route::Token), 1);
+    assert_eq!(fake.requests(Route::Track), 2);
+    let auth = db.get_auth(USER_URI.to_string()).await.unwrap().unwrap();
+    assert_ne!(auth.access_token, "expired-token");
+    assert_eq!(auth.refresh_token, "fake-refresh-token");
+}
+
+#[tokio::test]
+async fn test_spotify_errors_reach_the_client() {
+    let app = setup(USER_URI).await;
+    let (fake, config) = (&app.fake, &app.config);
+    app.login(USER_URI).await;
+    let routes = app.routes();
+
+    //Without a listener the instance shouldn't get traffic
+    let res = warp::test::request().path("/readyz").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let res = warp::test::request().path("/healthz").reply(&routes).await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    //Still failing once the retries run out
+    for _ in 0..=config.spotify.max_retries {
+        fake.script(Route::Search, 503, json!({ "error": "unavailable" }));
+    }
+    let res = warp::test::request()
+        .path("/api/v1/search?q=song")
+        .header("cookie", app.cookie(USER_URI))
+        .reply(&routes)
+        .await;
+
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["error"], "spotify");
+    assert_eq!(
+        fake.requests(Route::Search),
+        config.spotify.max_retries as usize + 1
+    );
+}
@@ -0,0 +1,121 @@
+mod support;
+
+use serde_json::{json, Value};
+use social_music::db::moderation::Role;
+use support::app::setup;
+use support::socket::{queue_song, send, system_message};
+
+const OWNER_URI: &str = "spotify:user:fake";
+const GUEST_URI: &str = "spotify:user:guest";
+const ROOM_ID: &str = "moderation-room";
+
+#[tokio::test]
+async fn test_owner_moderates_guest() {
+    let app = setup(OWNER_URI).await;
+    let db = &app.db;
+    app.fake.add_track("one", "First Song", 60_000);
+    app.create_room(app.room(ROOM_ID)).await;
+    for user_id in [OWNER_URI, GUEST_URI] {
+        app.login(user_id).await;
+    }
+
+    let mut owner = app.connect(ROOM_ID, OWNER_URI).await;
+    let mut guest = app.connect(ROOM_ID, GUEST_URI).await;
+
+    //Guests can't touch the owner
+    assert_eq!(
+        send(&mut guest, json!({ "Kick": { "user_id": OWNER_URI } })).await,
+        vec!["Only moderators can do that."]
+    );
+
+    assert!(
+        send(&mut owner, json!({ "Mute": { "user_id": GUEST_URI } }))
+            .await
+            .is_empty()
+    );
+    assert_eq!(
+        send(&mut guest, json!({ "ChatMessage": { "message": "hi" } })).await,
+        vec!["You're muted in this room."]
+    );
+
+    assert!(queue_song(&mut guest, "spotify:track:one").await.is_empty());
+    assert!(send(&mut guest, json!({ "JoinQueue": null }))
+        .await
+        .is_empty());
+    assert!(send(
+        &mut owner,
+        json!({ "SetRole": { "user_id": GUEST_URI, "role": "listener" } })
+    )
+    .await
+    .is_empty());
+    loop {
+        let message = guest.recv().await.unwrap();
+        let message: Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        if message.get("RoleChange").is_some() {
+            assert_eq!(message["RoleChange"]["user_id"], GUEST_URI);
+            assert_eq!(message["RoleChange"]["role"], "listener");
+            break;
+        }
+    }
+    assert_eq!(
+        queue_song(&mut guest, "spotify:track:one").await,
+        vec!["Listeners can't queue tracks in this room."]
+    );
+    assert!(db.list_queue(ROOM_ID.to_string()).await.unwrap().is_empty());
+
+    //Only the owner appoints moderators
+    assert!(send(
+        &mut owner,
+        json!({ "SetRole": { "user_id": GUEST_URI, "role": "moderator" } })
+    )
+    .await
+    .is_empty());
+    assert_eq!(
+        db.get_role(ROOM_ID.to_string(), GUEST_URI.to_string())
+            .await
+            .unwrap(),
+        Role::Moderator
+    );
+    assert_eq!(
+        send(&mut guest, json!({ "Ban": { "user_id": OWNER_URI } })).await,
+        vec!["You can't do that to someone of your own rank or above."]
+    );
+
+    //Kicked users can come back, banned ones can't
+    assert!(
+        send(&mut owner, json!({ "Kick": { "user_id": GUEST_URI } }))
+            .await
+            .is_empty()
+    );
+    assert_eq!(
+        system_message(&mut guest).await,
+        "You were removed from this room."
+    );
+    guest.recv_closed().await.unwrap();
+
+    let mut guest = app.connect(ROOM_ID, GUEST_URI).await;
+    assert!(send(&mut owner, json!({ "Ban": { "user_id": GUEST_URI } }))
+        .await
+        .is_empty());
+    assert_eq!(
+        system_message(&mut guest).await,
+        "You were banned from this room."
+    );
+    guest.recv_closed().await.unwrap();
+
+    let mut guest = app.connect(ROOM_ID, GUEST_URI).await;
+    assert_eq!(
+        system_message(&mut guest).await,
+        "You're banned from this room."
+    );
+
+    assert!(
+        send(&mut owner, json!({ "Unban": { "user_id": GUEST_URI } }))
+            .await
+            .is_empty()
+    );
+    assert!(!db
+        .is_banned(ROOM_ID.to_string(), GUEST_URI.to_string())
+        .await
+        .unwrap());
+}
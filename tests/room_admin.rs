@@ -0,0 +1,73 @@
+mod support;
+
+use serde_json::{json, Value};
+use support::app::setup;
+use support::socket::{queue_song, system_message};
+use warp::http::StatusCode;
+
+const OWNER_URI: &str = "spotify:user:fake";
+const GUEST_URI: &str = "spotify:user:guest";
+const ROOM_ID: &str = "admin-room";
+
+#[tokio::test]
+async fn test_only_the_owner_changes_and_deletes_a_room() {
+    let app = setup(OWNER_URI).await;
+    let db = &app.db;
+    app.fake.add_track("one", "First Song", 60_000);
+    app.create_room(app.room(ROOM_ID)).await;
+    app.login(OWNER_URI).await;
+
+    let routes = app.routes();
+    let owner = app.cookie(OWNER_URI);
+    let guest = app.cookie(GUEST_URI);
+    let request = |method: &str, cookie: &str, body: Value| {
+        warp::test::request()
+            .method(method)
+            .path(&format!("/api/v1/rooms/{}", ROOM_ID))
+            .header("cookie", cookie)
+            .json(&body)
+            .reply(&routes)
+    };
+
+    let res = request("PATCH", &guest, json!({ "title": "Taken over" })).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    let res = request("DELETE", &guest, json!({})).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = request(
+        "PATCH",
+        &owner,
+        json!({ "title": "Renamed", "max_duration_ms": "300000" }),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let room = db.get_room(ROOM_ID.to_string()).await.unwrap().unwrap();
+    assert_eq!(room.title, "Renamed");
+    assert_eq!(room.rules.max_duration_ms, Some(300_000));
+
+    let res = request("PATCH", &owner, json!({ "title": "" })).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = request("PATCH", &owner, json!({ "max_duration_ms": "" })).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    let room = db.get_room(ROOM_ID.to_string()).await.unwrap().unwrap();
+    assert_eq!(room.title, "Renamed");
+    assert_eq!(room.rules.max_duration_ms, None);
+
+    let mut ws = app.connect(ROOM_ID, OWNER_URI).await;
+    assert!(queue_song(&mut ws, "spotify:track:one").await.is_empty());
+
+    let res = request("DELETE", &owner, json!({})).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    assert_eq!(system_message(&mut ws).await, "This room was deleted.");
+    ws.recv_closed().await.unwrap();
+
+    assert!(!db.exists_room(ROOM_ID.to_string()).await.unwrap());
+    assert!(db
+        .list_user_queue(ROOM_ID.to_string(), OWNER_URI.to_string())
+        .await
+        .unwrap()
+        .is_empty());
+    let res = request("DELETE", &owner, json!({})).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
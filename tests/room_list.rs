@@ -0,0 +1,124 @@
+mod support;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+use social_music::db::playing::Playing;
+use social_music::db::room::Room;
+use support::app::setup;
+use warp::http::StatusCode;
+
+const USER_URI: &str = "spotify:user:fake";
+
+#[tokio::test]
+async fn test_room_list_pages_and_searches() {
+    let app = setup(USER_URI).await;
+    let db = &app.db;
+    for (id, title, listeners) in [
+        ("jazz", "Late Night Jazz", 3),
+        ("rock", "Rock Classics", 1),
+        ("lofi", "Lofi Beats", 2),
+    ] {
+        app.create_room(Room {
+            title: title.to_string(),
+            ..app.room(id)
+        })
+        .await;
+        for listener in 0..listeners {
+            db.add_presences(id.to_string(), format!("spotify:user:{}", listener))
+                .await
+                .unwrap();
+        }
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    db.set_playing(
+        "rock".to_string(),
+        Playing {
+            user_id: Some(USER_URI.to_string()),
+            track_id: "spotify:track:one".to_string(),
+            start_time: now,
+            length: 60_000,
+            autoplay: false,
+        },
+    )
+    .await
+    .unwrap();
+
+    let routes = app.routes();
+    let cookie = app.cookie(USER_URI);
+    let get = |path: String| {
+        warp::test::request()
+            .path(&path)
+            .header("cookie", &cookie)
+            .reply(&routes)
+    };
+    let ids = |body: &Value| -> Vec<String> {
+        body["rooms"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|room| room["id"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    //Busiest rooms first by default
+    let res = get("/api/v1/rooms".to_string()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(ids(&body), vec!["jazz", "lofi", "rock"]);
+    assert_eq!(body["rooms"][0]["listeners"], 3);
+    assert_eq!(body["rooms"][0]["title"], "Late Night Jazz");
+    assert!(body["rooms"][0]["playing"].is_null());
+    assert_eq!(body["rooms"][2]["playing"]["track_id"], "spotify:track:one");
+    assert_eq!(body["rooms"][2]["playing"]["autoplay"], false);
+    assert!(body["next"].is_null());
+
+    let res = get("/api/v1/rooms?sort=activity&limit=1".to_string()).await;
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(ids(&body), vec!["rock"]);
+
+    let mut titles = Vec::new();
+    let mut path = "/api/v1/rooms?sort=title&limit=2".to_string();
+    loop {
+        let res = get(path).await;
+        let body: Value = serde_json::from_slice(res.body()).unwrap();
+        titles.extend(ids(&body));
+        match body["next"].as_str() {
+            Some(next) => {
+                path = format!(
+                    "/api/v1/rooms?sort=title&limit=2&after={}",
+                    next.replace(' ', "%20").replace(':', "%3A")
+                )
+            }
+            None => break,
+        }
+    }
+    assert_eq!(titles, vec!["jazz", "lofi", "rock"]);
+
+    let res = get("/api/v1/rooms?q=ROCK".to_string()).await;
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(ids(&body), vec!["rock"]);
+
+    let res = get("/api/v1/rooms?limit=0".to_string()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = get("/api/v1/rooms?sort=popularity".to_string()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let res = warp::test::request()
+        .path("/api/v1/rooms")
+        .header("cookie", "theme=dark")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    //Anything else on /rooms gets the fallback
+    let res = warp::test::request()
+        .method("PUT")
+        .path("/api/v1/rooms")
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.body(), "room");
+}
@@ -0,0 +1,223 @@
+mod support;
+
+use serde_json::json;
+use social_music::db;
+use social_music::db::room::{Room, SchedulingPolicy};
+use support::app::{setup, setup_with, wait_until, TestApp};
+use support::socket::send;
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "rotation-room";
+
+//The user logged in, with a room of their own and two tracks in the catalog
+async fn setup_rotation(app: TestApp) -> TestApp {
+    app.login(USER_URI).await;
+    app.fake.add_track("one", "First Song", 60_000);
+    app.fake.add_track("two", "Second Song", 60_000);
+    app.create_room(app.room(ROOM_ID)).await;
+    app
+}
+
+async fn rotation(db: &db::Db) -> Vec<String> {
+    db.list_queue(ROOM_ID.to_string()).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_leave_queue_keeps_user_in_room() {
+    let app = setup_rotation(setup(USER_URI).await).await;
+    let db = &app.db;
+    let mut ws = app.connect(ROOM_ID, USER_URI).await;
+
+    assert!(send(&mut ws, json!({ "JoinQueue": null })).await.is_empty());
+    //Nothing is playing for stepping down to wait on
+    assert!(send(&mut ws, json!("StepDown")).await.is_empty());
+    assert!(rotation(db).await.is_empty());
+    assert!(send(&mut ws, json!({ "JoinQueue": null })).await.is_empty());
+    assert!(send(&mut ws, json!("LeaveQueue")).await.is_empty());
+
+    assert!(rotation(db).await.is_empty());
+    let presences = db.scan_presence(ROOM_ID.to_string()).await.unwrap();
+    assert_eq!(presences, vec![USER_URI.to_string()]);
+}
+
+#[tokio::test]
+async fn test_step_down_after_current_track() {
+    const OTHER_URI: &str = "spotify:user:other";
+    let app = setup_rotation(setup(USER_URI).await).await;
+    let db = &app.db;
+    app.login(OTHER_URI).await;
+    app.fake.add_track("short", "Short Song", 1_000);
+    let mut ws = app.connect(ROOM_ID, USER_URI).await;
+
+    for (user_id, track) in [
+        (OTHER_URI, "spotify:track:short"),
+        (USER_URI, "spotify:track:one"),
+    ] {
+        db.push_user_queue(ROOM_ID.to_string(), user_id.to_string(), track.to_string())
+            .await
+            .unwrap();
+        db.push_queue(ROOM_ID.to_string(), user_id.to_string())
+            .await
+            .unwrap();
+    }
+
+    db.offer_room(ROOM_ID.to_string()).await.unwrap();
+    app.serve().await;
+    wait_until(|| async { db.get_playing(ROOM_ID.to_string()).await.unwrap().is_some() }).await;
+    let playing = db.get_playing(ROOM_ID.to_string()).await.unwrap().unwrap();
+    assert_eq!(playing.user_id.as_deref(), Some(OTHER_URI));
+
+    //Someone else's track is playing, which is the one they leave after
+    assert_eq!(
+        send(&mut ws, json!("StepDown")).await,
+        vec!["You'll leave the queue once the current track has played."]
+    );
+    assert!(rotation(db).await.contains(&USER_URI.to_string()));
+
+    wait_until(|| async { rotation(db).await.is_empty() }).await;
+    let history = db
+        .list_history(ROOM_ID.to_string(), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(
+        db.list_user_queue(ROOM_ID.to_string(), USER_URI.to_string())
+            .await
+            .unwrap(),
+        vec!["spotify:track:one"]
+    );
+}
+
+#[tokio::test]
+async fn test_fair_share_picks_least_listened_dj() {
+    const OTHER_URI: &str = "spotify:user:other";
+    const FAIR_ROOM_ID: &str = "fair-room";
+    let app = setup_rotation(setup(USER_URI).await).await;
+    let db = &app.db;
+    app.create_room(Room {
+        policy: SchedulingPolicy::FairShare,
+        ..app.room(FAIR_ROOM_ID)
+    })
+    .await;
+    app.login(OTHER_URI).await;
+
+    //First in the rotation, but has already had plenty of airtime
+    for user_id in [USER_URI, OTHER_URI] {
+        db.push_user_queue(
+            FAIR_ROOM_ID.to_string(),
+            user_id.to_string(),
+            "spotify:track:one".to_string(),
+        )
+        .await
+        .unwrap();
+        db.push_queue(FAIR_ROOM_ID.to_string(), user_id.to_string())
+            .await
+            .unwrap();
+    }
+    db.add_listening_time(FAIR_ROOM_ID.to_string(), USER_URI.to_string(), 600_000)
+        .await
+        .unwrap();
+
+    db.offer_room(FAIR_ROOM_ID.to_string()).await.unwrap();
+    app.serve().await;
+    wait_until(|| async {
+        db.get_playing(FAIR_ROOM_ID.to_string())
+            .await
+            .unwrap()
+            .is_some()
+    })
+    .await;
+
+    let playing = db
+        .get_playing(FAIR_ROOM_ID.to_string())
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(playing.user_id.as_deref(), Some(OTHER_URI));
+    assert_eq!(
+        db.list_queue(FAIR_ROOM_ID.to_string()).await.unwrap(),
+        vec![USER_URI.to_string(), OTHER_URI.to_string()]
+    );
+    let listening_time = db
+        .list_listening_time(FAIR_ROOM_ID.to_string())
+        .await
+        .unwrap();
+    //Credited once the track is over, for as long as it went on
+    assert_eq!(listening_time.get(OTHER_URI), None);
+}
+
+//Queues two tracks for the user, starts serving the room and waits for the first one to play
+async fn play_first_track(app: &TestApp) {
+    let db = &app.db;
+    for track in ["spotify:track:one", "spotify:track:two"] {
+        db.push_user_queue(ROOM_ID.to_string(), USER_URI.to_string(), track.to_string())
+            .await
+            .unwrap();
+    }
+    db.push_queue(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap();
+    db.add_presence(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap();
+
+    db.offer_room(ROOM_ID.to_string()).await.unwrap();
+    app.serve().await;
+    wait_until(|| async { db.get_playing(ROOM_ID.to_string()).await.unwrap().is_some() }).await;
+}
+
+async fn is_away(db: &db::Db) -> bool {
+    !db.list_away(ROOM_ID.to_string()).await.unwrap().is_empty()
+}
+
+#[tokio::test]
+async fn test_reconnect_within_grace_keeps_place() {
+    let app = setup_rotation(setup(USER_URI).await).await;
+    let db = &app.db;
+    play_first_track(&app).await;
+
+    db.remove_presence(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap();
+    wait_until(|| is_away(db)).await;
+    db.add_presence(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap();
+    wait_until(|| async { !is_away(db).await }).await;
+
+    assert_eq!(rotation(db).await, vec![USER_URI.to_string()]);
+    assert_eq!(
+        db.list_user_queue(ROOM_ID.to_string(), USER_URI.to_string())
+            .await
+            .unwrap(),
+        vec!["spotify:track:two"]
+    );
+}
+
+#[tokio::test]
+async fn test_place_is_lost_after_grace() {
+    let app = setup_with(USER_URI, |config| {
+        config.rooms.reconnect_grace_secs = 1;
+        config.timings.room_claim_refresh_secs = 1;
+    })
+    .await;
+    let app = setup_rotation(app).await;
+    let db = &app.db;
+    play_first_track(&app).await;
+
+    db.remove_presence(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap();
+    wait_until(|| is_away(db)).await;
+    //Keeps their place while the grace period runs
+    assert_eq!(rotation(db).await, vec![USER_URI.to_string()]);
+    wait_until(|| async { rotation(db).await.is_empty() }).await;
+
+    assert!(!is_away(db).await);
+    assert!(db
+        .list_user_queue(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap()
+        .is_empty());
+}
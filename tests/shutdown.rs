@@ -0,0 +1,44 @@
+mod support;
+
+use std::time::Duration;
+use support::app::{setup, wait_until};
+use support::socket::system_message;
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "shutdown-room";
+
+#[tokio::test]
+async fn test_shutdown_says_goodbye_and_releases_the_room() {
+    let app = setup(USER_URI).await;
+    let db = &app.db;
+    app.create_room(app.room(ROOM_ID)).await;
+
+    app.serve().await;
+    let mut ws = app.connect(ROOM_ID, USER_URI).await;
+
+    //The room worker only tracks presences once it has claimed the room
+    wait_until(|| async {
+        !db.list_presences(ROOM_ID.to_string())
+            .await
+            .unwrap()
+            .is_empty()
+    })
+    .await;
+
+    let shutdown = tokio::task::spawn(app.controller.shutdown(Duration::from_secs(10)));
+
+    //Skip room updates until the goodbye arrives
+    system_message(&mut ws).await;
+    ws.recv_closed().await.unwrap();
+
+    assert!(shutdown.await.unwrap());
+    assert!(db
+        .scan_presence(ROOM_ID.to_string())
+        .await
+        .unwrap()
+        .is_empty());
+
+    //The room went back on offer for the next instance
+    let claimed = db.claim_room().await.unwrap().await.unwrap();
+    assert_eq!(claimed, Some(ROOM_ID.to_string()));
+}
@@ -0,0 +1,140 @@
+mod support;
+
+use serde_json::{json, Value};
+use social_music::error::Error;
+use std::time::{Duration, Instant};
+use support::app::{setup, setup_with, wait_until};
+use support::fake_spotify::Route;
+use warp::http::StatusCode;
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "retry-room";
+
+#[tokio::test]
+async fn test_server_errors_are_retried() {
+    let app = setup(USER_URI).await;
+    app.login(USER_URI).await;
+    let (fake, db, spotify) = (&app.fake, &app.db, &app.spotify);
+    fake.add_track("one", "First Song", 60_000);
+
+    fake.script(Route::Track, 502, json!({ "error": "bad gateway" }));
+    fake.script(Route::Track, 503, json!({ "error": "unavailable" }));
+    let token = db.require_auth(USER_URI.to_string()).await.unwrap();
+    let track = spotify
+        .request_track(token, "spotify:track:one".to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(track.name, "First Song");
+    assert_eq!(fake.requests(Route::Track), 3);
+}
+
+#[tokio::test]
+async fn test_rate_limit_waits_for_retry_after() {
+    let app = setup(USER_URI).await;
+    app.login(USER_URI).await;
+    let (fake, db, spotify) = (&app.fake, &app.db, &app.spotify);
+    fake.add_track("one", "First Song", 60_000);
+
+    fake.script_rate_limit(Route::Track, 1);
+    let token = db.require_auth(USER_URI.to_string()).await.unwrap();
+    let started = Instant::now();
+    let track = spotify
+        .request_track(token, "spotify:track:one".to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(track.name, "First Song");
+    assert!(started.elapsed() >= Duration::from_secs(1));
+    assert_eq!(fake.requests(Route::Track), 2);
+}
+
+#[tokio::test]
+async fn test_long_retry_after_fails_right_away() {
+    let app = setup(USER_URI).await;
+    app.login(USER_URI).await;
+    let (fake, db, spotify) = (&app.fake, &app.db, &app.spotify);
+    fake.add_track("one", "First Song", 60_000);
+
+    fake.script_rate_limit(Route::Track, 600);
+    let token = db.require_auth(USER_URI.to_string()).await.unwrap();
+    let result = spotify
+        .request_track(token, "spotify:track:one".to_string())
+        .await;
+
+    match result {
+        Err(Error::RateLimited { retry_after }) => {
+            assert_eq!(retry_after, Duration::from_secs(600))
+        }
+        other => panic!("Expected to be rate limited, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(fake.requests(Route::Track), 1);
+}
+
+#[tokio::test]
+async fn test_exhausted_budget_reaches_the_client() {
+    let app = setup_with(USER_URI, |config| config.spotify.token_budget = 1).await;
+    app.login(USER_URI).await;
+    let routes = app.routes();
+    let cookie = app.cookie(USER_URI);
+
+    let search = || {
+        warp::test::request()
+            .path("/api/v1/search?q=song")
+            .header("cookie", &cookie)
+            .reply(&routes)
+    };
+    assert_eq!(search().await.status(), StatusCode::OK);
+    let res = search().await;
+
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers()["retry-after"], "30");
+    let body: Value = serde_json::from_slice(res.body()).unwrap();
+    assert_eq!(body["error"], "rate_limited");
+    assert_eq!(app.fake.requests(Route::Search), 1);
+}
+
+#[tokio::test]
+async fn test_song_stays_queued_while_spotify_is_down() {
+    let app = setup(USER_URI).await;
+    app.login(USER_URI).await;
+    let (fake, db) = (&app.fake, &app.db);
+    fake.add_track("one", "First Song", 60_000);
+    app.create_room(app.room(ROOM_ID)).await;
+
+    let room_id = ROOM_ID.to_string();
+    let user_id = USER_URI.to_string();
+    db.set_device(user_id.clone(), "fake-device".to_string())
+        .await
+        .unwrap();
+    db.add_presence(room_id.clone(), user_id.clone())
+        .await
+        .unwrap();
+    db.push_user_queue(
+        room_id.clone(),
+        user_id.clone(),
+        "spotify:track:one".to_string(),
+    )
+    .await
+    .unwrap();
+    db.push_queue(room_id.clone(), user_id.clone())
+        .await
+        .unwrap();
+
+    //Enough failures to use up every retry of the first attempt
+    let max_retries = app.config.spotify.max_retries;
+    for _ in 0..=max_retries {
+        fake.script(Route::Track, 503, json!({ "error": "unavailable" }));
+    }
+    db.offer_room(room_id.clone()).await.unwrap();
+    app.serve().await;
+    wait_until(|| async { !fake.plays().is_empty() }).await;
+
+    let play = fake.plays().pop().unwrap();
+    assert_eq!(play.uris, vec!["spotify:track:one".to_string()]);
+    assert_eq!(fake.requests(Route::Track), max_retries as usize + 2);
+    assert_eq!(
+        db.list_queue(room_id.clone()).await.unwrap(),
+        vec![user_id.clone()]
+    );
+}
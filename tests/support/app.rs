@@ -0,0 +1,136 @@
+//Everything a test needs to talk to the service: a fake Spotify, a memory database and the real routes
+use social_music::config::Config;
+use social_music::db::room::Room;
+use social_music::{cookie, db, health, room, routes, shutdown, spotify};
+use std::convert::Infallible;
+use std::time::Duration;
+use warp::Filter;
+
+use super::fake_spotify::FakeSpotify;
+
+pub struct TestApp {
+    //Who the fake Spotify logs in as and the owner of rooms made with room()
+    pub owner: String,
+    pub fake: FakeSpotify,
+    pub config: Config,
+    pub db: db::Db,
+    pub spotify: spotify::Spotify,
+    pub controller: shutdown::Controller,
+    pub heartbeat: health::Heartbeat,
+}
+
+pub async fn setup(owner: &str) -> TestApp {
+    setup_with(owner, |_| ()).await
+}
+
+//Same as setup, with the config changed before anything uses it
+pub async fn setup_with(owner: &str, configure: impl FnOnce(&mut Config)) -> TestApp {
+    let fake = FakeSpotify::start(owner).await;
+    let mut config = Config {
+        cookie_key: "0".repeat(32),
+        spotify: fake.config.clone(),
+        ..Default::default()
+    };
+    configure(&mut config);
+    let db = db::connect_memory_db(&config);
+    let spotify = spotify::init(db.clone(), &config.spotify);
+
+    TestApp {
+        owner: owner.to_string(),
+        fake,
+        config,
+        db,
+        spotify,
+        controller: shutdown::Controller::new(),
+        heartbeat: health::Heartbeat::default(),
+    }
+}
+
+impl TestApp {
+    //A room owned by the owner, titled after its id
+    pub fn room(&self, id: &str) -> Room {
+        Room {
+            id: id.to_string(),
+            title: id.to_string(),
+            owner: self.owner.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub async fn create_room(&self, room: Room) {
+        self.db.create_room(room).await.unwrap();
+    }
+
+    //As if the user went through the login flow, anything talking to Spotify for them needs this
+    pub async fn login(&self, user_id: &str) {
+        self.db
+            .set_auth(
+                user_id.to_string(),
+                "token".to_string(),
+                "fake-refresh-token".to_string(),
+            )
+            .await
+            .unwrap();
+    }
+
+    pub fn routes(&self) -> impl Filter<Extract = impl warp::Reply, Error = Infallible> + Clone {
+        routes::routes(
+            self.config.clone(),
+            self.db.clone(),
+            self.spotify.clone(),
+            self.controller.handle(),
+            self.heartbeat.clone(),
+        )
+    }
+
+    //The cookie header of a logged in user
+    pub fn cookie(&self, user_id: &str) -> String {
+        format!(
+            "userid={}",
+            cookie::gen_user(&cookie::init(&self.config), user_id.to_string())
+        )
+    }
+
+    pub async fn handshake(
+        &self,
+        room_id: &str,
+        user_id: &str,
+    ) -> Result<warp::test::WsClient, warp::test::WsError> {
+        warp::test::ws()
+            .path(&format!("/chat/{}", room_id))
+            .header("cookie", self.cookie(user_id))
+            .handshake(self.routes())
+            .await
+    }
+
+    pub async fn connect(&self, room_id: &str, user_id: &str) -> warp::test::WsClient {
+        self.handshake(room_id, user_id).await.unwrap()
+    }
+
+    //Starts serving rooms that are on offer, like an instance would
+    pub async fn serve(&self) {
+        room::start_listener(
+            self.db.clone(),
+            self.spotify.clone(),
+            self.config.timings.clone(),
+            self.config.rooms.clone(),
+            self.controller.handle(),
+            self.heartbeat.clone(),
+        )
+        .await;
+    }
+}
+
+pub async fn wait_until<F, Fut>(condition: F)
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !condition().await {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("Condition was never met");
+}
@@ -0,0 +1,415 @@
+//A local stand-in for the spotify web api and accounts service.
+//Serves a small track catalog and records playback requests, individual responses can be scripted per route.
+use serde::Deserialize;
+use serde_json::{json, Value};
+use social_music::config::SpotifyConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Route {
+    Token,
+    Me,
+    Search,
+    Track,
+    Tracks,
+    Recommendations,
+    Play,
+}
+
+#[derive(Debug, Clone)]
+pub struct Play {
+    pub access_token: String,
+    pub device_id: String,
+    pub uris: Vec<String>,
+    pub position_ms: u64,
+}
+
+#[derive(Default)]
+struct State {
+    user_uri: String,
+    tokens_issued: u32,
+    tracks: Vec<Value>,
+    scripted: HashMap<Route, VecDeque<Scripted>>,
+    requests: Vec<Route>,
+    plays: Vec<Play>,
+}
+
+struct Scripted {
+    status: u16,
+    body: Value,
+    retry_after: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct FakeSpotify {
+    pub config: SpotifyConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeSpotify {
+    pub async fn start(user_uri: &str) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            user_uri: user_uri.to_string(),
+            ..Default::default()
+        }));
+
+        let (addr, server) = warp::serve(routes(state.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::task::spawn(server);
+
+        let base = format!("http://{}", addr);
+        let config = SpotifyConfig {
+            client_id: "fake-client-id".to_string(),
+            client_secret: "fake-client-secret".to_string(),
+            return_url: "http://localhost:3030/authorize".to_string(),
+            api_url: base.clone(),
+            accounts_url: base,
+            //Keeps retries from slowing the tests down
+            retry_base_delay_ms: 1,
+            ..Default::default()
+        };
+
+        Self { config, state }
+    }
+
+    pub fn add_track(&self, id: &str, name: &str, duration_ms: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .tracks
+            .push(track(id, name, duration_ms));
+    }
+
+    //Same recording as another track, like a remaster or a single that's also on an album
+    pub fn add_release(&self, id: &str, name: &str, duration_ms: u64, isrc: &str) {
+        let mut track = track(id, name, duration_ms);
+        track["external_ids"]["isrc"] = json!(isrc);
+        self.state.lock().unwrap().tracks.push(track);
+    }
+
+    //Next request to the route gets this response instead of the default one
+    pub fn script(&self, route: Route, status: u16, body: Value) {
+        self.push_scripted(
+            route,
+            Scripted {
+                status,
+                body,
+                retry_after: None,
+            },
+        );
+    }
+
+    //Next request to the route gets a 429 asking to wait retry_after seconds
+    pub fn script_rate_limit(&self, route: Route, retry_after: u64) {
+        self.push_scripted(
+            route,
+            Scripted {
+                status: 429,
+                body: json!({ "error": { "status": 429, "message": "API rate limit exceeded" } }),
+                retry_after: Some(retry_after),
+            },
+        );
+    }
+
+    fn push_scripted(&self, route: Route, scripted: Scripted) {
+        self.state
+            .lock()
+            .unwrap()
+            .scripted
+            .entry(route)
+            .or_default()
+            .push_back(scripted);
+    }
+
+    pub fn requests(&self, route: Route) -> usize {
+        let state = self.state.lock().unwrap();
+        state.requests.iter().filter(|r| **r == route).count()
+    }
+
+    pub fn plays(&self) -> Vec<Play> {
+        self.state.lock().unwrap().plays.clone()
+    }
+}
+
+fn routes(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let with_state = warp::any().map(move || state.clone());
+    let bearer = warp::header::optional::<String>("authorization");
+
+    let token = warp::path!("api" / "token")
+        .and(warp::post())
+        .and(with_state.clone())
+        .and(warp::body::form::<HashMap<String, String>>())
+        .map(|state, form: HashMap<String, String>| {
+            respond(state, Route::Token, None, |state| {
+                state.tokens_issued += 1;
+                let refresh_token = match form.get("grant_type").map(|s| &s[..]) {
+                    Some("authorization_code") => Some("fake-refresh-token"),
+                    _ => None,
+                };
+
+                json!({
+                    "access_token": format!("fake-access-token-{}", state.tokens_issued),
+                    "token_type": "Bearer",
+                    "scope": "user-modify-playback-state streaming",
+                    "expires_in": 3600,
+                    "refresh_token": refresh_token,
+                })
+            })
+        });
+
+    let me = warp::path!("v1" / "me")
+        .and(warp::get())
+        .and(with_state.clone())
+        .and(bearer)
+        .map(|state, auth| {
+            respond(state, Route::Me, auth, |state| {
+                json!({
+                    "country": "SE",
+                    "display_name": "Fake User",
+                    "email": null,
+                    "external_urls": { "spotify": "https://open.spotify.com/user/fake" },
+                    "followers": { "href": null, "total": 0 },
+                    "href": "https://api.spotify.com/v1/users/fake",
+                    "images": [],
+                    "product": "premium",
+                    "type": "user",
+                    "uri": state.user_uri,
+                })
+            })
+        });
+
+    let search = warp::path!("v1" / "search")
+        .and(warp::get())
+        .and(with_state.clone())
+        .and(bearer)
+        .and(warp::query::<SearchQuery>())
+        .map(|state, auth, query: SearchQuery| {
+            respond(state, Route::Search, auth, |state| {
+                let needle = query.q.to_lowercase();
+                let items: Vec<&Value> = state
+                    .tracks
+                    .iter()
+                    .filter(|track| {
+                        track["name"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_lowercase()
+                            .contains(&needle)
+                    })
+                    .collect();
+
+                json!({
+                    "tracks": {
+                        "href": "https://api.spotify.com/v1/search",
+                        "items": items,
+                        "limit": 20,
+                        "offset": 0,
+                        "previous": null,
+                        "next": null,
+                        "total": items.len(),
+                    }
+                })
+            })
+        });
+
+    let single_track = warp::path!("v1" / "tracks" / String)
+        .and(warp::get())
+        .and(with_state.clone())
+        .and(bearer)
+        .map(|id: String, state, auth| {
+            respond(state, Route::Track, auth, |state| {
+                find_track(state, &id).unwrap_or(Value::Null)
+            })
+        });
+
+    let tracks = warp::path!("v1" / "tracks")
+        .and(warp::get())
+        .and(with_state.clone())
+        .and(bearer)
+        .and(warp::query::<TracksQuery>())
+        .map(|state, auth, query: TracksQuery| {
+            respond(state, Route::Tracks, auth, |state| {
+                let tracks: Vec<Value> = query
+                    .ids
+                    .split(',')
+                    .map(|id| find_track(state, id).unwrap_or(Value::Null))
+                    .collect();
+
+                json!({ "tracks": tracks })
+            })
+        });
+
+    //Recommends the whole catalog except the seeds
+    let recommendations = warp::path!("v1" / "recommendations")
+        .and(warp::get())
+        .and(with_state.clone())
+        .and(bearer)
+        .and(warp::query::<RecommendationsQuery>())
+        .map(|state, auth, query: RecommendationsQuery| {
+            respond(state, Route::Recommendations, auth, |state| {
+                let seeds: Vec<&str> = query.seed_tracks.split(',').collect();
+                let tracks: Vec<&Value> = state
+                    .tracks
+                    .iter()
+                    .filter(|track| !seeds.contains(&track["id"].as_str().unwrap_or_default()))
+                    .take(query.limit)
+                    .collect();
+
+                json!({ "tracks": tracks, "seeds": [] })
+            })
+        });
+
+    let play = warp::path!("v1" / "me" / "player" / "play")
+        .and(warp::put())
+        .and(with_state)
+        .and(bearer)
+        .and(warp::query::<PlayQuery>())
+        .and(warp::body::json::<Value>())
+        .map(
+            |state, auth: Option<String>, query: PlayQuery, body: Value| {
+                let access_token = auth.clone().unwrap_or_default().replace("Bearer ", "");
+                respond(state, Route::Play, auth, |state| {
+                    state.plays.push(Play {
+                        access_token,
+                        device_id: query.device_id,
+                        uris: serde_json::from_value(body["uris"].clone()).unwrap_or_default(),
+                        position_ms: body["position_ms"].as_u64().unwrap_or_default(),
+                    });
+
+                    Value::Null
+                })
+            },
+        );
+
+    token
+        .or(me)
+        .unify()
+        .or(search)
+        .unify()
+        .or(tracks)
+        .unify()
+        .or(single_track)
+        .unify()
+        .or(recommendations)
+        .unify()
+        .or(play)
+        .unify()
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+#[derive(Deserialize)]
+struct TracksQuery {
+    ids: String,
+}
+
+#[derive(Deserialize)]
+struct RecommendationsQuery {
+    seed_tracks: String,
+    limit: usize,
+}
+
+#[derive(Deserialize)]
+struct PlayQuery {
+    device_id: String,
+}
+
+fn respond(
+    state: Arc<Mutex<State>>,
+    route: Route,
+    auth: Option<String>,
+    default: impl FnOnce(&mut State) -> Value,
+) -> Response {
+    let mut state = state.lock().unwrap();
+    state.requests.push(route);
+
+    let scripted = state.scripted.get_mut(&route).and_then(|s| s.pop_front());
+    let (status, body, retry_after) = match scripted {
+        Some(scripted) => (scripted.status, scripted.body, scripted.retry_after),
+        None if route != Route::Token && auth.is_none() => (
+            401,
+            json!({ "error": { "status": 401, "message": "No token provided" } }),
+            None,
+        ),
+        None => (200, default(&mut state), None),
+    };
+
+    let status = StatusCode::from_u16(status).unwrap();
+    let mut response = if body.is_null() {
+        let status = if status == StatusCode::OK {
+            StatusCode::NO_CONTENT
+        } else {
+            status
+        };
+        warp::reply::with_status(warp::reply(), status).into_response()
+    } else {
+        warp::reply::with_status(warp::reply::json(&body), status).into_response()
+    };
+
+    if let Some(retry_after) = retry_after {
+        response
+            .headers_mut()
+            .insert("retry-after", retry_after.to_string().parse().unwrap());
+    }
+    response
+}
+
+fn find_track(state: &State, id: &str) -> Option<Value> {
+    state.tracks.iter().find(|track| track["id"] == id).cloned()
+}
+
+fn track(id: &str, name: &str, duration_ms: u64) -> Value {
+    let external_urls = json!({ "spotify": format!("https://open.spotify.com/track/{}", id) });
+    let artist = json!({
+        "external_urls": { "spotify": "https://open.spotify.com/artist/fake" },
+        "href": "https://api.spotify.com/v1/artists/fake",
+        "id": "fake",
+        "name": "Fake Artist",
+        "type": "artist",
+        "uri": "spotify:artist:fake",
+    });
+
+    json!({
+        "album": {
+            "album_type": "album",
+            "total_tracks": 1,
+            "available_markets": ["SE"],
+            "external_urls": external_urls,
+            "href": "https://api.spotify.com/v1/albums/fake",
+            "id": "fake",
+            "images": [
+                { "url": "https://i.scdn.co/image/large", "height": 640, "width": 640 },
+                { "url": "https://i.scdn.co/image/small", "height": 64, "width": 64 },
+            ],
+            "name": "Fake Album",
+            "release_date": "2022-01-01",
+            "type": "album",
+            "uri": "spotify:album:fake",
+            "artists": [artist],
+        },
+        "artists": [artist],
+        "available_markets": ["SE"],
+        "disc_number": 1,
+        "duration_ms": duration_ms,
+        "explicit": false,
+        "external_ids": { "isrc": format!("FAKE{}", id) },
+        "external_urls": external_urls,
+        "href": format!("https://api.spotify.com/v1/tracks/{}", id),
+        "id": id,
+        "is_local": false,
+        "name": name,
+        "popularity": 50,
+        "preview_url": null,
+        "track_number": 1,
+        "type": "track",
+        "uri": format!("spotify:track:{}", id),
+    })
+}
@@ -0,0 +1,6 @@
+//Every test binary compiles its own copy and only uses some of it
+#![allow(dead_code)]
+
+pub mod app;
+pub mod fake_spotify;
+pub mod socket;
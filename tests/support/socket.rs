@@ -0,0 +1,45 @@
+//Helpers for talking to a room over a test websocket
+use serde_json::{json, Value};
+
+//Queues the track and returns the system messages it got back, empty if it went through
+pub async fn queue_song(ws: &mut warp::test::WsClient, track_id: &str) -> Vec<String> {
+    send(ws, json!({ "QueueSong": { "track_id": track_id } })).await
+}
+
+//Sends any message and returns the system messages that came back before the server got to a ping after it
+pub async fn send(ws: &mut warp::test::WsClient, message: Value) -> Vec<String> {
+    ws.send_text(message.to_string()).await;
+    ws.send_text(json!({ "KeepAlivePing": { "data": "sync" } }).to_string())
+        .await;
+
+    let mut rejections = Vec::new();
+    loop {
+        let message = ws.recv().await.unwrap();
+        let message: Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        if message.get("KeepAlivePong").is_some() {
+            return rejections;
+        }
+        if message["ChatMessage"]["from"] == "system" {
+            rejections.push(
+                message["ChatMessage"]["message"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+    }
+}
+
+//Reads until a system message shows up and returns it
+pub async fn system_message(ws: &mut warp::test::WsClient) -> String {
+    loop {
+        let message = ws.recv().await.unwrap();
+        let message: Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        if message["ChatMessage"]["from"] == "system" {
+            return message["ChatMessage"]["message"]
+                .as_str()
+                .unwrap()
+                .to_string();
+        }
+    }
+}
@@ -0,0 +1,132 @@
+mod support;
+
+use serde_json::{json, Value};
+use social_music::db;
+use support::app::{setup, TestApp};
+use warp::http::StatusCode;
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "queue-room";
+
+//A room with three tracks in the user's queue
+async fn setup_queue() -> TestApp {
+    let app = setup(USER_URI).await;
+    app.create_room(app.room(ROOM_ID)).await;
+    for track in [
+        "spotify:track:one",
+        "spotify:track:two",
+        "spotify:track:three",
+    ] {
+        app.db
+            .push_user_queue(ROOM_ID.to_string(), USER_URI.to_string(), track.to_string())
+            .await
+            .unwrap();
+    }
+
+    app
+}
+
+async fn user_queue(db: &db::Db) -> Vec<String> {
+    db.list_user_queue(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_rest_reorders_and_clears_queue() {
+    let app = setup_queue().await;
+    let db = &app.db;
+    let routes = app.routes();
+    let cookie = app.cookie(USER_URI);
+
+    let res = warp::test::request()
+        .method("PATCH")
+        .path(&format!("/api/v1/queues/{}/2", ROOM_ID))
+        .header("cookie", &cookie)
+        .json(&json!({ "track_id": "spotify:track:three", "to": 0 }))
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        user_queue(db).await,
+        vec![
+            "spotify:track:three",
+            "spotify:track:one",
+            "spotify:track:two"
+        ]
+    );
+
+    //The track has moved on since the client last looked
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!(
+            "/api/v1/queues/{}/2?track_id=spotify:track:three",
+            ROOM_ID
+        ))
+        .header("cookie", &cookie)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!(
+            "/api/v1/queues/{}/1?track_id=spotify:track:one",
+            ROOM_ID
+        ))
+        .header("cookie", &cookie)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        user_queue(db).await,
+        vec!["spotify:track:three", "spotify:track:two"]
+    );
+
+    let res = warp::test::request()
+        .method("DELETE")
+        .path(&format!("/api/v1/queues/{}", ROOM_ID))
+        .header("cookie", &cookie)
+        .reply(&routes)
+        .await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert!(user_queue(db).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_socket_changes_notify_the_user() {
+    let app = setup_queue().await;
+    let db = &app.db;
+    let mut ws = app.connect(ROOM_ID, USER_URI).await;
+
+    let messages = [
+        json!({ "RemoveQueuedTrack": { "index": 0, "track_id": "spotify:track:one" } }),
+        json!({ "MoveQueuedTrack": { "index": 1, "to": 0, "track_id": "spotify:track:three" } }),
+    ];
+    for message in messages {
+        ws.send_text(message.to_string()).await;
+
+        loop {
+            let message = ws.recv().await.unwrap();
+            let message: Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+            if message == "UserQueueChange" {
+                break;
+            }
+        }
+    }
+    assert_eq!(
+        user_queue(db).await,
+        vec!["spotify:track:three", "spotify:track:two"]
+    );
+
+    ws.send_text(json!({ "ClearMyQueue": null }).to_string())
+        .await;
+    loop {
+        let message = ws.recv().await.unwrap();
+        let message: Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+        if message == "UserQueueChange" {
+            break;
+        }
+    }
+    assert!(user_queue(db).await.is_empty());
+}
@@ -0,0 +1,71 @@
+mod support;
+
+use serde_json::json;
+use social_music::db;
+use support::app::{setup, wait_until};
+use support::socket::system_message;
+
+const USER_URI: &str = "spotify:user:fake";
+const ROOM_ID: &str = "skip-room";
+
+async fn wait_for_track(db: &db::Db, track_id: &str) {
+    wait_until(|| async {
+        let playing = db.get_playing(ROOM_ID.to_string()).await.unwrap();
+        playing.is_some_and(|playing| playing.track_id == track_id)
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_vote_skip_advances_to_next_track() {
+    let app = setup(USER_URI).await;
+    app.login(USER_URI).await;
+    let db = &app.db;
+    app.fake.add_track("one", "First Song", 60_000);
+    app.fake.add_track("two", "Second Song", 60_000);
+    app.create_room(app.room(ROOM_ID)).await;
+    for track in ["spotify:track:one", "spotify:track:two"] {
+        db.push_user_queue(ROOM_ID.to_string(), USER_URI.to_string(), track.to_string())
+            .await
+            .unwrap();
+    }
+    db.push_queue(ROOM_ID.to_string(), USER_URI.to_string())
+        .await
+        .unwrap();
+
+    app.serve().await;
+    let mut ws = app.connect(ROOM_ID, USER_URI).await;
+
+    wait_for_track(db, "spotify:track:one").await;
+    //The only listener makes up the whole room
+    wait_until(|| async {
+        !db.list_presences(ROOM_ID.to_string())
+            .await
+            .unwrap()
+            .is_empty()
+    })
+    .await;
+
+    ws.send_text(json!({ "VoteSkip": null }).to_string()).await;
+    wait_for_track(db, "spotify:track:two").await;
+
+    let text = system_message(&mut ws).await;
+    assert!(text.starts_with("Skipping this track"), "{}", text);
+    assert!(db
+        .list_skip_votes(ROOM_ID.to_string())
+        .await
+        .unwrap()
+        .is_empty());
+
+    //Only the part of the skipped track that played counts towards its DJ's share
+    let listening_time = db.list_listening_time(ROOM_ID.to_string()).await.unwrap();
+    assert!(listening_time[USER_URI] < 60_000);
+
+    let history = db
+        .list_history(ROOM_ID.to_string(), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(history[1].track_id, "spotify:track:one");
+    assert!(history[1].skipped);
+    assert!(!history[0].skipped);
+}